@@ -1,8 +1,18 @@
-use std::io::Write;
+use std::borrow::Cow;
+use std::cmp;
+use std::io::{self, Read, Write};
 
 use crate::{GrufError, Result};
+use encoding::all::{WINDOWS_1252, WINDOWS_949};
 use encoding::label::encoding_from_whatwg_label;
-use encoding::EncoderTrap;
+use encoding::{DecoderTrap, EncoderTrap, Encoding};
+
+// Maximum amount of memory allocated upfront when reading data whose size comes
+// from an archive
+const MAX_PREALLOCATION_SIZE: u64 = 1024 * 1024;
+// Archives come from untrusted sources, file tables are loaded in
+// memory and thus cannot be bigger than this once decompressed
+pub(crate) const MAX_TABLE_SIZE: usize = 512 * 1024 * 1024;
 
 pub struct GenericFileEntry {
     pub offset: u64,
@@ -11,6 +21,56 @@ pub struct GenericFileEntry {
     pub size_compressed: u32,
 }
 
+/// Converts a path into the form used by archives' entries, where each byte
+/// of the path is mapped to a win1252 char.
+///
+/// Paths can be given either in that form or in their display form (i.e.
+/// decoded from CP949). Paths that are already in the archives' form are
+/// returned as is.
+pub fn to_archive_path(path: &str) -> Cow<'_, str> {
+    if path.is_ascii() || WINDOWS_1252.encode(path, EncoderTrap::Strict).is_ok() {
+        return Cow::Borrowed(path);
+    }
+    WINDOWS_949
+        .encode(path, EncoderTrap::Strict)
+        .ok()
+        .and_then(|raw_path| WINDOWS_1252.decode(&raw_path, DecoderTrap::Strict).ok())
+        .map_or(Cow::Borrowed(path), Cow::Owned)
+}
+
+/// Decodes a path's raw bytes as CP949, for display purposes
+pub fn display_path_from_raw(raw_path: &[u8]) -> String {
+    match WINDOWS_949.decode(raw_path, DecoderTrap::Replace) {
+        Ok(v) => v,
+        // Cannot fail with `DecoderTrap::Replace`
+        Err(_) => String::from_utf8_lossy(raw_path).into_owned(),
+    }
+}
+
+/// Returns a path's display form, given a path in the archives' form
+pub fn display_path(relative_path: &str) -> String {
+    match serialize_to_win1252(relative_path) {
+        Ok(raw_path) => display_path_from_raw(&raw_path),
+        Err(_) => relative_path.to_string(),
+    }
+}
+
+/// Normalizes an entry's path so that it can be used as a lookup key.
+///
+/// The game client resolves paths case-insensitively and accepts both `/` and
+/// `\` as separators. Paths can be given in their display form.
+pub fn normalize_path(relative_path: &str) -> String {
+    // Only ASCII characters are lowercased since paths are
+    // CP949-encoded in practice
+    to_archive_path(relative_path)
+        .chars()
+        .map(|c| match c {
+            '/' => '\\',
+            _ => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
 /// Serializes string into a NULL-terminated list of win1252 chars and write it
 /// into writer.
 ///
@@ -38,3 +98,116 @@ pub fn serialize_to_win1252(string: &str) -> Result<Vec<u8>> {
         .encode(string, EncoderTrap::Strict)
         .map_err(|_| GrufError::serialization_error("Encoding failed"))
 }
+
+/// Reader that ensures the wrapped reader yields exactly `expected_size` bytes.
+///
+/// Used to validate the size of decompressed entries when streaming them.
+pub struct SizeCheckedReader<R: Read> {
+    inner: Option<R>,
+    expected_size: u64,
+    read_size: u64,
+}
+
+impl<R: Read> SizeCheckedReader<R> {
+    pub fn new(inner: R, expected_size: u64) -> Self {
+        Self {
+            inner: Some(inner),
+            expected_size,
+            read_size: 0,
+        }
+    }
+
+    /// Reader for empty entries, which yields no bytes
+    pub fn empty() -> Self {
+        Self {
+            inner: None,
+            expected_size: 0,
+            read_size: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for SizeCheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_size = match self.inner.as_mut() {
+            None => return Ok(0),
+            Some(inner) => inner.read(buf)?,
+        };
+        self.read_size += read_size as u64;
+        let reached_end = read_size == 0 && !buf.is_empty();
+        if self.read_size > self.expected_size
+            || (reached_end && self.read_size != self.expected_size)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Decompressed content is not as expected",
+            ));
+        }
+        Ok(read_size)
+    }
+}
+
+/// Reads exactly `size` bytes from `reader`.
+///
+/// Sizes read from archives cannot be trusted, memory is thus allocated as
+/// data is actually read instead of upfront.
+pub(crate) fn read_exact_bounded<R: Read>(reader: R, size: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(cmp::min(size, MAX_PREALLOCATION_SIZE) as usize);
+    reader.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(GrufError::parsing_error("Unexpected end of data"));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_checked_reader() {
+        let data = [1u8, 2, 3, 4];
+        let mut output = Vec::new();
+        let mut reader = SizeCheckedReader::new(&data[..], 4);
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+
+        let mut reader = SizeCheckedReader::new(&data[..], 5);
+        assert!(reader.read_to_end(&mut output).is_err());
+        let mut reader = SizeCheckedReader::new(&data[..], 3);
+        assert!(reader.read_to_end(&mut output).is_err());
+
+        output.clear();
+        let mut reader = SizeCheckedReader::<&[u8]>::empty();
+        reader.read_to_end(&mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_display_path() {
+        let archive_path = "data\\texture\\\u{c0}\u{af}\u{c0}\u{fa}\u{c0}\u{ce}\u{c5}\u{cd}\u{c6}\u{e4}\u{c0}\u{cc}\u{bd}\u{ba}";
+        let display = "data\\texture\\유저인터페이스";
+        assert_eq!(display_path(archive_path), display);
+        assert_eq!(to_archive_path(display), archive_path);
+        assert_eq!(to_archive_path(archive_path), archive_path);
+        assert_eq!(to_archive_path("data\\a.txt"), "data\\a.txt");
+        assert_eq!(normalize_path(display), normalize_path(archive_path));
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path("data\\texture\\x.bmp"),
+            "data\\texture\\x.bmp"
+        );
+        assert_eq!(
+            normalize_path("DATA/Texture\\X.BMP"),
+            "data\\texture\\x.bmp"
+        );
+        // Non-ASCII characters are left untouched
+        assert_eq!(
+            normalize_path("data\\\u{C0}\u{AF}.bmp"),
+            "data\\\u{C0}\u{AF}.bmp"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{self, Read, Write};
+
+use crate::{GrufError, Result};
+use flate2::read::ZlibEncoder;
+use flate2::{write, Compression};
+use serde::{Deserialize, Serialize};
+
+const MAX_COMPRESSION_LEVEL: u32 = 9;
+
+/// Compression applied to entries' content when building archives.
+///
+/// Content is always stored as a zlib stream, for compatibility with the
+/// game client, stored content simply isn't compressed in that stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionPolicy {
+    /// Compress content with the given zlib level, from 0 (no compression) to
+    /// 9 (best compression)
+    Level(u32),
+    /// Store content without compressing it, for content that is already
+    /// compressed (e.g. .ogg, .jpg or .mp3 files)
+    Store,
+    /// Compress content with the default level and keep the smaller result
+    /// between compressed and stored content
+    Auto,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy::Level(Compression::default().level())
+    }
+}
+
+/// Compresses the content read from `data` as a zlib stream, following the
+/// given policy.
+///
+/// Returns the size of the content and the compressed content.
+pub(crate) fn compress<R: Read>(mut data: R, policy: CompressionPolicy) -> Result<(u64, Vec<u8>)> {
+    match policy {
+        CompressionPolicy::Level(level) => compress_with_level(data, compression_level(level)?),
+        CompressionPolicy::Store => compress_with_level(data, Compression::none()),
+        CompressionPolicy::Auto => {
+            let mut content = Vec::new();
+            data.read_to_end(&mut content)?;
+            let compressed = compress_with_level(content.as_slice(), Compression::default())?;
+            let stored = compress_with_level(content.as_slice(), Compression::none())?;
+            if compressed.1.len() < stored.1.len() {
+                Ok(compressed)
+            } else {
+                Ok(stored)
+            }
+        }
+    }
+}
+
+/// Compresses the content read from `data` as a zlib stream written into
+/// `writer`, without keeping it in memory.
+///
+/// `CompressionPolicy::Auto` requires the whole content and behaves like the
+/// default level here. Returns the size of the content.
+pub(crate) fn compress_into<R: Read, W: Write>(
+    mut data: R,
+    writer: W,
+    policy: CompressionPolicy,
+) -> Result<u64> {
+    let level = match policy {
+        CompressionPolicy::Level(level) => compression_level(level)?,
+        CompressionPolicy::Store => Compression::none(),
+        CompressionPolicy::Auto => Compression::default(),
+    };
+    let mut encoder = write::ZlibEncoder::new(writer, level);
+    let size = io::copy(&mut data, &mut encoder)?;
+    encoder.finish()?;
+    Ok(size)
+}
+
+fn compression_level(level: u32) -> Result<Compression> {
+    if level > MAX_COMPRESSION_LEVEL {
+        return Err(GrufError::serialization_error(format!(
+            "Invalid compression level: {}",
+            level
+        )));
+    }
+    Ok(Compression::new(level))
+}
+
+fn compress_with_level<R: Read>(data: R, level: Compression) -> Result<(u64, Vec<u8>)> {
+    let mut encoder = ZlibEncoder::new(data, level);
+    let mut compressed_data = Vec::new();
+    encoder.read_to_end(&mut compressed_data)?;
+    Ok((encoder.total_in(), compressed_data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        let mut content = Vec::new();
+        ZlibDecoder::new(data).read_to_end(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn test_compress() {
+        let compressible = vec![0x42u8; 4096];
+        let incompressible: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for content in &[&compressible, &incompressible] {
+            let stored = compress(content.as_slice(), CompressionPolicy::Store).unwrap();
+            assert_eq!(stored.0, content.len() as u64);
+            assert!(stored.1.len() > content.len());
+            assert_eq!(&decompress(&stored.1), *content);
+            for level in 0..=9 {
+                let compressed =
+                    compress(content.as_slice(), CompressionPolicy::Level(level)).unwrap();
+                assert_eq!(compressed.0, content.len() as u64);
+                assert_eq!(&decompress(&compressed.1), *content);
+            }
+            let auto = compress(content.as_slice(), CompressionPolicy::Auto).unwrap();
+            let default = compress(content.as_slice(), CompressionPolicy::default()).unwrap();
+            assert_eq!(auto.0, content.len() as u64);
+            assert_eq!(auto.1.len(), stored.1.len().min(default.1.len()));
+            assert_eq!(&decompress(&auto.1), *content);
+        }
+        assert!(compress(&compressible[..], CompressionPolicy::Level(10)).is_err());
+    }
+
+    #[test]
+    fn test_compress_into() {
+        let content = vec![0x42u8; 4096];
+        for policy in &[
+            CompressionPolicy::Store,
+            CompressionPolicy::Level(9),
+            CompressionPolicy::Auto,
+        ] {
+            let mut output = Vec::new();
+            let size = compress_into(content.as_slice(), &mut output, *policy).unwrap();
+            assert_eq!(size, content.len() as u64);
+            assert_eq!(output, compress(content.as_slice(), *policy).unwrap().1);
+        }
+        assert!(compress_into(&content[..], Vec::new(), CompressionPolicy::Level(10)).is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{serialize_to_win1252, to_archive_path};
+use crate::vfs::find_on_disk;
+use crate::{GrufError, Result};
+use encoding::all::WINDOWS_1252;
+use encoding::{DecoderTrap, Encoding};
+
+const DATA_INI_FILE_NAME: &str = "DATA.INI";
+const DATA_INI_SECTION: &str = "Data";
+
+/// Returns the path of the `DATA.INI` file of the client located in
+/// `client_dir`.
+///
+/// The file is looked up case-insensitively, `DATA.INI` is returned if it
+/// doesn't exist.
+pub fn data_ini_path<P: AsRef<Path>>(client_dir: P) -> PathBuf {
+    let client_dir = client_dir.as_ref();
+    find_on_disk(client_dir, DATA_INI_FILE_NAME)
+        .unwrap_or_else(|| client_dir.join(DATA_INI_FILE_NAME))
+}
+
+/// `DATA.INI` file, which lists the GRF archives loaded by the game client.
+///
+/// GRF archives are listed in the `[Data]` section, as `<priority>=<name>`
+/// entries where 0 is the highest priority. Lines that aren't modified
+/// (comments, other sections, unknown entries) are written back as they were
+/// read.
+#[derive(Debug, Clone)]
+pub struct DataIni {
+    lines: Vec<DataIniLine>,
+    line_ending: &'static str,
+}
+
+#[derive(Debug, Clone)]
+enum DataIniLine {
+    Raw(String),
+    Grf {
+        priority: u32,
+        grf_name: String,
+        // Original line, discarded once the entry is modified
+        raw: Option<String>,
+    },
+}
+
+impl Default for DataIni {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DataIni {
+    /// Creates an empty `DATA.INI` file
+    pub fn new() -> Self {
+        Self {
+            lines: Vec::new(),
+            line_ending: "\r\n",
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(data_ini_path: P) -> Result<Self> {
+        Self::parse(&fs::read(data_ini_path)?)
+    }
+
+    /// Parses the content of a `DATA.INI` file.
+    ///
+    /// GRF names are returned in the archives' form (see `to_archive_path`).
+    pub fn parse(content: &[u8]) -> Result<Self> {
+        let content = WINDOWS_1252
+            .decode(content, DecoderTrap::Strict)
+            .map_err(GrufError::parsing_error)?;
+        let line_ending = if content.contains("\r\n") || !content.contains('\n') {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let mut lines = Vec::new();
+        let mut in_data_section = false;
+        for line in content.lines() {
+            if let Some(section) = section_name(line) {
+                in_data_section = section.eq_ignore_ascii_case(DATA_INI_SECTION);
+            } else if in_data_section {
+                if let Some((priority, grf_name)) = parse_grf_entry(line) {
+                    lines.push(DataIniLine::Grf {
+                        priority,
+                        grf_name,
+                        raw: Some(line.to_string()),
+                    });
+                    continue;
+                }
+            }
+            lines.push(DataIniLine::Raw(line.to_string()));
+        }
+        Ok(Self { lines, line_ending })
+    }
+
+    /// Serializes the file, lines are terminated with the line ending used in
+    /// the parsed content (CRLF by default)
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut content = String::new();
+        for line in &self.lines {
+            match line {
+                DataIniLine::Raw(raw) | DataIniLine::Grf { raw: Some(raw), .. } => {
+                    content.push_str(raw)
+                }
+                DataIniLine::Grf {
+                    priority,
+                    grf_name,
+                    raw: None,
+                } => content.push_str(&format!("{}={}", priority, grf_name)),
+            }
+            content.push_str(self.line_ending);
+        }
+        serialize_to_win1252(&content)
+    }
+
+    /// Writes the file at the given path.
+    ///
+    /// The content is written into a temporary file first, which then replaces
+    /// the destination so that it's never left half-written.
+    pub fn write<P: AsRef<Path>>(&self, data_ini_path: P) -> Result<()> {
+        let data_ini_path = data_ini_path.as_ref();
+        let mut temporary_path = OsString::from(data_ini_path.as_os_str());
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        {
+            let mut temporary_file = File::create(&temporary_path)?;
+            temporary_file.write_all(&self.to_bytes()?)?;
+            temporary_file.sync_all()?;
+        }
+        fs::rename(&temporary_path, data_ini_path)?;
+        Ok(())
+    }
+
+    /// Returns the names of the GRF archives loaded by the client, by
+    /// decreasing priority.
+    ///
+    /// When several entries share the same priority, the last one is used.
+    pub fn grf_names(&self) -> Vec<String> {
+        self.loaded_grfs()
+            .into_iter()
+            .map(|(_, name)| String::from(name))
+            .collect()
+    }
+
+    /// Returns the priority of the given GRF archive if it's loaded by the
+    /// client. GRF names are compared case-insensitively.
+    pub fn priority<S: AsRef<str>>(&self, grf_name: S) -> Option<u32> {
+        let grf_name = to_archive_path(grf_name.as_ref());
+        self.loaded_grfs()
+            .into_iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(&grf_name))
+            .map(|(priority, _)| priority)
+    }
+
+    pub fn contains_grf<S: AsRef<str>>(&self, grf_name: S) -> bool {
+        self.priority(grf_name).is_some()
+    }
+
+    /// Adds a GRF archive with the given priority, if it isn't loaded already.
+    ///
+    /// GRF archives with the same or a lower priority are shifted, so that the
+    /// order of the other archives is preserved. Returns true if the archive
+    /// has been added.
+    pub fn insert_grf<S: AsRef<str>>(&mut self, priority: u32, grf_name: S) -> bool {
+        if self.contains_grf(&grf_name) {
+            return false;
+        }
+        if self.loaded_grfs().contains_key(&priority) {
+            for line in &mut self.lines {
+                if let DataIniLine::Grf {
+                    priority: entry_priority,
+                    raw,
+                    ..
+                } = line
+                {
+                    if *entry_priority >= priority {
+                        *entry_priority = entry_priority.saturating_add(1);
+                        *raw = None;
+                    }
+                }
+            }
+        }
+        let new_line = DataIniLine::Grf {
+            priority,
+            grf_name: to_archive_path(grf_name.as_ref()).into_owned(),
+            raw: None,
+        };
+        // Keep entries sorted by priority, following the file's layout
+        let position = self
+            .lines
+            .iter()
+            .position(|line| matches!(line, DataIniLine::Grf { priority: p, .. } if *p > priority))
+            .or_else(|| {
+                self.lines
+                    .iter()
+                    .rposition(|line| matches!(line, DataIniLine::Grf { .. }))
+                    .map(|i| i + 1)
+            })
+            .or_else(|| {
+                self.lines
+                    .iter()
+                    .position(|line| match line {
+                        DataIniLine::Raw(raw) => section_name(raw)
+                            .map_or(false, |name| name.eq_ignore_ascii_case(DATA_INI_SECTION)),
+                        _ => false,
+                    })
+                    .map(|i| i + 1)
+            });
+        match position {
+            Some(position) => self.lines.insert(position, new_line),
+            None => {
+                self.lines
+                    .push(DataIniLine::Raw(format!("[{}]", DATA_INI_SECTION)));
+                self.lines.push(new_line);
+            }
+        }
+        true
+    }
+
+    /// Removes all the entries of the given GRF archive. Returns true if an
+    /// entry has been removed.
+    pub fn remove_grf<S: AsRef<str>>(&mut self, grf_name: S) -> bool {
+        let grf_name = to_archive_path(grf_name.as_ref());
+        let line_count = self.lines.len();
+        self.lines.retain(|line| match line {
+            DataIniLine::Grf { grf_name: name, .. } => !name.eq_ignore_ascii_case(&grf_name),
+            _ => true,
+        });
+        self.lines.len() != line_count
+    }
+
+    fn loaded_grfs(&self) -> BTreeMap<u32, &str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                DataIniLine::Grf {
+                    priority, grf_name, ..
+                } => Some((*priority, grf_name.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn section_name(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix('[')
+        .and_then(|line| line.strip_suffix(']'))
+        .map(str::trim)
+}
+
+fn parse_grf_entry(line: &str) -> Option<(u32, String)> {
+    let line = line.trim();
+    if line.starts_with(';') || line.starts_with('#') {
+        return None;
+    }
+    let mut key_value = line.splitn(2, '=');
+    let priority = key_value.next()?.trim().parse().ok()?;
+    let grf_name = key_value.next()?.trim();
+    if grf_name.is_empty() {
+        return None;
+    }
+    Some((priority, grf_name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const DATA_INI: &str = "; Comment\r\n\
+                            [Data]\r\n\
+                            2=data.grf\r\n\
+                            0 = rdata.grf \r\n\
+                            #1=commented.grf\r\n\
+                            1=\r\n\
+                            [Other]\r\n\
+                            3=other.grf\r\n\
+                            [data]\r\n\
+                            1=sdata.grf\r\n";
+
+    #[test]
+    fn test_parse() {
+        let data_ini = DataIni::parse(DATA_INI.as_bytes()).unwrap();
+        assert_eq!(
+            data_ini.grf_names(),
+            vec!["rdata.grf", "sdata.grf", "data.grf"]
+        );
+        assert_eq!(data_ini.priority("DATA.GRF"), Some(2));
+        assert!(!data_ini.contains_grf("other.grf"));
+        assert!(!data_ini.contains_grf("commented.grf"));
+        // Unmodified files are written back as is
+        assert_eq!(data_ini.to_bytes().unwrap(), DATA_INI.as_bytes());
+        let content = b"[Data]\n0=\xb5\xa5\xc0\xcc\xc5\xcd.grf\n";
+        let data_ini = DataIni::parse(content).unwrap();
+        assert!(data_ini.contains_grf("데이터.grf"));
+        assert_eq!(data_ini.to_bytes().unwrap(), content);
+        // Duplicated priorities
+        let data_ini = DataIni::parse(b"[Data]\r\n0=a.grf\r\n0=b.grf").unwrap();
+        assert_eq!(data_ini.grf_names(), vec!["b.grf"]);
+        assert!(DataIni::parse(b"").unwrap().grf_names().is_empty());
+    }
+
+    #[test]
+    fn test_insert_grf() {
+        let mut data_ini = DataIni::parse(DATA_INI.as_bytes()).unwrap();
+        assert!(!data_ini.insert_grf(0, "Data.grf"));
+        assert!(data_ini.insert_grf(0, "myserver.grf"));
+        assert!(data_ini.insert_grf(10, "last.grf"));
+        assert_eq!(
+            data_ini.grf_names(),
+            vec![
+                "myserver.grf",
+                "rdata.grf",
+                "sdata.grf",
+                "data.grf",
+                "last.grf"
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(data_ini.to_bytes().unwrap()).unwrap(),
+            "; Comment\r\n\
+             [Data]\r\n\
+             0=myserver.grf\r\n\
+             3=data.grf\r\n\
+             1=rdata.grf\r\n\
+             #1=commented.grf\r\n\
+             1=\r\n\
+             [Other]\r\n\
+             3=other.grf\r\n\
+             [data]\r\n\
+             2=sdata.grf\r\n\
+             10=last.grf\r\n"
+        );
+        // Priorities that aren't used are kept as is
+        let mut data_ini = DataIni::parse(b"[Data]\n0=rdata.grf\n2=data.grf\n").unwrap();
+        assert!(data_ini.insert_grf(1, "myserver.grf"));
+        assert_eq!(
+            data_ini.to_bytes().unwrap(),
+            b"[Data]\n0=rdata.grf\n1=myserver.grf\n2=data.grf\n"
+        );
+        // Missing section
+        let mut data_ini = DataIni::parse(b"; Comment").unwrap();
+        assert!(data_ini.insert_grf(0, "data.grf"));
+        assert_eq!(
+            data_ini.to_bytes().unwrap(),
+            b"; Comment\r\n[Data]\r\n0=data.grf\r\n"
+        );
+        let mut data_ini = DataIni::new();
+        assert!(data_ini.insert_grf(0, "data.grf"));
+        assert_eq!(data_ini.to_bytes().unwrap(), b"[Data]\r\n0=data.grf\r\n");
+    }
+
+    #[test]
+    fn test_remove_grf() {
+        let mut data_ini = DataIni::parse(DATA_INI.as_bytes()).unwrap();
+        assert!(data_ini.remove_grf("SDATA.grf"));
+        assert!(!data_ini.remove_grf("sdata.grf"));
+        assert_eq!(data_ini.grf_names(), vec!["rdata.grf", "data.grf"]);
+        assert_eq!(
+            data_ini.to_bytes().unwrap(),
+            DATA_INI.replace("1=sdata.grf\r\n", "").as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let temp_dir = tempdir().unwrap();
+        assert_eq!(
+            data_ini_path(temp_dir.path()),
+            temp_dir.path().join("DATA.INI")
+        );
+        let path = temp_dir.path().join("data.ini");
+        let mut data_ini = DataIni::new();
+        data_ini.insert_grf(0, "data.grf");
+        data_ini.write(&path).unwrap();
+        assert_eq!(data_ini_path(temp_dir.path()), path);
+        assert_eq!(
+            DataIni::open(&path).unwrap().to_bytes().unwrap(),
+            data_ini.to_bytes().unwrap()
+        );
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+}
@@ -21,8 +21,12 @@ pub enum GrufError {
     InvalidContent(String),
     #[error("failed to serialize data: {0}")]
     SerializationError(String),
+    #[error("invalid pattern: {0}")]
+    InvalidPattern(String),
     #[error("dyn_alloc error")]
     DynAllocError,
+    #[error("archive has an interrupted commit")]
+    InterruptedCommit,
 }
 
 impl GrufError {
@@ -37,4 +41,8 @@ impl GrufError {
     pub fn serialization_error(msg: impl Into<String>) -> Self {
         Self::SerializationError(msg.into())
     }
+
+    pub fn invalid_pattern(msg: impl Into<String>) -> Self {
+        Self::InvalidPattern(msg.into())
+    }
 }
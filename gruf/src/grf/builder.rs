@@ -1,20 +1,44 @@
+use std::borrow::Cow;
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
-use crate::archive::{serialize_as_win1252_cstr_into, GenericFileEntry};
+use crate::archive::{
+    normalize_path, serialize_as_win1252_cstr_into, serialize_to_win1252, to_archive_path,
+    GenericFileEntry,
+};
+use crate::compression::{compress, compress_into};
+use crate::grf::crypto::{
+    decrypt_file_content, encrypt_file_content, encrypt_file_name, ContentEncryptor, DES_BLOCK_SIZE,
+};
 use crate::grf::dyn_alloc::{self, AvailableChunkList};
-use crate::grf::{GrfArchive, GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
+use crate::grf::journal::{recover_file, Journal};
+use crate::grf::reader::{
+    determine_file_encryption_101, table_info_padding, GrfEntryFlags, GrfFileEncryption,
+};
+use crate::grf::{GrfArchive, GrfFileEntry, GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
+use crate::parallel::{default_thread_count, process_in_order};
 use crate::thor::ThorArchive;
-use crate::{GrufError, Result};
+use crate::{CompressionPolicy, GrufError, Result};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use serde::Serialize;
+use twox_hash::xxh3;
 
 const GRF_FIXED_KEY: [u8; 14] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14];
+// Constants used to obfuscate sizes in GRF 1.x file tables
+const SIZE_COMPRESSED_OBFUSCATION_101: u32 = 0x02CB;
+const SIZE_COMPRESSED_ALIGNED_OBFUSCATION_101: u32 = 0x92CB;
+// Maximum amount of data moved past the end of the archive at once when
+// compacting an archive
+const COMPACTION_BATCH_SIZE: usize = 64 * 1024 * 1024;
+const COMPACTION_BUFFER_SIZE: usize = 1024 * 1024;
+// Size of the buffer used to copy and encrypt streamed entries, must be a
+// multiple of DES_BLOCK_SIZE
+const STREAMING_BUFFER_SIZE: usize = 1024 * 1024;
 
 pub struct GrfArchiveBuilder<W: Write + Seek> {
     obj: Box<W>,
@@ -22,8 +46,70 @@ pub struct GrfArchiveBuilder<W: Write + Seek> {
     finished: bool,
     version_major: u32,
     version_minor: u32,
-    entries: HashMap<String, GenericFileEntry>,
+    encrypt_entries: bool,
+    deduplicate_entries: bool,
+    compression: CompressionPolicy,
+    thread_count: usize,
+    // Entries are indexed by their normalized path. Ordered maps
+    // are used so that the generated archives are reproducible.
+    entries: BTreeMap<String, BuilderFileEntry>,
+    // Directory entries have no content, only their paths and
+    // flags are kept
+    directories: BTreeMap<String, (String, GrfEntryFlags)>,
     chunks: AvailableChunkList,
+    // Offset and size of the file table referenced by the archive's header
+    table_region: Option<(u64, usize)>,
+    // Content written by this builder, used to deduplicate
+    // entries. Content is also indexed by offset, in order to forget it once
+    // its chunk gets released or overwritten.
+    content_index: HashMap<ContentKey, u64>,
+    indexed_offsets: HashMap<u64, ContentKey>,
+    // Handle to the archive's file when the builder opened it,
+    // used to sync and truncate it whatever the builder's object is
+    file: Option<File>,
+    // Journal used to commit the header, in journaled mode
+    journal: Option<Journal>,
+    // Chunks referenced by the archive's current file table (data
+    // of shadowed entries and, in journaled mode, of replaced or removed
+    // entries) cannot be reused before a new table has been written. Releases
+    // are postponed until then.
+    pending_releases: Vec<(u64, usize)>,
+}
+
+struct BuilderFileEntry {
+    relative_path: String,
+    generic: GenericFileEntry,
+    // Encrypted content is padded to a multiple of 8 bytes
+    size_compressed_aligned: u32,
+    encryption: GrfFileEncryption,
+}
+
+// Compressed (and possibly encrypted) entry, ready to be written
+struct PreparedEntry {
+    relative_path: String,
+    content: Vec<u8>,
+    size: u32,
+    size_compressed: u32,
+    encryption: GrfFileEncryption,
+}
+
+// Data used by one or several entries, moved around when compacting archives
+struct DataRegion {
+    offset: u64,
+    size: usize,
+    // Keys of the entries using this region
+    keys: Vec<String>,
+}
+
+// Identifies the content of an entry, entries with the same key can share the
+// same data
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ContentKey {
+    digest: u128,
+    size_compressed_aligned: usize,
+    size: u32,
+    size_compressed: u32,
+    encryption: GrfFileEncryption,
 }
 
 #[derive(Debug, Serialize)]
@@ -35,6 +121,18 @@ struct SerializableGrfHeader {
     pub version: u32,
 }
 
+#[derive(Debug, Serialize)]
+struct SerializableGrfFileEntry101 {
+    // relative_path is obfuscated and isn't fixed-length
+    // relative_path_size_padded: u32,
+    // relative_path: Vec<u8>,
+    size_compressed_obfuscated: u32,
+    size_compressed_aligned_obfuscated: u32,
+    size: u32,
+    entry_type: u8,
+    offset: u32,
+}
+
 #[derive(Debug, Serialize)]
 struct SerializableGrfFileEntry200 {
     // Note(LinkZ): relative_path isn't fixed-length
@@ -46,9 +144,23 @@ struct SerializableGrfFileEntry200 {
     offset: u32,
 }
 
+#[derive(Debug, Serialize)]
+struct SerializableGrfFileEntry300 {
+    // relative_path isn't fixed-length
+    // relative_path: String,
+    size_compressed: u32,
+    size_compressed_aligned: u32,
+    size: u32,
+    entry_type: u8,
+    offset: u64,
+}
+
 impl<W: Write + Seek> GrfArchiveBuilder<W> {
     pub fn create(mut obj: W, version_major: u32, version_minor: u32) -> Result<Self> {
-        let start_offset = obj.seek(io::SeekFrom::Current(0)).unwrap_or(0);
+        if !is_supported_version(version_major, version_minor) {
+            return Err(GrufError::serialization_error("Wrong file format version"));
+        }
+        let start_offset = obj.seek(SeekFrom::Current(0)).unwrap_or(0);
         // Placeholder for the GRF header
         obj.write_all(&[0; GRF_HEADER_SIZE])?;
         Ok(Self {
@@ -57,46 +169,129 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             finished: false,
             version_major,
             version_minor,
-            entries: HashMap::new(),
+            encrypt_entries: false,
+            deduplicate_entries: false,
+            compression: CompressionPolicy::default(),
+            thread_count: default_thread_count(),
+            entries: BTreeMap::new(),
+            directories: BTreeMap::new(),
             chunks: AvailableChunkList::new(),
+            table_region: None,
+            content_index: HashMap::new(),
+            indexed_offsets: HashMap::new(),
+            file: None,
+            journal: None,
+            pending_releases: Vec::new(),
         })
     }
 
-    pub fn import_raw_entry_from_grf(
+    /// Makes the builder encrypt the content of entries added or imported
+    /// afterwards.
+    ///
+    /// Entries are encrypted the same way GRF 1.x archives' entries are. This
+    /// has no effect on GRF 1.x archives, which are always encrypted.
+    pub fn set_encrypt_entries(&mut self, encrypt_entries: bool) {
+        self.encrypt_entries = encrypt_entries;
+    }
+
+    /// Makes the builder deduplicate the content of entries added or imported
+    /// afterwards.
+    ///
+    /// Entries whose stored (compressed and possibly encrypted) content is
+    /// identical to the content of another entry written by this builder point
+    /// to that entry's data instead of storing it again.
+    pub fn set_deduplicate_entries(&mut self, deduplicate_entries: bool) {
+        self.deduplicate_entries = deduplicate_entries;
+    }
+
+    /// Sets the compression policy used for entries added afterwards with
+    /// `add_file`.
+    pub fn set_compression(&mut self, compression: CompressionPolicy) {
+        self.compression = compression;
+    }
+
+    /// Sets the number of worker threads used to compress entries added with
+    /// `add_files`. Defaults to the available parallelism.
+    pub fn set_thread_count(&mut self, thread_count: usize) {
+        self.thread_count = thread_count;
+    }
+
+    pub fn import_raw_entry_from_grf<R: Read + Seek>(
         &mut self,
-        archive: &mut GrfArchive,
+        archive: &mut GrfArchive<R>,
         relative_path: String,
     ) -> Result<()> {
         let entry = archive
             .get_file_entry(&relative_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
-        let content = archive.get_entry_raw_data(&relative_path)?;
-        let offset = {
-            if let Some(grf_entry) = self.entries.get(&relative_path) {
-                self.chunks.realloc_chunk(
-                    grf_entry.offset,
-                    grf_entry.size_compressed as usize,
-                    content.len(),
-                )?
-            } else {
-                self.chunks.alloc_chunk(content.len())?
-            }
+        let encryption = self.entry_encryption(&relative_path, entry.size_compressed);
+        if self.deduplicate_entries || encryption != entry.encryption {
+            // Content has to be loaded to be hashed or
+            // re-encrypted
+            let content = archive.get_entry_raw_data(&relative_path)?;
+            return self.import_raw_grf_content(relative_path, &entry, Cow::Owned(content));
+        }
+        let size_compressed_aligned = if entry.size == 0 {
+            0
+        } else {
+            u32::try_from(entry.size_compressed_aligned)?
         };
-
-        self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut content_reader = Cursor::new(content);
-        let content_size = io::copy(&mut content_reader, self.obj.by_ref())?;
-        debug_assert_eq!(entry.size_compressed_aligned as u64, content_size);
-        self.entries.insert(
+        let mut raw_data = archive.open_raw_entry(&relative_path)?;
+        self.copy_raw_entry(
             relative_path,
+            &mut raw_data,
             GenericFileEntry {
-                offset,
-                size: entry.size as u32,
-                size_compressed: entry.size_compressed_aligned as u32,
+                offset: 0,
+                size: u32::try_from(entry.size)?,
+                size_compressed: u32::try_from(entry.size_compressed)?,
             },
-        );
-        Ok(())
+            size_compressed_aligned,
+            encryption,
+        )
+    }
+
+    /// Imports an entry from an in-memory (or memory-mapped) archive without
+    /// copying its raw data beforehand.
+    pub fn import_raw_entry_from_grf_slice<T: AsRef<[u8]>>(
+        &mut self,
+        archive: &GrfArchive<Cursor<T>>,
+        relative_path: String,
+    ) -> Result<()> {
+        let entry = archive
+            .get_file_entry(&relative_path)
+            .ok_or(GrufError::EntryNotFound)?;
+        // Empty entries come without content, their chunk isn't copied
+        let content = archive.get_entry_raw_slice(&relative_path)?;
+        self.import_raw_grf_content(relative_path, entry, Cow::Borrowed(content))
+    }
+
+    pub(crate) fn import_raw_grf_content(
+        &mut self,
+        relative_path: String,
+        entry: &GrfFileEntry,
+        mut content: Cow<[u8]>,
+    ) -> Result<()> {
+        // Content might have to be re-encrypted if the archives' encryption
+        // schemes differ
+        let encryption = self.entry_encryption(&relative_path, entry.size_compressed);
+        if encryption != entry.encryption {
+            let content = content.to_mut();
+            if let GrfFileEncryption::Encrypted(cycle) = entry.encryption {
+                decrypt_file_content(content, cycle);
+            }
+            content.truncate(entry.size_compressed);
+            if let GrfFileEncryption::Encrypted(cycle) = encryption {
+                encrypt_file_content(content, cycle);
+            }
+        }
+        self.write_entry(
+            relative_path,
+            &content,
+            u32::try_from(entry.size)?,
+            u32::try_from(entry.size_compressed)?,
+            encryption,
+        )
     }
 
     pub fn import_raw_entry_from_thor<R: Read + Seek>(
@@ -108,72 +303,166 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
             .get_file_entry(&relative_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
-        let content = thor_archive.get_entry_raw_data(&relative_path)?;
-        let offset = {
-            if let Some(grf_entry) = self.entries.get(&relative_path) {
-                self.chunks.realloc_chunk(
-                    grf_entry.offset,
-                    grf_entry.size_compressed as usize,
-                    content.len(),
-                )?
-            } else {
-                self.chunks.alloc_chunk(content.len())?
-            }
-        };
-
-        self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut content_reader = Cursor::new(content);
-        let _ = io::copy(&mut content_reader, self.obj.by_ref())?;
-        self.entries.insert(
+        // THOR archives' content is never encrypted
+        let encryption = self.entry_encryption(&relative_path, entry.size_compressed);
+        if !self.deduplicate_entries && encryption == GrfFileEncryption::Unencrypted {
+            let mut raw_data = thor_archive.open_raw_entry(&relative_path)?;
+            return self.copy_raw_entry(
+                relative_path,
+                &mut raw_data,
+                GenericFileEntry {
+                    offset: 0,
+                    size: u32::try_from(entry.size)?,
+                    size_compressed: u32::try_from(entry.size_compressed)?,
+                },
+                u32::try_from(entry.size_compressed)?,
+                encryption,
+            );
+        }
+        let mut content = thor_archive.get_entry_raw_data(&relative_path)?;
+        if let GrfFileEncryption::Encrypted(cycle) = encryption {
+            encrypt_file_content(&mut content, cycle);
+        }
+        self.write_entry(
             relative_path,
-            GenericFileEntry {
-                offset,
-                size: entry.size as u32,
-                size_compressed: entry.size_compressed as u32,
+            content.as_slice(),
+            u32::try_from(entry.size)?,
+            u32::try_from(entry.size_compressed)?,
+            encryption,
+        )
+    }
+
+    pub fn add_file<R: Read>(&mut self, relative_path: String, data: R) -> Result<()> {
+        self.add_file_with_compression(relative_path, data, self.compression)
+    }
+
+    /// Adds a file, compressed with the given policy instead of the builder's
+    /// policy
+    pub fn add_file_with_compression<R: Read>(
+        &mut self,
+        relative_path: String,
+        data: R,
+        compression: CompressionPolicy,
+    ) -> Result<()> {
+        let prepared_entry =
+            prepare_entry(relative_path, data, compression, self.encrypts_entries())?;
+        self.write_prepared_entry(prepared_entry)
+    }
+
+    /// Adds several files, compressed on a pool of worker threads.
+    ///
+    /// Files are written in order, the resulting archive is the same as the
+    /// one obtained by calling `add_file` on each file.
+    pub fn add_files<I, R>(&mut self, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, R)>,
+        R: Read + Send,
+    {
+        let compression = self.compression;
+        self.add_files_with_compression(
+            files
+                .into_iter()
+                .map(|(relative_path, data)| (relative_path, data, compression)),
+        )
+    }
+
+    /// Adds several files, compressed on a pool of worker threads with their
+    /// own compression policy.
+    ///
+    /// Files are written in order, the resulting archive is the same as the
+    /// one obtained by calling `add_file_with_compression` on each file.
+    pub fn add_files_with_compression<I, R>(&mut self, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, R, CompressionPolicy)>,
+        R: Read + Send,
+    {
+        let encrypt = self.encrypts_entries();
+        process_in_order(
+            files,
+            self.thread_count,
+            |(relative_path, data, compression)| {
+                prepare_entry(relative_path, data, compression, encrypt)
             },
-        );
-        Ok(())
+            |prepared_entry| self.write_prepared_entry(prepared_entry),
+        )
     }
 
-    pub fn add_file<R: Read>(&mut self, relative_path: String, mut data: R) -> Result<()> {
-        // Compress it
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        let data_size = io::copy(data.by_ref(), &mut encoder)?;
-        let data_size_u32 = u32::try_from(data_size)?;
-        // Write compressed data
-        let compressed_data = encoder.finish()?;
-        let compressed_data_size = compressed_data.len();
-        let offset = {
-            if let Some(grf_entry) = self.entries.get(&relative_path) {
-                self.chunks.realloc_chunk(
-                    grf_entry.offset,
-                    grf_entry.size_compressed as usize,
-                    compressed_data_size,
-                )?
-            } else {
-                self.chunks.alloc_chunk(compressed_data_size)?
-            }
-        };
+    fn write_prepared_entry(&mut self, prepared_entry: PreparedEntry) -> Result<()> {
+        self.write_entry(
+            prepared_entry.relative_path,
+            prepared_entry.content.as_slice(),
+            prepared_entry.size,
+            prepared_entry.size_compressed,
+            prepared_entry.encryption,
+        )
+    }
+
+    /// Adds a file without keeping its compressed content in memory.
+    ///
+    /// Content is compressed into `spill` (a temporary file for example)
+    /// first, then copied into the archive, so memory usage doesn't depend on
+    /// the file's size. Such entries aren't deduplicated, and
+    /// `CompressionPolicy::Auto` behaves like the default level.
+    pub fn add_file_spilled<R, S>(
+        &mut self,
+        relative_path: String,
+        data: R,
+        mut spill: S,
+    ) -> Result<()>
+    where
+        R: Read,
+        S: Read + Write + Seek,
+    {
+        let relative_path = to_archive_path(&relative_path).into_owned();
+        let key = normalize_path(&relative_path);
+        spill.seek(SeekFrom::Start(0))?;
+        let size = u32::try_from(compress_into(data, &mut spill, self.compression)?)?;
+        let size_compressed = u32::try_from(spill.seek(SeekFrom::Current(0))?)?;
+        spill.seek(SeekFrom::Start(0))?;
+        let encryption = self.entry_encryption(&relative_path, size_compressed as usize);
+        let size_compressed_aligned = aligned_size(size_compressed, &encryption)?;
 
+        let offset = self.allocate_entry_chunk(&key, size_compressed_aligned as usize)?;
         self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
-        let mut compressed_reader = Cursor::new(compressed_data);
-        let _ = io::copy(&mut compressed_reader, self.obj.by_ref())?;
-        let compressed_data_size_u32 = u32::try_from(compressed_data_size)?;
-        self.entries.insert(
+        let mut encryptor = match encryption {
+            GrfFileEncryption::Encrypted(cycle) => Some(ContentEncryptor::new(cycle)),
+            GrfFileEncryption::Unencrypted => None,
+        };
+        let mut buffer = vec![0; STREAMING_BUFFER_SIZE];
+        let mut remaining_size = size_compressed_aligned as usize;
+        let mut remaining_content_size = size_compressed as usize;
+        while remaining_size > 0 {
+            let chunk = &mut buffer[..std::cmp::min(STREAMING_BUFFER_SIZE, remaining_size)];
+            // Encrypted content is padded with zeros
+            let content_size = std::cmp::min(chunk.len(), remaining_content_size);
+            spill.read_exact(&mut chunk[..content_size])?;
+            for b in &mut chunk[content_size..] {
+                *b = 0;
+            }
+            if let Some(encryptor) = &mut encryptor {
+                encryptor.encrypt_blocks(chunk);
+            }
+            self.obj.write_all(chunk)?;
+            remaining_size -= chunk.len();
+            remaining_content_size -= content_size;
+        }
+        self.insert_entry(
+            key,
             relative_path,
             GenericFileEntry {
                 offset,
-                size: data_size_u32,
-                size_compressed: compressed_data_size_u32,
+                size,
+                size_compressed,
             },
+            size_compressed_aligned,
+            encryption,
         );
         Ok(())
     }
 
     pub fn remove_file<S: AsRef<str>>(&mut self, relative_path: S) -> Result<bool> {
-        if let Some(entry) = self.entries.remove(relative_path.as_ref()) {
-            self.chunks
-                .free_chunk(entry.offset, entry.size_compressed as usize)?;
+        if let Some(entry) = self.entries.remove(&normalize_path(relative_path.as_ref())) {
+            self.release_chunk(entry.generic.offset, entry.size_compressed_aligned as usize)?;
             Ok(true)
         } else {
             Ok(false)
@@ -186,135 +475,1232 @@ impl<W: Write + Seek> GrfArchiveBuilder<W> {
         }
         self.finished = true;
 
-        let v_file_count = i32::try_from(self.entries.len() + 7)?;
-        let file_table_offset = match self.version_major {
-            2 => self.write_grf_table_200()?,
-            1 => std::unimplemented!(), // TODO(LinkZ): Implement
-            _ => return Err(GrufError::serialization_error("Wrong file format version")),
+        let table = self.serialize_grf_table()?;
+        let table_offset = if self.version_major == 1 {
+            // GRF 1.x tables span until the end of the file
+            let table_offset = self.chunks.end_offset();
+            self.chunks.reserve_chunk(table_offset, table.len())?;
+            table_offset
+        } else {
+            self.chunks.alloc_chunk(table.len())?
         };
+        let previous_table_region = self.table_region;
+        self.write_grf_table(table_offset, &table)?;
+        if self.journal.is_some() {
+            // The previous table can be reused
+            if let Some((offset, size)) = previous_table_region {
+                if size > 0 {
+                    self.chunks.free_chunk(offset, size)?;
+                }
+            }
+        }
+        // Content referenced only by the previous table can be reused
+        self.release_pending_chunks()?;
+        if let Some(file) = &self.file {
+            // Drop the space left unused after the last chunk
+            file.set_len(self.start_offset + self.chunks.end_offset())?;
+        }
+        Ok(())
+    }
+
+    /// Drops a reference to a chunk, its content is forgotten if the chunk
+    /// gets released
+    fn release_chunk(&mut self, offset: u64, size: usize) -> Result<()> {
+        if self.journal.is_some() {
+            self.pending_releases.push((offset, size));
+            return Ok(());
+        }
+        if self.chunks.free_chunk(offset, size)? {
+            self.forget_content(offset);
+        }
+        Ok(())
+    }
+
+    /// Releases the chunks whose release has been postponed until the file
+    /// table's commit
+    fn release_pending_chunks(&mut self) -> Result<()> {
+        for (offset, size) in std::mem::take(&mut self.pending_releases) {
+            if self.chunks.free_chunk(offset, size)? {
+                self.forget_content(offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn index_content(&mut self, content_key: ContentKey, offset: u64) {
+        if let Some(previous_key) = self.indexed_offsets.insert(offset, content_key.clone()) {
+            self.content_index.remove(&previous_key);
+        }
+        self.content_index.insert(content_key, offset);
+    }
+
+    fn forget_content(&mut self, offset: u64) {
+        if let Some(content_key) = self.indexed_offsets.remove(&offset) {
+            self.content_index.remove(&content_key);
+        }
+    }
+
+    fn serialize_grf_table(&self) -> Result<Vec<u8>> {
+        match self.version_major {
+            2 | 3 => self.serialize_grf_table_200(),
+            1 => self.serialize_grf_table_101(),
+            _ => Err(GrufError::serialization_error("Wrong file format version")),
+        }
+    }
+
+    /// Writes the file table at the given offset and updates the header to
+    /// reference it
+    fn write_grf_table(&mut self, table_offset: u64, table: &[u8]) -> Result<()> {
+        self.obj
+            .seek(SeekFrom::Start(self.start_offset + table_offset))?;
+        self.obj.write_all(table)?;
+        self.obj.flush()?;
         // Update the header
-        self.obj.seek(SeekFrom::Start(self.start_offset))?;
+        let v_file_count = i32::try_from(self.entries.len() + self.directories.len() + 7)?;
+        let mut header = Vec::with_capacity(GRF_HEADER_SIZE);
         write_grf_header(
             (self.version_major << 8) | (self.version_minor),
-            (file_table_offset - GRF_HEADER_SIZE as u64) as u32,
+            table_offset,
             v_file_count,
-            &mut self.obj,
-        )
+            &mut header,
+        )?;
+        match (&self.journal, self.file.as_mut()) {
+            (Some(journal), Some(file)) => journal.commit(file, self.start_offset, &header)?,
+            _ => {
+                self.obj.seek(SeekFrom::Start(self.start_offset))?;
+                self.obj.write_all(&header)?;
+                self.obj.flush()?;
+            }
+        }
+        self.table_region = Some((table_offset, table.len()));
+        Ok(())
+    }
+
+    /// Returns the encryption that must be applied to an entry's content,
+    /// depending on the archive's version and the builder's configuration
+    fn entry_encryption(&self, relative_path: &str, size_compressed: usize) -> GrfFileEncryption {
+        entry_encryption(self.encrypts_entries(), relative_path, size_compressed)
+    }
+
+    fn encrypts_entries(&self) -> bool {
+        self.version_major == 1 || self.encrypt_entries
+    }
+
+    /// Writes an entry's (compressed and possibly encrypted) content into the
+    /// archive and registers the entry.
+    fn write_entry(
+        &mut self,
+        relative_path: String,
+        content: &[u8],
+        size: u32,
+        size_compressed: u32,
+        encryption: GrfFileEncryption,
+    ) -> Result<()> {
+        let content_size = content.len();
+        let relative_path = to_archive_path(&relative_path).into_owned();
+        let key = normalize_path(&relative_path);
+        let content_key = if self.deduplicate_entries && !content.is_empty() {
+            Some(ContentKey {
+                digest: xxh3::hash128(content),
+                size_compressed_aligned: content_size,
+                size,
+                size_compressed,
+                encryption: encryption.clone(),
+            })
+        } else {
+            None
+        };
+        let duplicate_offset = content_key
+            .as_ref()
+            .and_then(|content_key| self.content_index.get(content_key))
+            .copied();
+        let offset = match duplicate_offset {
+            Some(duplicate_offset) => {
+                // Add the new reference before dropping the
+                // previous one, both might point to the same chunk
+                self.chunks
+                    .add_chunk_reference(duplicate_offset, content_size);
+                if let Some((offset, size)) = self.previous_chunk(&key) {
+                    self.release_chunk(offset, size)?;
+                }
+                duplicate_offset
+            }
+            None => {
+                let offset = self.allocate_entry_chunk(&key, content_size)?;
+                self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
+                self.obj.write_all(content)?;
+                if let Some(content_key) = content_key {
+                    self.index_content(content_key, offset);
+                }
+                offset
+            }
+        };
+        self.insert_entry(
+            key,
+            relative_path,
+            GenericFileEntry {
+                offset,
+                size,
+                size_compressed,
+            },
+            u32::try_from(content_size)?,
+            encryption,
+        );
+        Ok(())
+    }
+
+    /// Copies an entry's raw content from `raw_data` into the archive without
+    /// loading it into memory, then registers the entry.
+    ///
+    /// `raw_data` must provide `size_compressed_aligned` bytes of content that
+    /// doesn't need to be re-encrypted.
+    fn copy_raw_entry<R: Read>(
+        &mut self,
+        relative_path: String,
+        raw_data: &mut R,
+        generic: GenericFileEntry,
+        size_compressed_aligned: u32,
+        encryption: GrfFileEncryption,
+    ) -> Result<()> {
+        let relative_path = to_archive_path(&relative_path).into_owned();
+        let key = normalize_path(&relative_path);
+        let previous_chunk = self.previous_chunk(&key);
+        let offset = self.allocate_entry_chunk(&key, size_compressed_aligned as usize)?;
+        if let Err(e) = self.copy_into_chunk(offset, raw_data, size_compressed_aligned as u64) {
+            self.discard_entry_chunk(
+                &key,
+                offset,
+                size_compressed_aligned as usize,
+                previous_chunk,
+            );
+            return Err(e);
+        }
+        self.insert_entry(
+            key,
+            relative_path,
+            GenericFileEntry { offset, ..generic },
+            size_compressed_aligned,
+            encryption,
+        );
+        Ok(())
+    }
+
+    /// Copies `size` bytes from `raw_data` into the chunk located at `offset`
+    fn copy_into_chunk<R: Read>(&mut self, offset: u64, raw_data: &mut R, size: u64) -> Result<()> {
+        self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
+        let copied = io::copy(raw_data, self.obj.as_mut())?;
+        if copied != size {
+            return Err(GrufError::invalid_content("Entry's raw data is truncated"));
+        }
+        Ok(())
+    }
+
+    /// Undoes `allocate_entry_chunk` when the entry's content couldn't be
+    /// written into the allocated chunk
+    fn discard_entry_chunk(
+        &mut self,
+        key: &str,
+        offset: u64,
+        size: usize,
+        previous_chunk: Option<(u64, usize)>,
+    ) {
+        let _ = self.chunks.free_chunk(offset, size);
+        match previous_chunk {
+            Some(previous_chunk) if self.journal.is_some() => {
+                // The previous content is untouched and still in use
+                if let Some(i) = self
+                    .pending_releases
+                    .iter()
+                    .rposition(|chunk| *chunk == previous_chunk)
+                {
+                    self.pending_releases.remove(i);
+                }
+            }
+            Some(_) => {
+                // The previous content has been released or overwritten
+                self.entries.remove(key);
+            }
+            None => {}
+        }
+    }
+
+    /// Returns the offset and size of the chunk used by an entry, if any
+    fn previous_chunk(&self, key: &str) -> Option<(u64, usize)> {
+        self.entries.get(key).map(|grf_entry| {
+            (
+                grf_entry.generic.offset,
+                grf_entry.size_compressed_aligned as usize,
+            )
+        })
+    }
+
+    /// Allocates a chunk for an entry's content, reusing the chunk of the
+    /// entry it replaces when possible
+    fn allocate_entry_chunk(&mut self, key: &str, size: usize) -> Result<u64> {
+        let previous_chunk = self.previous_chunk(key);
+        let offset = match previous_chunk {
+            // In journaled mode, the previous content must stay
+            // untouched until the file table is committed
+            Some((offset, previous_size)) if self.journal.is_none() => {
+                // The previous content is about to be overwritten or moved,
+                // unless other entries use it
+                if !self.chunks.is_shared_chunk(offset) {
+                    self.forget_content(offset);
+                }
+                self.chunks.realloc_chunk(offset, previous_size, size)?
+            }
+            _ => self.chunks.alloc_chunk(size)?,
+        };
+        if self.version_major < 3 {
+            // Make sure the offset can be stored in the file
+            // table before writing anything
+            if let Err(e) = offset_to_u32(offset) {
+                let _ = self.chunks.free_chunk(offset, size);
+                if self.journal.is_none()
+                    && previous_chunk.map(|(previous_offset, _)| previous_offset) != Some(offset)
+                {
+                    // The previous content isn't reserved anymore
+                    self.entries.remove(key);
+                }
+                return Err(e);
+            }
+        }
+        if self.journal.is_some() {
+            if let Some((previous_offset, previous_size)) = previous_chunk {
+                self.release_chunk(previous_offset, previous_size)?;
+            }
+        }
+        Ok(offset)
+    }
+
+    /// Registers an entry whose content has been written
+    fn insert_entry(
+        &mut self,
+        key: String,
+        relative_path: String,
+        generic: GenericFileEntry,
+        size_compressed_aligned: u32,
+        encryption: GrfFileEncryption,
+    ) {
+        self.directories.remove(&key);
+        // Keep the original casing of replaced entries
+        let relative_path = match self.entries.get(&key) {
+            Some(grf_entry) => grf_entry.relative_path.clone(),
+            None => relative_path,
+        };
+        self.entries.insert(
+            key,
+            BuilderFileEntry {
+                relative_path,
+                generic,
+                size_compressed_aligned,
+                encryption,
+            },
+        );
     }
 
-    fn write_grf_table_200(&mut self) -> Result<u64> {
+    fn serialize_grf_table_101(&self) -> Result<Vec<u8>> {
         let mut table: Vec<u8> = Vec::new();
-        // Generate table and write files' content
-        for (relative_path, entry) in &self.entries {
-            let grf_file_entry = SerializableGrfFileEntry200 {
-                size_compressed: entry.size_compressed,
-                size_compressed_aligned: entry.size_compressed,
-                size: entry.size,
-                entry_type: 1,
-                offset: (entry.offset - GRF_HEADER_SIZE as u64) as u32,
+        // Generate table
+        for entry in self.entries.values() {
+            let grf_file_entry = SerializableGrfFileEntry101 {
+                size_compressed_obfuscated: entry
+                    .generic
+                    .size_compressed
+                    .checked_add(entry.generic.size)
+                    .and_then(|v| v.checked_add(SIZE_COMPRESSED_OBFUSCATION_101))
+                    .ok_or_else(|| GrufError::serialization_error("Entry is too big"))?,
+                size_compressed_aligned_obfuscated: entry
+                    .size_compressed_aligned
+                    .checked_add(SIZE_COMPRESSED_ALIGNED_OBFUSCATION_101)
+                    .ok_or_else(|| GrufError::serialization_error("Entry is too big"))?,
+                size: entry.generic.size,
+                // Encryption is implicit in GRF 1.x
+                entry_type: GrfEntryFlags::FILE.bits(),
+                offset: offset_to_u32(entry.generic.offset)?,
+            };
+            serialize_grf_file_entry_101_into(&mut table, &entry.relative_path, &grf_file_entry)?;
+        }
+        for (relative_path, flags) in self.directories.values() {
+            let grf_directory_entry = SerializableGrfFileEntry101 {
+                size_compressed_obfuscated: SIZE_COMPRESSED_OBFUSCATION_101,
+                size_compressed_aligned_obfuscated: SIZE_COMPRESSED_ALIGNED_OBFUSCATION_101,
+                size: 0,
+                entry_type: flags.bits(),
+                offset: 0,
             };
-            serialize_as_win1252_cstr_into(&mut table, &relative_path)?;
-            bincode::serialize_into(&mut table, &grf_file_entry)?;
+            serialize_grf_file_entry_101_into(&mut table, relative_path, &grf_directory_entry)?;
+        }
+        // The table isn't compressed in GRF 1.x
+        Ok(table)
+    }
+
+    fn serialize_grf_table_200(&self) -> Result<Vec<u8>> {
+        let mut table: Vec<u8> = Vec::new();
+        // Generate table
+        for entry in self.entries.values() {
+            let entry_type = GrfEntryFlags::from_encryption(&entry.encryption).bits();
+            serialize_as_win1252_cstr_into(&mut table, &entry.relative_path)?;
+            if self.version_major == 3 {
+                let grf_file_entry = SerializableGrfFileEntry300 {
+                    size_compressed: entry.generic.size_compressed,
+                    size_compressed_aligned: entry.size_compressed_aligned,
+                    size: entry.generic.size,
+                    entry_type,
+                    offset: entry.generic.offset - GRF_HEADER_SIZE as u64,
+                };
+                bincode::serialize_into(&mut table, &grf_file_entry)?;
+            } else {
+                let grf_file_entry = SerializableGrfFileEntry200 {
+                    size_compressed: entry.generic.size_compressed,
+                    size_compressed_aligned: entry.size_compressed_aligned,
+                    size: entry.generic.size,
+                    entry_type,
+                    offset: offset_to_u32(entry.generic.offset)?,
+                };
+                bincode::serialize_into(&mut table, &grf_file_entry)?;
+            }
+        }
+        for (relative_path, flags) in self.directories.values() {
+            serialize_as_win1252_cstr_into(&mut table, relative_path)?;
+            if self.version_major == 3 {
+                let grf_directory_entry = SerializableGrfFileEntry300 {
+                    size_compressed: 0,
+                    size_compressed_aligned: 0,
+                    size: 0,
+                    entry_type: flags.bits(),
+                    offset: 0,
+                };
+                bincode::serialize_into(&mut table, &grf_directory_entry)?;
+            } else {
+                let grf_directory_entry = SerializableGrfFileEntry200 {
+                    size_compressed: 0,
+                    size_compressed_aligned: 0,
+                    size: 0,
+                    entry_type: flags.bits(),
+                    offset: 0,
+                };
+                bincode::serialize_into(&mut table, &grf_directory_entry)?;
+            }
         }
         // Compress the table
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
         encoder.write_all(&table)?;
         let compressed_table = encoder.finish()?;
-        let compressed_table_size = compressed_table.len();
-        let table_offset = self
-            .chunks
-            .alloc_chunk(compressed_table_size + 2 * std::mem::size_of::<u32>())?;
         let table_size_u32 = u32::try_from(table.len())?;
-        let compressed_table_size_u32 = u32::try_from(compressed_table_size)?;
-        self.obj
-            .seek(SeekFrom::Start(self.start_offset + table_offset))?;
-        // Write table's offset and size
-        bincode::serialize_into(self.obj.by_ref(), &compressed_table_size_u32)?;
-        bincode::serialize_into(self.obj.by_ref(), &table_size_u32)?;
+        let compressed_table_size_u32 = u32::try_from(compressed_table.len())?;
+        let table_info_padding = table_info_padding(self.version_major);
+        let mut serialized_table = Vec::with_capacity(
+            table_info_padding + 2 * std::mem::size_of::<u32>() + compressed_table.len(),
+        );
+        // GRF 0x300 tables start with 4 unknown bytes
+        serialized_table.resize(table_info_padding, 0);
+        // Write table's compressed size and size
+        bincode::serialize_into(&mut serialized_table, &compressed_table_size_u32)?;
+        bincode::serialize_into(&mut serialized_table, &table_size_u32)?;
         // Write table's content
-        self.obj.write_all(&compressed_table)?;
-        // Return file table's offset
-        Ok(table_offset)
+        serialized_table.extend_from_slice(&compressed_table);
+        Ok(serialized_table)
     }
 }
 
-impl GrfArchiveBuilder<File> {
-    pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
-        let mut grf_archive = GrfArchive::open(&grf_path)?;
+impl<W: Read + Write + Seek> GrfArchiveBuilder<W> {
+    /// Opens an existing archive for in-place modifications.
+    ///
+    /// The archive is expected to start at the object's current position.
+    pub fn open(obj: W) -> Result<Self> {
+        let mut grf_archive = GrfArchive::new(obj)?;
+        // Only entries' chunks are used, the region of the
+        // current file table is available like any other unused space
         let chunks = dyn_alloc::list_available_chunks(&mut grf_archive)?;
-        let mut entries = HashMap::with_capacity(grf_archive.file_count());
+        let mut entries = BTreeMap::new();
         for entry in grf_archive.get_entries() {
             entries.insert(
-                entry.relative_path.clone(),
-                GenericFileEntry {
-                    offset: entry.offset,
-                    size: entry.size as u32,
-                    size_compressed: entry.size_compressed_aligned as u32,
+                normalize_path(&entry.relative_path),
+                BuilderFileEntry {
+                    relative_path: entry.relative_path.clone(),
+                    generic: GenericFileEntry {
+                        offset: entry.offset,
+                        size: entry.size as u32,
+                        size_compressed: entry.size_compressed as u32,
+                    },
+                    size_compressed_aligned: entry.size_compressed_aligned as u32,
+                    encryption: entry.encryption.clone(),
                 },
             );
         }
 
-        let file = OpenOptions::new().read(true).write(true).open(&grf_path)?;
+        let directories = grf_archive
+            .get_directory_entries()
+            .map(|entry| {
+                (
+                    normalize_path(&entry.relative_path),
+                    (entry.relative_path.clone(), entry.entry_type),
+                )
+            })
+            .collect();
+
+        let version_major = grf_archive.version_major();
+        let version_minor = grf_archive.version_minor();
+        let start_offset = grf_archive.start_offset();
+        let table_region = grf_archive.table_region();
+        // Shadowed entries are dropped from the new file table
+        let pending_releases = grf_archive.shadowed_chunks().to_vec();
         Ok(Self {
-            obj: Box::new(file),
-            start_offset: 0,
+            obj: Box::new(grf_archive.into_inner()),
+            start_offset,
             finished: false,
-            version_major: grf_archive.version_major(),
-            version_minor: grf_archive.version_minor(),
+            version_major,
+            version_minor,
+            encrypt_entries: false,
+            deduplicate_entries: false,
+            compression: CompressionPolicy::default(),
+            thread_count: default_thread_count(),
             entries,
+            directories,
             chunks,
+            table_region: Some(table_region),
+            content_index: HashMap::new(),
+            indexed_offsets: HashMap::new(),
+            file: None,
+            journal: None,
+            pending_releases,
         })
     }
-}
 
-impl<W: Write + Seek> Drop for GrfArchiveBuilder<W> {
-    // Automatically call finish on destruction
-    fn drop(&mut self) {
-        let _ = self.finish();
+    /// Moves entries towards the beginning of the archive to reclaim the
+    /// space left unused by removed and replaced entries, then writes the file
+    /// table right after the last entry. This finishes the archive.
+    ///
+    /// Entries are only ever copied into space that the archive's current file
+    /// table doesn't reference, and the header is updated after each step. An
+    /// interrupted compaction thus leaves a valid archive, which can be
+    /// compacted again later. Entries that cannot be moved directly are first
+    /// staged in batches after the entries' data, reusing space freed by
+    /// previous batches. The archive doesn't grow past its compacted size by
+    /// more than `COMPACTION_BATCH_SIZE` bytes plus a copy of the file table
+    /// (archives that are already bigger grow by a fraction of that).
+    ///
+    /// Returns the new size of the archive. Data past that size isn't used
+    /// anymore and can be truncated.
+    pub fn compact(&mut self) -> Result<u64> {
+        self.compact_in_batches(COMPACTION_BATCH_SIZE)
     }
-}
 
-fn write_grf_header<W: Write>(
-    version: u32,
-    file_table_offset: u32,
-    v_file_count: i32,
-    writer: &mut W,
-) -> Result<()> {
-    let grf_header = SerializableGrfHeader {
-        key: GRF_FIXED_KEY,
-        file_table_offset,
-        seed: 0,
-        v_file_count,
-        version,
-    };
-    writer.write_all(GRF_HEADER_MAGIC.as_bytes())?;
-    bincode::serialize_into(writer, &grf_header)?;
-    Ok(())
-}
+    fn compact_in_batches(&mut self, batch_size: usize) -> Result<u64> {
+        if self.journal.is_some() {
+            // Entries replaced or removed since the archive was
+            // opened are referenced by its table until a new one is committed
+            self.finish()?;
+        }
+        self.finished = true;
+        // Content is moved around, forget where it was
+        self.content_index.clear();
+        self.indexed_offsets.clear();
+        // Zero-sized entries don't need any space
+        for entry in self.entries.values_mut() {
+            if entry.size_compressed_aligned == 0 {
+                entry.generic.offset = GRF_HEADER_SIZE as u64;
+            }
+        }
+        self.chunks = AvailableChunkList::from_used_chunks(
+            self.entries
+                .values()
+                .map(|e| (e.generic.offset, e.size_compressed_aligned as usize)),
+        )?;
+        // Chunks that aren't used by entries are free already, the file
+        // table committed below doesn't reference them
+        self.pending_releases.clear();
+        if let Some((table_offset, table_size)) = self.table_region {
+            // The current table might have been overwritten by
+            // entries added since the archive was opened
+            if self.chunks.reserve_chunk(table_offset, table_size).is_err() {
+                self.table_region = None;
+            }
+        }
+        self.commit_grf_table(None)?;
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
-    use std::fs::File;
-    use std::path::PathBuf;
+        let mut buffer = vec![0; COMPACTION_BUFFER_SIZE];
+        // Fill available chunks with entries located after them
+        while self.fill_available_chunks(&mut buffer)? {}
+        // Slide the remaining entries down, batch by batch. Batches
+        // that fit in the gap left before them are moved directly, others
+        // overlap their destination and are staged after the entries' data
+        // first. Staging must not go further than `batch_size` bytes past the
+        // compacted archive's data.
+        let data_size: u64 = self.data_regions().iter().map(|r| r.size as u64).sum();
+        let staging_limit = GRF_HEADER_SIZE as u64 + data_size + batch_size as u64;
+        while let Some((destination_offset, gap_size)) = self.chunks.first_chunk() {
+            let staging_size = std::cmp::min(
+                staging_limit.saturating_sub(self.chunks.end_offset()),
+                batch_size as u64,
+            ) as usize;
+            // Archives that are already past the limit still stage
+            // reasonably big batches, the table is committed twice per batch
+            let max_batch_size = std::cmp::max(
+                std::cmp::max(staging_size, batch_size / 16),
+                std::cmp::min(gap_size, batch_size),
+            );
+            let batch = self.next_compaction_batch(max_batch_size);
+            if batch.is_empty() {
+                break;
+            }
+            let moved_size: usize = batch.iter().map(|region| region.size).sum();
+            let batch = if moved_size > gap_size {
+                self.move_data_regions(&batch, None, &mut buffer)?
+            } else {
+                batch
+            };
+            self.move_data_regions(&batch, Some(destination_offset), &mut buffer)?;
+        }
+        // Move the file table right after the last entry
+        while let Some((chunk_offset, chunk_size)) = self.chunks.first_chunk() {
+            let table = self.serialize_grf_table()?;
+            if table.is_empty() {
+                break;
+            }
+            if table.len() <= chunk_size {
+                self.commit_grf_table(Some(chunk_offset))?;
+            } else {
+                self.commit_grf_table(None)?;
+            }
+        }
+        Ok(self.chunks.end_offset())
+    }
 
-    use crate::grf::{GrfArchive, GrfArchiveBuilder, GrfFileEntry};
-    use tempfile::tempdir;
+    /// Writes the file table at the given offset (or after the entries'
+    /// data), updates the header and releases the previous table's chunk
+    fn commit_grf_table(&mut self, table_offset: Option<u64>) -> Result<()> {
+        let table = self.serialize_grf_table()?;
+        let table_offset = match table_offset {
+            Some(table_offset) => table_offset,
+            None => self.find_chunk_after_data(table.len()),
+        };
+        if !table.is_empty() {
+            self.chunks.reserve_chunk(table_offset, table.len())?;
+        }
+        let previous_table_region = self.table_region;
+        self.write_grf_table(table_offset, &table)?;
+        if let Some((offset, size)) = previous_table_region {
+            if size > 0 {
+                self.chunks.free_chunk(offset, size)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of the first available chunk located after the
+    /// entries' data that can hold `size` bytes, or the offset right after
+    /// the last used chunk.
+    ///
+    /// Space freed there is reused, so that the file table and staged entries
+    /// neither get in the way of entries moved towards the beginning nor make
+    /// the archive grow.
+    fn find_chunk_after_data(&self, size: usize) -> u64 {
+        let data_end_offset = self
+            .entries
+            .values()
+            .map(|e| e.generic.offset + e.size_compressed_aligned as u64)
+            .max()
+            .unwrap_or(GRF_HEADER_SIZE as u64);
+        self.chunks
+            .find_chunk_after(size, data_end_offset)
+            .unwrap_or_else(|| self.chunks.end_offset())
+    }
+
+    /// Returns the data regions used by entries, ordered by offset. Entries
+    /// sharing the same offset share the same region.
+    fn data_regions(&self) -> Vec<DataRegion> {
+        let mut regions: HashMap<u64, DataRegion> = HashMap::new();
+        for (key, entry) in &self.entries {
+            let size = entry.size_compressed_aligned as usize;
+            if size == 0 {
+                continue;
+            }
+            let region = regions
+                .entry(entry.generic.offset)
+                .or_insert_with(|| DataRegion {
+                    offset: entry.generic.offset,
+                    size,
+                    keys: Vec::new(),
+                });
+            region.size = std::cmp::max(region.size, size);
+            region.keys.push(key.clone());
+        }
+        let mut regions: Vec<DataRegion> = regions.into_iter().map(|(_, v)| v).collect();
+        regions.sort_unstable_by_key(|r| r.offset);
+        regions
+    }
+
+    /// Moves data regions into the first available chunks located before them.
+    ///
+    /// Returns false if no region could be moved.
+    fn fill_available_chunks(&mut self, buffer: &mut [u8]) -> Result<bool> {
+        let mut moved_regions = Vec::new();
+        // Start with the last regions
+        for region in self.data_regions().into_iter().rev() {
+            if let Some(new_offset) = self.chunks.find_chunk_before(region.size, region.offset) {
+                self.move_data_region(&region, new_offset, buffer)?;
+                moved_regions.push(region);
+            }
+        }
+        if moved_regions.is_empty() {
+            return Ok(false);
+        }
+        // Moved regions are referenced by the current file table
+        // and cannot be released before it's replaced
+        self.commit_grf_table(None)?;
+        for region in moved_regions {
+            self.release_data_region(&region)?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the contiguous data regions located right after the first
+    /// available chunk, up to `max_size` bytes (or a single region)
+    fn next_compaction_batch(&self, max_size: usize) -> Vec<DataRegion> {
+        let first_used_offset = match self.chunks.first_chunk() {
+            Some((offset, size)) => offset + size as u64,
+            None => return Vec::new(),
+        };
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        let mut next_offset = first_used_offset;
+        for region in self.data_regions() {
+            if region.offset < first_used_offset {
+                continue;
+            }
+            if region.offset != next_offset
+                || (!batch.is_empty() && batch_size + region.size > max_size)
+            {
+                break;
+            }
+            batch_size += region.size;
+            next_offset += region.size as u64;
+            batch.push(region);
+        }
+        batch
+    }
+
+    /// Moves data regions one after the other, starting at the given offset
+    /// (or after the entries' data), then commits the file table
+    fn move_data_regions(
+        &mut self,
+        regions: &[DataRegion],
+        destination_offset: Option<u64>,
+        buffer: &mut [u8],
+    ) -> Result<Vec<DataRegion>> {
+        let mut new_offset = match destination_offset {
+            Some(destination_offset) => destination_offset,
+            None => self.find_chunk_after_data(regions.iter().map(|r| r.size).sum()),
+        };
+        let mut moved_regions = Vec::with_capacity(regions.len());
+        for region in regions {
+            self.move_data_region(region, new_offset, buffer)?;
+            moved_regions.push(DataRegion {
+                offset: new_offset,
+                size: region.size,
+                keys: region.keys.clone(),
+            });
+            new_offset += region.size as u64;
+        }
+        self.commit_grf_table(None)?;
+        for region in regions {
+            self.release_data_region(region)?;
+        }
+        Ok(moved_regions)
+    }
+
+    /// Copies a data region to a new location and updates the entries using it
+    fn move_data_region(
+        &mut self,
+        region: &DataRegion,
+        new_offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<()> {
+        self.chunks.reserve_chunk(new_offset, region.size)?;
+        for _ in 1..region.keys.len() {
+            self.chunks.add_chunk_reference(new_offset, region.size);
+        }
+        self.copy_chunk(region.offset, new_offset, region.size, buffer)?;
+        for key in &region.keys {
+            if let Some(entry) = self.entries.get_mut(key) {
+                entry.generic.offset = new_offset;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the references to a data region's previous location
+    fn release_data_region(&mut self, region: &DataRegion) -> Result<()> {
+        for _ in &region.keys {
+            self.chunks.free_chunk(region.offset, region.size)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a file without keeping its compressed content in memory, like
+    /// `add_file_spilled` but without a spill file.
+    ///
+    /// Content is compressed directly after the last used chunk of the
+    /// archive, then registered once its size is known. Encrypted content is
+    /// read back to be encrypted in place. The same limitations as
+    /// `add_file_spilled` apply.
+    pub fn add_file_streamed<R: Read>(&mut self, relative_path: String, data: R) -> Result<()> {
+        let relative_path = to_archive_path(&relative_path).into_owned();
+        let key = normalize_path(&relative_path);
+        let offset = self.chunks.end_offset();
+        if self.version_major < 3 {
+            offset_to_u32(offset)?;
+        }
+        self.obj.seek(SeekFrom::Start(self.start_offset + offset))?;
+        let size = u32::try_from(compress_into(data, &mut self.obj, self.compression)?)?;
+        let size_compressed =
+            u32::try_from(self.obj.seek(SeekFrom::Current(0))? - self.start_offset - offset)?;
+        let encryption = self.entry_encryption(&relative_path, size_compressed as usize);
+        let size_compressed_aligned = aligned_size(size_compressed, &encryption)?;
+        if let GrfFileEncryption::Encrypted(cycle) = encryption {
+            let padding = [0; DES_BLOCK_SIZE];
+            self.obj
+                .write_all(&padding[..(size_compressed_aligned - size_compressed) as usize])?;
+            self.encrypt_chunk(offset, size_compressed_aligned as usize, cycle)?;
+        }
+
+        // The previous content is released only once the new
+        // content has been written
+        self.chunks
+            .reserve_chunk(offset, size_compressed_aligned as usize)?;
+        if let Some((previous_offset, previous_size)) = self.previous_chunk(&key) {
+            self.release_chunk(previous_offset, previous_size)?;
+        }
+        self.insert_entry(
+            key,
+            relative_path,
+            GenericFileEntry {
+                offset,
+                size,
+                size_compressed,
+            },
+            size_compressed_aligned,
+            encryption,
+        );
+        Ok(())
+    }
+
+    /// Encrypts a chunk of the archive in place
+    fn encrypt_chunk(&mut self, offset: u64, size: usize, cycle: usize) -> Result<()> {
+        let mut encryptor = ContentEncryptor::new(cycle);
+        let mut buffer = vec![0; std::cmp::min(STREAMING_BUFFER_SIZE, size)];
+        let mut encrypted = 0;
+        while encrypted < size {
+            let chunk = &mut buffer[..std::cmp::min(STREAMING_BUFFER_SIZE, size - encrypted)];
+            let chunk_offset = self.start_offset + offset + encrypted as u64;
+            self.obj.seek(SeekFrom::Start(chunk_offset))?;
+            self.obj.read_exact(chunk)?;
+            encryptor.encrypt_blocks(chunk);
+            self.obj.seek(SeekFrom::Start(chunk_offset))?;
+            self.obj.write_all(chunk)?;
+            encrypted += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Copies data between two chunks which don't overlap
+    fn copy_chunk(&mut self, from: u64, to: u64, size: usize, buffer: &mut [u8]) -> Result<()> {
+        let mut copied = 0;
+        while copied < size {
+            let length = std::cmp::min(buffer.len(), size - copied);
+            self.obj
+                .seek(SeekFrom::Start(self.start_offset + from + copied as u64))?;
+            self.obj.read_exact(&mut buffer[..length])?;
+            self.obj
+                .seek(SeekFrom::Start(self.start_offset + to + copied as u64))?;
+            self.obj.write_all(&buffer[..length])?;
+            copied += length;
+        }
+        Ok(())
+    }
+}
+
+/// Compacts a GRF archive in place, then truncates it.
+///
+/// The archive is opened in journaled mode (see
+/// `GrfArchiveBuilder::open_journaled`): commits interrupted beforehand are
+/// recovered first, and each step of the compaction is committed through the
+/// journal. Returns the number of bytes reclaimed. See
+/// `GrfArchiveBuilder::compact`.
+pub fn compact_file<P: AsRef<Path>>(grf_path: P) -> Result<u64> {
+    let mut builder = GrfArchiveBuilder::open_journaled(&grf_path)?;
+    let grf_file = OpenOptions::new().write(true).open(&grf_path)?;
+    let previous_size = grf_file.metadata()?.len();
+    let new_size = builder.compact()?;
+    grf_file.set_len(new_size)?;
+    grf_file.sync_all()?;
+    Ok(previous_size.saturating_sub(new_size))
+}
+
+impl GrfArchiveBuilder<File> {
+    /// Opens the archive located at the given path for in-place
+    /// modifications.
+    ///
+    /// The file is truncated once the archive is finished, so that it doesn't
+    /// keep space left unused after the last entry or table.
+    pub fn open_file<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
+        let grf_file = OpenOptions::new().read(true).write(true).open(grf_path)?;
+        let file = grf_file.try_clone()?;
+        let mut builder = Self::open(grf_file)?;
+        builder.file = Some(file);
+        Ok(builder)
+    }
+
+    /// Opens the archive located at the given path for in-place modifications
+    /// in journaled mode.
+    ///
+    /// In this mode, data referenced by the archive's current file table is
+    /// never overwritten and the new header is committed through a journal
+    /// (see `gruf::grf::journal`), so that the archive is left either in its
+    /// previous or in its new state if the process is interrupted. Changes
+    /// are only committed by `finish` (or `compact`), dropping the builder
+    /// discards them. Like with `open_file`, the file is truncated once the
+    /// archive is finished.
+    ///
+    /// Commits interrupted beforehand are recovered first.
+    pub fn open_journaled<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
+        recover_file(&grf_path)?;
+        let grf_file = OpenOptions::new().read(true).write(true).open(&grf_path)?;
+        let file = grf_file.try_clone()?;
+        let mut builder = Self::open(grf_file)?;
+        builder.file = Some(file);
+        builder.journal = Some(Journal::new(&grf_path));
+        if let Some((table_offset, table_size)) = builder.table_region {
+            builder.chunks.reserve_chunk(table_offset, table_size)?;
+        }
+        Ok(builder)
+    }
+}
+
+impl<W: Write + Seek> Drop for GrfArchiveBuilder<W> {
+    // Automatically call finish on destruction, unless changes must be
+    // committed explicitly
+    fn drop(&mut self) {
+        if self.journal.is_none() {
+            let _ = self.finish();
+        }
+    }
+}
+
+/// Returns the size of an entry's content once encrypted (if needed)
+fn aligned_size(size_compressed: u32, encryption: &GrfFileEncryption) -> Result<u32> {
+    match encryption {
+        GrfFileEncryption::Unencrypted => Ok(size_compressed),
+        GrfFileEncryption::Encrypted(_) => {
+            let block_size = DES_BLOCK_SIZE as u32;
+            size_compressed
+                .checked_add(block_size - 1)
+                .map(|size| size / block_size * block_size)
+                .ok_or_else(|| GrufError::serialization_error("Entry is too big"))
+        }
+    }
+}
+
+/// Returns the encryption applied to an entry's content
+fn entry_encryption(
+    encrypt_entries: bool,
+    relative_path: &str,
+    size_compressed: usize,
+) -> GrfFileEncryption {
+    if encrypt_entries {
+        determine_file_encryption_101(relative_path, size_compressed)
+    } else {
+        GrfFileEncryption::Unencrypted
+    }
+}
+
+/// Compresses and encrypts (if needed) an entry's content before it gets
+/// written
+fn prepare_entry<R: Read>(
+    relative_path: String,
+    data: R,
+    compression: CompressionPolicy,
+    encrypt_entries: bool,
+) -> Result<PreparedEntry> {
+    // Compress it
+    let (data_size, mut content) = compress(data, compression)?;
+    let size = u32::try_from(data_size)?;
+    let size_compressed = u32::try_from(content.len())?;
+    // Encrypt it if needed
+    let encryption = entry_encryption(encrypt_entries, &relative_path, content.len());
+    if let GrfFileEncryption::Encrypted(cycle) = encryption {
+        encrypt_file_content(&mut content, cycle);
+    }
+    Ok(PreparedEntry {
+        relative_path,
+        content,
+        size,
+        size_compressed,
+        encryption,
+    })
+}
+
+fn is_supported_version(version_major: u32, version_minor: u32) -> bool {
+    match version_major {
+        // Only versions 1.1, 1.2 and 1.3 are supported
+        1 => (1..=3).contains(&version_minor),
+        2 | 3 => version_minor == 0,
+        _ => false,
+    }
+}
+
+/// Serializes a GRF 1.x file entry (including its obfuscated path) and write it
+/// into writer.
+fn serialize_grf_file_entry_101_into<W: Write>(
+    mut writer: W,
+    relative_path: &str,
+    grf_file_entry: &SerializableGrfFileEntry101,
+) -> Result<()> {
+    let obfuscated_path = encrypt_file_name(&serialize_to_win1252(relative_path)?);
+    // Size of the path includes the surrounding NUL chars
+    let path_size_padded = u32::try_from(obfuscated_path.len() + 6)?;
+    bincode::serialize_into(writer.by_ref(), &path_size_padded)?;
+    writer.write_all(&[0; 2])?;
+    writer.write_all(&obfuscated_path)?;
+    writer.write_all(&[0; 4])?;
+    bincode::serialize_into(writer.by_ref(), grf_file_entry)?;
+    Ok(())
+}
+
+/// Converts an entry's offset into the 32-bit offset stored in GRF 1.x and
+/// 2.0 file tables
+fn offset_to_u32(offset: u64) -> Result<u32> {
+    u32::try_from(offset - GRF_HEADER_SIZE as u64).map_err(|_| {
+        GrufError::serialization_error("Archive is too big for this version, GRF 0x300 is required")
+    })
+}
+
+/// Writes a GRF header referencing a file table located at table_offset
+fn write_grf_header<W: Write>(
+    version: u32,
+    table_offset: u64,
+    v_file_count: i32,
+    writer: &mut W,
+) -> Result<()> {
+    let grf_header = if (version >> 8) == 3 {
+        // GRF 0x300 has no seed, the offset is stored on 64 bits
+        let file_table_offset = table_offset - GRF_HEADER_SIZE as u64;
+        SerializableGrfHeader {
+            key: GRF_FIXED_KEY,
+            file_table_offset: file_table_offset as u32,
+            seed: (file_table_offset >> 32) as u32 as i32,
+            v_file_count,
+            version,
+        }
+    } else {
+        SerializableGrfHeader {
+            key: GRF_FIXED_KEY,
+            file_table_offset: offset_to_u32(table_offset)?,
+            seed: 0,
+            v_file_count,
+            version,
+        }
+    };
+    writer.write_all(GRF_HEADER_MAGIC.as_bytes())?;
+    bincode::serialize_into(writer, &grf_header)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+
+    use super::BuilderFileEntry;
+    use crate::archive::GenericFileEntry;
+    use crate::grf::dyn_alloc::AvailableChunkList;
+    use crate::grf::fsck::{check, check_file};
+    use crate::grf::journal::journal_path;
+    use crate::grf::reader::{determine_file_encryption_101, GRF_HEADER_SIZE};
+    use crate::grf::{
+        build_test_archive, compact_file, GrfArchive, GrfArchiveBuilder, GrfEntryFlags,
+        GrfFileEncryption, GrfFileEntry, GRF_HEADER_MAGIC,
+    };
+    use crate::thor::ThorArchive;
+    use crate::CompressionPolicy;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use tempfile::tempdir;
 
     #[test]
     fn test_add_file() {
         let temp_dir = tempdir().unwrap();
-        let output_path = temp_dir.path().join("200-builder.grf");
+        // Incompressible data, big enough to be partially encrypted in GRF 1.x
+        let random_data: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
         let test_content = vec![
             ("data\\file.gat", vec![0u8; 60]),
             ("data\\subfolder\\file.gnd", vec![0xCCu8; 341]),
             ("data\\file.gat", (0..129).collect()), // Overwrite
             ("data\\file2.gat", vec![3u8; 60]),
+            ("data\\file3.bmp", random_data.clone()),
         ];
         let expected_content: HashMap<&str, Vec<u8>> = [
             ("data\\file.gat", (0..129).collect()),
             ("data\\file2.gat", vec![3u8; 60]),
             ("data\\subfolder\\file.gnd", vec![0xCCu8; 341]),
+            ("data\\file3.bmp", random_data.clone()),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        for (version_major, version_minor) in &[(3, 0), (2, 0), (1, 1), (1, 2), (1, 3)] {
+            let output_path = temp_dir
+                .path()
+                .join(format!("{}0{}-builder.grf", version_major, version_minor));
+            // Generate
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder =
+                    GrfArchiveBuilder::create(output_file, *version_major, *version_minor).unwrap();
+                for (name, content) in &test_content {
+                    builder
+                        .add_file(name.to_string(), content.as_slice())
+                        .unwrap();
+                }
+                // Call finish manually, even though builder will be dropped on scope exit
+                builder.finish().unwrap();
+            }
+            // Check result
+            {
+                let mut grf_archive = GrfArchive::open(&output_path).unwrap();
+                assert_eq!(grf_archive.version_major(), *version_major);
+                assert_eq!(grf_archive.version_minor(), *version_minor);
+                assert_eq!(grf_archive.file_count(), expected_content.len());
+                let file_entries: Vec<GrfFileEntry> = grf_archive.get_entries().cloned().collect();
+                assert_eq!(file_entries.len(), expected_content.len());
+                for entry in file_entries {
+                    let file_path: &str = entry.relative_path.as_str();
+                    assert!(expected_content.contains_key(file_path));
+                    let expected_data = &expected_content[file_path];
+                    // Size check
+                    assert_eq!(expected_data.len(), entry.size);
+                    // Content check
+                    assert_eq!(
+                        expected_data,
+                        &grf_archive.read_file_content(file_path).unwrap()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_replace_file_case_insensitive() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-case.grf");
+        {
+            let grf_file = File::create(&grf_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+            builder
+                .add_file("data\\texture\\x.bmp".to_string(), &b"old"[..])
+                .unwrap();
+        }
+        {
+            let grf_file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&grf_path)
+                .unwrap();
+            let mut builder = GrfArchiveBuilder::open(grf_file).unwrap();
+            builder
+                .add_file("DATA/Texture/X.BMP".to_string(), &b"new"[..])
+                .unwrap();
+            builder
+                .add_file("data\\texture\\y.bmp".to_string(), &b"y"[..])
+                .unwrap();
+            assert!(builder.remove_file("Data/Texture/Y.bmp").unwrap());
+        }
+        let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf_archive.file_count(), 1);
+        let entry = grf_archive.get_entries().next().unwrap();
+        // The original casing is preserved
+        assert_eq!(entry.relative_path, "data\\texture\\x.bmp");
+        assert_eq!(
+            grf_archive
+                .read_file_content("data\\texture\\x.bmp")
+                .unwrap(),
+            b"new"
+        );
+    }
+
+    #[test]
+    fn test_korean_paths() {
+        let temp_dir = tempdir().unwrap();
+        let display_path = "data\\유저인터페이스\\a.bmp";
+        let raw_path: &[u8] = &[
+            0x64, 0x61, 0x74, 0x61, 0x5C, 0xC0, 0xAF, 0xC0, 0xFA, 0xC0, 0xCE, 0xC5, 0xCD, 0xC6,
+            0xE4, 0xC0, 0xCC, 0xBD, 0xBA, 0x5C, 0x61, 0x2E, 0x62, 0x6D, 0x70,
+        ];
+        for (version_major, version_minor) in &[(2, 0), (1, 3)] {
+            let grf_path = temp_dir
+                .path()
+                .join(format!("{}0{}-korean.grf", version_major, version_minor));
+            {
+                let grf_file = File::create(&grf_path).unwrap();
+                let mut builder =
+                    GrfArchiveBuilder::create(grf_file, *version_major, *version_minor).unwrap();
+                builder
+                    .add_file(display_path.to_string(), &b"content"[..])
+                    .unwrap();
+            }
+            // Re-open the archive in-place and replace the entry using the
+            // other form of the path
+            let archive_path = {
+                let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+                let file_entry = grf_archive.get_file_entry(display_path).unwrap().clone();
+                assert_eq!(file_entry.raw_path().unwrap(), raw_path);
+                assert_eq!(file_entry.display_path(), display_path);
+                assert_eq!(
+                    grf_archive
+                        .read_file_content(&file_entry.relative_path)
+                        .unwrap(),
+                    b"content"
+                );
+                file_entry.relative_path
+            };
+            {
+                let grf_file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&grf_path)
+                    .unwrap();
+                let mut builder = GrfArchiveBuilder::open(grf_file).unwrap();
+                builder
+                    .add_file(archive_path.clone(), &b"new content"[..])
+                    .unwrap();
+            }
+            let mut grf_archive = GrfArchive::open(&grf_path).unwrap();
+            assert_eq!(grf_archive.file_count(), 1);
+            let file_entry = grf_archive.get_entries().next().unwrap();
+            assert_eq!(file_entry.raw_path().unwrap(), raw_path);
+            assert_eq!(
+                grf_archive.read_file_content(display_path).unwrap(),
+                b"new content"
+            );
+        }
+    }
+
+    #[test]
+    fn test_add_file_encrypted() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("200-encrypted.grf");
+        let random_data: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let expected_content: HashMap<&str, (Vec<u8>, u8)> = [
+            ("data\\file.gat", (random_data.clone(), 0x05)),
+            ("data\\file.bmp", (random_data.clone(), 0x03)),
         ]
         .iter()
         .cloned()
@@ -323,24 +1709,27 @@ mod tests {
         {
             let output_file = File::create(&output_path).unwrap();
             let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
-            for (name, content) in &test_content {
+            builder.set_encrypt_entries(true);
+            for (name, (content, _)) in &expected_content {
                 builder
                     .add_file(name.to_string(), content.as_slice())
                     .unwrap();
             }
-            // Call finish manually, even though builder will be dropped on scope exit
-            builder.finish().unwrap();
         }
         // Check result
         {
             let mut grf_archive = GrfArchive::open(&output_path).unwrap();
             let file_entries: Vec<GrfFileEntry> = grf_archive.get_entries().cloned().collect();
+            assert_eq!(file_entries.len(), expected_content.len());
             for entry in file_entries {
                 let file_path: &str = entry.relative_path.as_str();
-                assert!(expected_content.contains_key(file_path));
-                let expected_data = &expected_content[file_path];
-                // Size check
-                assert_eq!(expected_data.len(), entry.size);
+                let (expected_data, expected_entry_type) = &expected_content[file_path];
+                assert_eq!(entry.entry_type.bits(), *expected_entry_type);
+                assert_eq!(entry.size_compressed_aligned % 8, 0);
+                assert_eq!(
+                    entry.encryption,
+                    determine_file_encryption_101(file_path, entry.size_compressed)
+                );
                 // Content check
                 assert_eq!(
                     expected_data,
@@ -348,43 +1737,1164 @@ mod tests {
                 );
             }
         }
+        // Flags should be preserved when the archive is patched in-place
+        {
+            let mut builder = GrfArchiveBuilder::open(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&output_path)
+                    .unwrap(),
+            )
+            .unwrap();
+            builder
+                .add_file("data\\file.txt".to_string(), &b"plain"[..])
+                .unwrap();
+        }
+        {
+            let mut grf_archive = GrfArchive::open(&output_path).unwrap();
+            let file_entries: Vec<GrfFileEntry> = grf_archive.get_entries().cloned().collect();
+            assert_eq!(file_entries.len(), expected_content.len() + 1);
+            for (file_path, (expected_data, expected_entry_type)) in &expected_content {
+                let entry = grf_archive.get_file_entry(file_path).unwrap();
+                assert_eq!(entry.entry_type.bits(), *expected_entry_type);
+                assert_eq!(
+                    expected_data,
+                    &grf_archive.read_file_content(file_path).unwrap()
+                );
+            }
+            let entry = grf_archive.get_file_entry("data\\file.txt").unwrap();
+            assert_eq!(entry.entry_type, GrfEntryFlags::FILE);
+            assert_eq!(entry.encryption, GrfFileEncryption::Unencrypted);
+        }
     }
 
     #[test]
-    fn test_import_raw_entry_from_grf() {
-        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
-        let grf_path = grf_dir_path.join("200-small.grf");
+    fn test_create_unsupported_version() {
         let temp_dir = tempdir().unwrap();
-        let output_path = temp_dir.path().join("200-builder.grf");
-        // Generate
+        let output_path = temp_dir.path().join("unsupported.grf");
+        for (version_major, version_minor) in &[(1, 0), (1, 4), (2, 1), (3, 1), (4, 0)] {
+            let output_file = File::create(&output_path).unwrap();
+            assert!(
+                GrfArchiveBuilder::create(output_file, *version_major, *version_minor).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_64_bit_offsets() {
+        let temp_dir = tempdir().unwrap();
+        let content = vec![0x42u8; 1024];
+        // Pretend the beginning of the archive is used, files
+        // are sparse so this doesn't actually use 4 GiB of disk space
+        let used_size = u32::MAX as usize + 1;
+        // GRF 0x200 cannot reference data beyond 4 GiB
         {
-            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            let output_path = temp_dir.path().join("200-big.grf");
             let output_file = File::create(&output_path).unwrap();
             let mut builder = GrfArchiveBuilder::create(output_file, 2, 0).unwrap();
-            let grf_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
-            for entry in grf_entries {
+            builder.chunks =
+                AvailableChunkList::from_used_chunks(vec![(GRF_HEADER_SIZE as u64, used_size)])
+                    .unwrap();
+            assert!(builder
+                .add_file("data\\file.txt".to_string(), content.as_slice())
+                .is_err());
+        }
+        // GRF 0x300 can
+        let output_path = temp_dir.path().join("300-big.grf");
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = GrfArchiveBuilder::create(output_file, 3, 0).unwrap();
+            builder.chunks =
+                AvailableChunkList::from_used_chunks(vec![(GRF_HEADER_SIZE as u64, used_size)])
+                    .unwrap();
+            builder
+                .add_file("data\\file.txt".to_string(), content.as_slice())
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        let mut grf = GrfArchive::open(&output_path).unwrap();
+        assert_eq!(grf.version_major(), 3);
+        assert_eq!(grf.version_minor(), 0);
+        assert_eq!(grf.file_count(), 1);
+        let entry = grf.get_file_entry("data\\file.txt").unwrap();
+        assert!(entry.offset > u32::MAX as u64);
+        assert!(grf.table_region().0 > u32::MAX as u64);
+        assert_eq!(grf.read_file_content("data\\file.txt").unwrap(), content);
+    }
+
+    #[test]
+    fn test_import_raw_entry_from_grf() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        for grf_name in &["200-small.grf", "103-small.grf"] {
+            let grf_path = grf_dir_path.join(grf_name);
+            for (version_major, version_minor) in &[(2, 0), (1, 3)] {
+                let output_path = temp_dir
+                    .path()
+                    .join(format!("{}0{}-builder.grf", version_major, version_minor));
+                // Generate
+                {
+                    let mut grf = GrfArchive::open(&grf_path).unwrap();
+                    let mapped_grf = GrfArchive::open_mmap(&grf_path).unwrap();
+                    let output_file = File::create(&output_path).unwrap();
+                    let mut builder =
+                        GrfArchiveBuilder::create(output_file, *version_major, *version_minor)
+                            .unwrap();
+                    let grf_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+                    for (i, entry) in grf_entries.into_iter().enumerate() {
+                        // Import from both kinds of readers
+                        if i % 2 == 0 {
+                            builder
+                                .import_raw_entry_from_grf(&mut grf, entry.relative_path)
+                                .unwrap();
+                        } else {
+                            builder
+                                .import_raw_entry_from_grf_slice(&mapped_grf, entry.relative_path)
+                                .unwrap();
+                        }
+                    }
+                }
+                // Check result
+                {
+                    let mut grf = GrfArchive::open(&grf_path).unwrap();
+                    let mut ouput_archive = GrfArchive::open(&output_path).unwrap();
+                    assert_eq!(grf.file_count(), ouput_archive.file_count());
+                    let file_entries: Vec<GrfFileEntry> =
+                        ouput_archive.get_entries().cloned().collect();
+                    for entry in file_entries {
+                        let expected_content = grf.read_file_content(&entry.relative_path).unwrap();
+                        // Size check
+                        assert_eq!(expected_content.len(), entry.size);
+                        // Content check
+                        assert_eq!(
+                            expected_content,
+                            ouput_archive
+                                .read_file_content(&entry.relative_path)
+                                .unwrap()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_import_empty_entry_from_grf() {
+        for (version_major, version_minor) in &[(2, 0), (1, 3)] {
+            let mut cursor = Cursor::new(Vec::new());
+            {
+                let mut builder =
+                    GrfArchiveBuilder::create(&mut cursor, *version_major, *version_minor).unwrap();
                 builder
-                    .import_raw_entry_from_grf(&mut grf, entry.relative_path)
+                    .add_file("data\\empty.gnd".to_string(), &b""[..])
+                    .unwrap();
+                builder
+                    .add_file("data\\file.txt".to_string(), &b"content"[..])
                     .unwrap();
             }
+            cursor.set_position(0);
+            let mut grf = GrfArchive::new(cursor).unwrap();
+            let source_entry = grf.get_file_entry("data\\empty.gnd").unwrap().clone();
+            assert_eq!(source_entry.size, 0);
+            assert_ne!(source_entry.size_compressed_aligned, 0);
+            for (output_major, output_minor) in &[(2, 0), (1, 3)] {
+                for deduplicate_entries in &[false, true] {
+                    let mut output = Cursor::new(Vec::new());
+                    {
+                        let mut builder =
+                            GrfArchiveBuilder::create(&mut output, *output_major, *output_minor)
+                                .unwrap();
+                        builder.set_deduplicate_entries(*deduplicate_entries);
+                        builder
+                            .import_raw_entry_from_grf_slice(&grf, "data\\empty.gnd".to_string())
+                            .unwrap();
+                        builder
+                            .import_raw_entry_from_grf(&mut grf, "data\\file.txt".to_string())
+                            .unwrap();
+                    }
+                    output.set_position(0);
+                    let mut output_archive = GrfArchive::new(output).unwrap();
+                    let entry = output_archive.get_file_entry("data\\empty.gnd").unwrap();
+                    assert_eq!(entry.size, 0);
+                    assert_eq!(entry.size_compressed_aligned, 0);
+                    assert!(output_archive
+                        .read_file_content("data\\empty.gnd")
+                        .unwrap()
+                        .is_empty());
+                    assert_eq!(
+                        output_archive.read_file_content("data\\file.txt").unwrap(),
+                        b"content"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_import_raw_entry_from_truncated_grf() {
+        let temp_dir = tempdir().unwrap();
+        let source_path = temp_dir.path().join("source.grf");
+        let content: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        fs::write(
+            &source_path,
+            build_test_archive(
+                2,
+                0,
+                &[("data\\a.txt", &content), ("data\\c.txt", &content)],
+            ),
+        )
+        .unwrap();
+        let mut source_grf = GrfArchive::open(&source_path).unwrap();
+        // Truncate the source once opened, in the middle of the entry's data
+        OpenOptions::new()
+            .write(true)
+            .open(&source_path)
+            .unwrap()
+            .set_len(GRF_HEADER_SIZE as u64 + 100)
+            .unwrap();
+
+        for journaled in &[false, true] {
+            let grf_path = temp_dir.path().join(format!("{}.grf", journaled));
+            let grf_data = build_test_archive(
+                2,
+                0,
+                &[
+                    ("data\\a.txt", &b"previous"[..]),
+                    ("data\\b.txt", &b"b"[..]),
+                ],
+            );
+            let grf_size = grf_data.len() as u64;
+            fs::write(&grf_path, grf_data).unwrap();
+            {
+                let mut builder = if *journaled {
+                    GrfArchiveBuilder::open_journaled(&grf_path).unwrap()
+                } else {
+                    GrfArchiveBuilder::open_file(&grf_path).unwrap()
+                };
+                // Replaced entry
+                assert!(builder
+                    .import_raw_entry_from_grf(&mut source_grf, "data\\a.txt".to_string())
+                    .is_err());
+                // New entry
+                assert!(builder
+                    .import_raw_entry_from_grf(&mut source_grf, "data\\c.txt".to_string())
+                    .is_err());
+                builder.finish().unwrap();
+            }
+            assert!(check_file(&grf_path).unwrap().is_ok());
+            // The chunks allocated for the imported entries have been released
+            assert!(fs::metadata(&grf_path).unwrap().len() < grf_size + content.len() as u64);
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            assert_eq!(grf.read_file_content("data\\b.txt").unwrap(), b"b");
+            if *journaled {
+                assert_eq!(grf.read_file_content("data\\a.txt").unwrap(), b"previous");
+                assert!(!grf.contains_file("data\\c.txt"));
+            } else {
+                // The previous content might have been overwritten
+                assert!(!grf.contains_file("data\\a.txt"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_import_raw_entry_from_thor() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let thor_path = thor_dir_path.join("small.thor");
+        let temp_dir = tempdir().unwrap();
+        for (version_major, version_minor) in &[(3, 0), (2, 0), (1, 3)] {
+            let output_path = temp_dir
+                .path()
+                .join(format!("{}0{}-thor.grf", version_major, version_minor));
+            // Generate
+            {
+                let mut thor = ThorArchive::open(&thor_path).unwrap();
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder =
+                    GrfArchiveBuilder::create(output_file, *version_major, *version_minor).unwrap();
+                let thor_entries: Vec<String> = thor
+                    .get_entries()
+                    .filter(|entry| !entry.is_removed)
+                    .map(|entry| entry.relative_path.clone())
+                    .collect();
+                for relative_path in thor_entries {
+                    builder
+                        .import_raw_entry_from_thor(&mut thor, relative_path)
+                        .unwrap();
+                }
+            }
+            // Check result
+            {
+                let mut thor = ThorArchive::open(&thor_path).unwrap();
+                let mut output_archive = GrfArchive::open(&output_path).unwrap();
+                let file_entries: Vec<GrfFileEntry> =
+                    output_archive.get_entries().cloned().collect();
+                assert!(!file_entries.is_empty());
+                for entry in file_entries {
+                    assert_eq!(
+                        thor.read_file_content(&entry.relative_path).unwrap(),
+                        output_archive
+                            .read_file_content(&entry.relative_path)
+                            .unwrap()
+                    );
+                }
+                assert!(check_file(&output_path).unwrap().is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_101() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("103-small.grf");
+        fs::copy(grf_dir_path.join("103-small.grf"), &grf_path).unwrap();
+        let removed_file = "data\\06guild_r.gnd";
+        let added_file = "data\\added.txt";
+        let added_content = vec![0x42u8; 1000];
+        // Patch in-place
+        {
+            let mut builder = GrfArchiveBuilder::open(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&grf_path)
+                    .unwrap(),
+            )
+            .unwrap();
+            assert!(builder.remove_file(removed_file).unwrap());
+            builder
+                .add_file(added_file.to_string(), added_content.as_slice())
+                .unwrap();
         }
         // Check result
         {
+            let mut original_grf = GrfArchive::open(grf_dir_path.join("103-small.grf")).unwrap();
             let mut grf = GrfArchive::open(&grf_path).unwrap();
-            let mut ouput_archive = GrfArchive::open(&output_path).unwrap();
-            let file_entries: Vec<GrfFileEntry> = ouput_archive.get_entries().cloned().collect();
+            assert_eq!(grf.version_major(), 1);
+            assert_eq!(grf.version_minor(), 3);
+            assert_eq!(grf.file_count(), original_grf.file_count());
+            assert!(!grf.contains_file(removed_file));
+            assert_eq!(added_content, grf.read_file_content(added_file).unwrap());
+            let file_entries: Vec<GrfFileEntry> = original_grf.get_entries().cloned().collect();
             for entry in file_entries {
-                let expected_content = grf.read_file_content(&entry.relative_path).unwrap();
-                // Size check
-                assert_eq!(expected_content.len(), entry.size);
-                // Content check
+                if entry.relative_path == removed_file {
+                    continue;
+                }
                 assert_eq!(
-                    expected_content,
-                    ouput_archive
+                    original_grf
                         .read_file_content(&entry.relative_path)
-                        .unwrap()
+                        .unwrap(),
+                    grf.read_file_content(&entry.relative_path).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_file() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        for grf_name in &["200-small.grf", "103-small.grf"] {
+            let grf_path = temp_dir.path().join(grf_name);
+            fs::copy(grf_dir_path.join(grf_name), &grf_path).unwrap();
+            let mut entries: Vec<GrfFileEntry> = GrfArchive::open(&grf_path)
+                .unwrap()
+                .get_entries()
+                .cloned()
+                .collect();
+            entries.sort_unstable_by_key(|entry| entry.offset);
+            // Patching repeatedly doesn't make the archive grow
+            let content = vec![0x42u8; 10_000];
+            let mut size = 0;
+            for i in 0..5 {
+                {
+                    let mut builder = GrfArchiveBuilder::open_file(&grf_path).unwrap();
+                    builder
+                        .add_file("data\\patched.txt".to_string(), content.as_slice())
+                        .unwrap();
+                }
+                assert!(check_file(&grf_path).unwrap().is_ok());
+                let patched_size = fs::metadata(&grf_path).unwrap().len();
+                if i > 0 {
+                    assert_eq!(patched_size, size);
+                }
+                size = patched_size;
+            }
+            // Removing the last entries shrinks the archive
+            {
+                let mut builder = GrfArchiveBuilder::open_file(&grf_path).unwrap();
+                builder.remove_file("data\\patched.txt").unwrap();
+                for entry in entries.iter().rev().take(2) {
+                    builder.remove_file(&entry.relative_path).unwrap();
+                }
+                builder.finish().unwrap();
+            }
+            assert!(check_file(&grf_path).unwrap().is_ok());
+            assert!(fs::metadata(&grf_path).unwrap().len() < size);
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            assert_eq!(grf.file_count(), entries.len() - 2);
+            for entry in &entries[..entries.len() - 2] {
+                assert_eq!(
+                    grf.read_file_content(&entry.relative_path).unwrap().len(),
+                    entry.size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_journaled() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        for grf_name in &["200-small.grf", "103-small.grf"] {
+            let original_path = grf_dir_path.join(grf_name);
+            let grf_path = temp_dir.path().join(grf_name);
+            fs::copy(&original_path, &grf_path).unwrap();
+            let mut original_grf = GrfArchive::open(&original_path).unwrap();
+            let original_entries: Vec<GrfFileEntry> = original_grf.get_entries().cloned().collect();
+            let replaced_file = original_entries[0].relative_path.clone();
+            let removed_file = original_entries[1].relative_path.clone();
+            let added_file = "data\\added.txt";
+            let content = vec![0x42u8; 100_000];
+            let patch = |builder: &mut GrfArchiveBuilder<File>| {
+                builder
+                    .add_file(replaced_file.clone(), content.as_slice())
+                    .unwrap();
+                assert!(builder.remove_file(&removed_file).unwrap());
+                builder
+                    .add_file(added_file.to_string(), content.as_slice())
+                    .unwrap();
+            };
+            // Uncommitted changes are discarded, and the content referenced
+            // by the archive's table is never overwritten
+            {
+                let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+                patch(&mut builder);
+                let mut grf = GrfArchive::open(&grf_path).unwrap();
+                assert_eq!(grf.file_count(), original_grf.file_count());
+                for entry in &original_entries {
+                    assert_eq!(
+                        original_grf
+                            .read_file_content(&entry.relative_path)
+                            .unwrap(),
+                        grf.read_file_content(&entry.relative_path).unwrap()
+                    );
+                }
+            }
+            let grf = GrfArchive::open(&grf_path).unwrap();
+            assert!(!grf.contains_file(added_file));
+            assert!(grf.contains_file(&removed_file));
+            // Committed changes
+            {
+                let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+                patch(&mut builder);
+                builder.finish().unwrap();
+            }
+            assert!(!journal_path(&grf_path).exists());
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            assert_eq!(grf.file_count(), original_grf.file_count());
+            assert!(!grf.contains_file(&removed_file));
+            assert_eq!(grf.read_file_content(&replaced_file).unwrap(), content);
+            assert_eq!(grf.read_file_content(added_file).unwrap(), content);
+            for entry in &original_entries[2..] {
+                assert_eq!(
+                    original_grf
+                        .read_file_content(&entry.relative_path)
+                        .unwrap(),
+                    grf.read_file_content(&entry.relative_path).unwrap()
                 );
             }
+            assert!(check_file(&grf_path).unwrap().is_ok());
+            // Space released by the previous commit is reused
+            let size = fs::metadata(&grf_path).unwrap().len();
+            {
+                let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+                builder.remove_file(added_file).unwrap();
+                builder.finish().unwrap();
+                let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+                builder
+                    .add_file(added_file.to_string(), &[0x43u8; 16][..])
+                    .unwrap();
+                builder.finish().unwrap();
+            }
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            assert!(grf.get_file_entry(added_file).unwrap().offset < size);
+            assert_eq!(grf.read_file_content(added_file).unwrap(), [0x43u8; 16]);
+            assert!(check_file(&grf_path).unwrap().is_ok());
         }
     }
+
+    #[test]
+    fn test_compact() {
+        let temp_dir = tempdir().unwrap();
+        let random_data = |size: u32, seed: u32| -> Vec<u8> {
+            (0..size)
+                .map(|i| ((i + seed).wrapping_mul(2654435761) >> 13) as u8)
+                .collect()
+        };
+        let files = vec![
+            ("data\\first.bin", random_data(1000, 1)),
+            ("data\\tiny.txt", b"x".to_vec()),
+            ("data\\second.bin", random_data(2000, 2)),
+            ("data\\third.bin", random_data(3000, 3)),
+            ("data\\small.txt", b"small".to_vec()),
+            ("data\\fourth.bin", random_data(1500, 4)),
+            ("data\\fifth.bin", random_data(500, 5)),
+        ];
+        let removed_files = ["data\\tiny.txt", "data\\small.txt", "data\\third.bin"];
+        let replaced_file = ("data\\first.bin", random_data(1200, 6));
+        for (version_major, version_minor) in &[(2, 0), (1, 3)] {
+            let grf_path = temp_dir
+                .path()
+                .join(format!("{}0{}-compact.grf", version_major, version_minor));
+            {
+                let grf_file = File::create(&grf_path).unwrap();
+                let mut builder =
+                    GrfArchiveBuilder::create(grf_file, *version_major, *version_minor).unwrap();
+                for (path, content) in &files {
+                    builder
+                        .add_file(path.to_string(), content.as_slice())
+                        .unwrap();
+                }
+            }
+            {
+                let grf_file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&grf_path)
+                    .unwrap();
+                let mut builder = GrfArchiveBuilder::open(grf_file).unwrap();
+                for path in &removed_files {
+                    assert!(builder.remove_file(path).unwrap());
+                }
+                builder
+                    .add_file(replaced_file.0.to_string(), replaced_file.1.as_slice())
+                    .unwrap();
+            }
+
+            let previous_size = fs::metadata(&grf_path).unwrap().len();
+            let reclaimed_size = compact_file(&grf_path).unwrap();
+            assert!(reclaimed_size > 0);
+            assert_eq!(
+                fs::metadata(&grf_path).unwrap().len(),
+                previous_size - reclaimed_size
+            );
+            // Compacting a compact archive only rewrites the file table
+            let _ = compact_file(&grf_path).unwrap();
+            let grf_size = fs::metadata(&grf_path).unwrap().len();
+            assert!(check_file(&grf_path).unwrap().is_ok());
+
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            // Entries and the file table must be contiguous
+            let entries_size: usize = grf.get_entries().map(|e| e.size_compressed_aligned).sum();
+            let (table_offset, table_size) = grf.table_region();
+            assert_eq!(table_offset, (GRF_HEADER_SIZE + entries_size) as u64);
+            assert_eq!(table_offset + table_size as u64, grf_size);
+            assert_eq!(grf.file_count(), files.len() - removed_files.len());
+            for (path, content) in &files {
+                if removed_files.contains(path) {
+                    assert!(!grf.contains_file(path));
+                } else if *path == replaced_file.0 {
+                    assert_eq!(&grf.read_file_content(path).unwrap(), &replaced_file.1);
+                } else {
+                    assert_eq!(&grf.read_file_content(path).unwrap(), content);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_shared_offsets() {
+        let compress = |data: &[u8]| {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let shared_content = b"shared content".to_vec();
+        let other_content = b"other content".to_vec();
+        let mut data = compress(&shared_content);
+        let shared_size = data.len() as u32;
+        data.extend_from_slice(&compress(&other_content));
+        let other_size = data.len() as u32 - shared_size;
+        // Build the archive by hand, "a" and "b" share the same data
+        let entries = [
+            ("data\\a.txt", shared_size, shared_content.len() as u32, 0),
+            ("data\\b.txt", shared_size, shared_content.len() as u32, 0),
+            (
+                "data\\c.txt",
+                other_size,
+                other_content.len() as u32,
+                shared_size,
+            ),
+        ];
+        let mut table = Vec::new();
+        for (path, size_compressed, size, offset) in &entries {
+            table.extend_from_slice(path.as_bytes());
+            table.push(0);
+            table.extend_from_slice(&size_compressed.to_le_bytes());
+            table.extend_from_slice(&size_compressed.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.push(GrfEntryFlags::FILE.bits());
+            table.extend_from_slice(&offset.to_le_bytes());
+        }
+        let compressed_table = compress(&table);
+        let mut grf = Vec::new();
+        grf.extend_from_slice(GRF_HEADER_MAGIC.as_bytes());
+        grf.extend_from_slice(&[0; 14]);
+        grf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&0i32.to_le_bytes());
+        grf.extend_from_slice(&(entries.len() as i32 + 7).to_le_bytes());
+        grf.extend_from_slice(&0x200u32.to_le_bytes());
+        grf.extend_from_slice(&data);
+        grf.extend_from_slice(&(compressed_table.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&compressed_table);
+
+        let mut cursor = Cursor::new(grf);
+        {
+            let mut builder = GrfArchiveBuilder::open(&mut cursor).unwrap();
+            // Removing "a" must not release the data used by "b"
+            assert!(builder.remove_file("data\\a.txt").unwrap());
+            builder
+                .add_file("data\\d.txt".to_string(), &shared_content[..])
+                .unwrap();
+        }
+        cursor.set_position(0);
+        {
+            let mut grf_archive = GrfArchive::new(&mut cursor).unwrap();
+            assert_eq!(grf_archive.file_count(), 3);
+            let entry = grf_archive.get_file_entry("data\\d.txt").unwrap();
+            assert!(entry.offset >= (GRF_HEADER_SIZE + data.len()) as u64);
+            assert_eq!(
+                grf_archive.read_file_content("data\\b.txt").unwrap(),
+                shared_content
+            );
+        }
+        cursor.set_position(0);
+        {
+            let mut builder = GrfArchiveBuilder::open(&mut cursor).unwrap();
+            // "b" doesn't share its data anymore
+            assert!(builder.remove_file("data\\b.txt").unwrap());
+            builder
+                .add_file("data\\e.txt".to_string(), &shared_content[..])
+                .unwrap();
+        }
+        cursor.set_position(0);
+        let mut grf_archive = GrfArchive::new(&mut cursor).unwrap();
+        let entry = grf_archive.get_file_entry("data\\e.txt").unwrap();
+        assert_eq!(entry.offset, GRF_HEADER_SIZE as u64);
+        for path in &["data\\c.txt", "data\\d.txt", "data\\e.txt"] {
+            assert!(grf_archive.contains_file(path));
+        }
+        assert_eq!(
+            grf_archive.read_file_content("data\\c.txt").unwrap(),
+            other_content
+        );
+    }
+
+    #[test]
+    fn test_open_shadowed_entries() {
+        let content = b"shadowed content".to_vec();
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut builder = GrfArchiveBuilder::create(&mut cursor, 2, 0).unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &content[..])
+                .unwrap();
+            builder
+                .add_file("data\\shadow.txt".to_string(), &content[..])
+                .unwrap();
+            builder
+                .add_file("data\\b.txt".to_string(), &b"other content"[..])
+                .unwrap();
+            // Give "shadow" a path that only differs from "a"'s by case
+            let entry = builder.entries.get_mut("data\\shadow.txt").unwrap();
+            entry.relative_path = "DATA\\A.TXT".to_string();
+        }
+        let (data_end_offset, shadowed_chunk) = {
+            cursor.set_position(0);
+            let grf_archive = GrfArchive::new(&mut cursor).unwrap();
+            assert_eq!(grf_archive.get_entries().count(), 2);
+            assert_eq!(grf_archive.shadowed_chunks().len(), 1);
+            let data_end_offset = grf_archive
+                .get_entries()
+                .map(|e| e.offset + e.size_compressed_aligned as u64)
+                .max()
+                .unwrap();
+            (data_end_offset, grf_archive.shadowed_chunks()[0])
+        };
+        // The shadowed entry's data is released once the new table has been
+        // written
+        let is_shadowed_chunk_available = |chunks: &AvailableChunkList| {
+            let (offset, size) = shadowed_chunk;
+            chunks.find_chunk_before(size, offset + 1) == Some(offset)
+        };
+        let data_range = GRF_HEADER_SIZE..data_end_offset as usize;
+        let original_data = cursor.get_ref()[data_range.clone()].to_vec();
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("shadowed.grf");
+        fs::write(&grf_path, cursor.get_ref()).unwrap();
+        // The shadowed entry's data must not be reused
+        cursor.set_position(0);
+        {
+            let mut builder = GrfArchiveBuilder::open(&mut cursor).unwrap();
+            builder
+                .add_file("data\\c.txt".to_string(), &content[..])
+                .unwrap();
+            assert!(!is_shadowed_chunk_available(&builder.chunks));
+            builder.finish().unwrap();
+            assert!(is_shadowed_chunk_available(&builder.chunks));
+        }
+        {
+            let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+            builder
+                .add_file("data\\c.txt".to_string(), &content[..])
+                .unwrap();
+            assert!(!is_shadowed_chunk_available(&builder.chunks));
+            builder.finish().unwrap();
+            assert!(is_shadowed_chunk_available(&builder.chunks));
+        }
+        for grf_data in &[cursor.into_inner(), fs::read(&grf_path).unwrap()] {
+            assert_eq!(&grf_data[data_range.clone()], original_data.as_slice());
+            let mut grf_archive = GrfArchive::new(Cursor::new(grf_data)).unwrap();
+            assert_eq!(grf_archive.file_count(), 3);
+            let entry = grf_archive.get_file_entry("data\\c.txt").unwrap();
+            assert!(entry.offset >= data_end_offset);
+            assert_eq!(
+                grf_archive.read_file_content("data\\c.txt").unwrap(),
+                content
+            );
+        }
+    }
+
+    /// Cursor recording the biggest size its buffer has reached
+    struct PeakSizeCursor {
+        cursor: Cursor<Vec<u8>>,
+        peak_size: u64,
+    }
+
+    impl Read for PeakSizeCursor {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.cursor.read(buf)
+        }
+    }
+
+    impl Write for PeakSizeCursor {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written = self.cursor.write(buf)?;
+            self.peak_size = std::cmp::max(self.peak_size, self.cursor.get_ref().len() as u64);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.cursor.flush()
+        }
+    }
+
+    impl Seek for PeakSizeCursor {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_compact_peak_size() {
+        let batch_size = 4096;
+        let random_data = |size: u32, seed: u32| -> Vec<u8> {
+            (0..size)
+                .map(|i| ((i + seed).wrapping_mul(2654435761) >> 13) as u8)
+                .collect()
+        };
+        let files: Vec<(String, Vec<u8>)> = (0..40)
+            .map(|i| (format!("data\\{:02}.bin", i), random_data(1000, i)))
+            .collect();
+        for (version_major, version_minor) in &[(2, 0), (1, 3)] {
+            let mut cursor = Cursor::new(Vec::new());
+            {
+                let mut builder =
+                    GrfArchiveBuilder::create(&mut cursor, *version_major, *version_minor).unwrap();
+                for (path, content) in &files {
+                    builder.add_file(path.clone(), content.as_slice()).unwrap();
+                }
+            }
+            // Leave a hole at the beginning of the archive
+            cursor.set_position(0);
+            {
+                let mut builder = GrfArchiveBuilder::open(&mut cursor).unwrap();
+                assert!(builder.remove_file(&files[0].0).unwrap());
+            }
+            cursor.set_position(0);
+            let mut grf = PeakSizeCursor {
+                peak_size: cursor.get_ref().len() as u64,
+                cursor,
+            };
+            let compacted_size = GrfArchiveBuilder::open(&mut grf)
+                .unwrap()
+                .compact_in_batches(batch_size)
+                .unwrap();
+            let mut grf_data = grf.cursor.into_inner();
+            grf_data.truncate(compacted_size as usize);
+            let mut grf_archive = GrfArchive::new(Cursor::new(grf_data)).unwrap();
+            let (_, table_size) = grf_archive.table_region();
+            assert!(
+                grf.peak_size <= compacted_size + (batch_size + table_size) as u64,
+                "peak size: {}, compacted size: {}",
+                grf.peak_size,
+                compacted_size
+            );
+            assert_eq!(grf_archive.file_count(), files.len() - 1);
+            for (path, content) in &files[1..] {
+                assert_eq!(&grf_archive.read_file_content(path).unwrap(), content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_shared_offsets() {
+        let content = b"shared content".to_vec();
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut builder = GrfArchiveBuilder::create(&mut cursor, 2, 0).unwrap();
+            builder
+                .add_file("data\\removed.txt".to_string(), &b"removed"[..])
+                .unwrap();
+            builder
+                .add_file("data\\a.txt".to_string(), &content[..])
+                .unwrap();
+            // Make "b" share "a"'s data
+            let entry = builder.entries.get("data\\a.txt").unwrap();
+            let (offset, size) = (entry.generic.offset, entry.size_compressed_aligned);
+            let shared_entry = BuilderFileEntry {
+                relative_path: "data\\b.txt".to_string(),
+                generic: GenericFileEntry {
+                    offset,
+                    size: entry.generic.size,
+                    size_compressed: entry.generic.size_compressed,
+                },
+                size_compressed_aligned: size,
+                encryption: entry.encryption.clone(),
+            };
+            builder
+                .entries
+                .insert("data\\b.txt".to_string(), shared_entry);
+            builder.chunks.add_chunk_reference(offset, size as usize);
+            assert!(builder.remove_file("data\\removed.txt").unwrap());
+            let grf_size = builder.compact().unwrap();
+            drop(builder);
+            cursor.get_mut().truncate(grf_size as usize);
+        }
+        cursor.set_position(0);
+        let report = check(&mut cursor).unwrap();
+        // Only the shared offset is reported
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+        cursor.set_position(0);
+        let mut grf_archive = GrfArchive::new(&mut cursor).unwrap();
+        for path in &["data\\a.txt", "data\\b.txt"] {
+            let entry = grf_archive.get_file_entry(path).unwrap();
+            assert_eq!(entry.offset, GRF_HEADER_SIZE as u64);
+            assert_eq!(grf_archive.read_file_content(path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_deduplicate_entries() {
+        let content = vec![0xABu8; 1000];
+        let other_content = vec![0xCDu8; 1000];
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut builder = GrfArchiveBuilder::create(&mut cursor, 2, 0).unwrap();
+            builder.set_deduplicate_entries(true);
+            for path in &["data\\a.txt", "data\\b.txt", "data\\c.txt"] {
+                builder
+                    .add_file(path.to_string(), content.as_slice())
+                    .unwrap();
+            }
+            builder
+                .add_file("data\\other.txt".to_string(), other_content.as_slice())
+                .unwrap();
+            let offset = builder.entries["data\\a.txt"].generic.offset;
+            assert_eq!(builder.entries["data\\b.txt"].generic.offset, offset);
+            assert_eq!(builder.entries["data\\c.txt"].generic.offset, offset);
+            assert_ne!(builder.entries["data\\other.txt"].generic.offset, offset);
+
+            // Removing or replacing aliases must not release shared data
+            assert!(builder.remove_file("data\\a.txt").unwrap());
+            builder
+                .add_file("data\\b.txt".to_string(), other_content.as_slice())
+                .unwrap();
+            assert_eq!(
+                builder.entries["data\\b.txt"].generic.offset,
+                builder.entries["data\\other.txt"].generic.offset
+            );
+            builder
+                .add_file("data\\d.txt".to_string(), content.as_slice())
+                .unwrap();
+            assert_eq!(builder.entries["data\\d.txt"].generic.offset, offset);
+
+            // Released data isn't reused
+            assert!(builder.remove_file("data\\c.txt").unwrap());
+            assert!(builder.remove_file("data\\d.txt").unwrap());
+            builder
+                .add_file("data\\e.txt".to_string(), vec![0xEFu8; 2000].as_slice())
+                .unwrap();
+            builder
+                .add_file("data\\f.txt".to_string(), content.as_slice())
+                .unwrap();
+        }
+        cursor.set_position(0);
+        let mut grf_archive = GrfArchive::new(cursor).unwrap();
+        assert_eq!(grf_archive.file_count(), 4);
+        for (path, expected_content) in &[
+            ("data\\b.txt", &other_content),
+            ("data\\other.txt", &other_content),
+            ("data\\e.txt", &vec![0xEFu8; 2000]),
+            ("data\\f.txt", &content),
+        ] {
+            assert_eq!(
+                &grf_archive.read_file_content(path).unwrap(),
+                *expected_content
+            );
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let content = vec![0x42u8; 4096];
+        let mut grf = Cursor::new(Vec::new());
+        {
+            let mut builder = GrfArchiveBuilder::create(&mut grf, 2, 0).unwrap();
+            builder.set_compression(CompressionPolicy::Store);
+            builder
+                .add_file("data\\stored.txt".to_string(), content.as_slice())
+                .unwrap();
+            builder
+                .add_file_with_compression(
+                    "data\\compressed.txt".to_string(),
+                    content.as_slice(),
+                    CompressionPolicy::Level(9),
+                )
+                .unwrap();
+            builder.set_compression(CompressionPolicy::Auto);
+            builder
+                .add_file("data\\auto.txt".to_string(), content.as_slice())
+                .unwrap();
+            assert!(builder
+                .add_file_with_compression(
+                    "data\\invalid.txt".to_string(),
+                    content.as_slice(),
+                    CompressionPolicy::Level(10),
+                )
+                .is_err());
+            builder.finish().unwrap();
+        }
+        grf.set_position(0);
+        let mut grf = GrfArchive::new(grf).unwrap();
+        assert_eq!(grf.file_count(), 3);
+        let stored = grf.get_file_entry("data\\stored.txt").unwrap();
+        assert!(stored.size_compressed > content.len());
+        let compressed = grf.get_file_entry("data\\compressed.txt").unwrap();
+        assert!(compressed.size_compressed < content.len());
+        let auto = grf.get_file_entry("data\\auto.txt").unwrap();
+        assert!(auto.size_compressed < content.len());
+        for path in &["data\\stored.txt", "data\\compressed.txt", "data\\auto.txt"] {
+            assert_eq!(grf.read_file_content(path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_add_files() {
+        let files: Vec<(String, Vec<u8>)> = (0..50u32)
+            .map(|i| {
+                let content = (0..(i * 97))
+                    .map(|j| (j.wrapping_mul(2654435761 + i) >> 13) as u8)
+                    .collect();
+                (format!("data\\file{}.bin", i), content)
+            })
+            .chain(std::iter::once((
+                "data\\file3.bin".to_string(),
+                vec![7u8; 300],
+            )))
+            .collect();
+        for (version_major, version_minor) in &[(2, 0), (1, 3)] {
+            let build = |parallel: bool| {
+                let mut grf = Cursor::new(Vec::new());
+                {
+                    let mut builder =
+                        GrfArchiveBuilder::create(&mut grf, *version_major, *version_minor)
+                            .unwrap();
+                    builder.set_encrypt_entries(true);
+                    if parallel {
+                        builder.set_thread_count(4);
+                        builder
+                            .add_files(
+                                files
+                                    .iter()
+                                    .map(|(path, content)| (path.clone(), content.as_slice())),
+                            )
+                            .unwrap();
+                    } else {
+                        for (path, content) in &files {
+                            builder.add_file(path.clone(), content.as_slice()).unwrap();
+                        }
+                    }
+                }
+                grf.into_inner()
+            };
+            let sequential_data = build(false);
+            // Same output
+            assert_eq!(sequential_data, build(true));
+            let mut sequential = GrfArchive::new(Cursor::new(&sequential_data)).unwrap();
+            assert_eq!(sequential.file_count(), files.len() - 1);
+            for (path, content) in files.iter().skip(4) {
+                assert_eq!(&sequential.read_file_content(path).unwrap(), content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let files: Vec<(String, Vec<u8>)> = (0..100u32)
+            .map(|i| {
+                (
+                    format!("data\\dir{}\\file{}.txt", i % 7, i),
+                    vec![i as u8; 100 + i as usize],
+                )
+            })
+            .collect();
+        for (version_major, version_minor) in &[(3, 0), (2, 0), (1, 3)] {
+            let build = || {
+                let mut grf = Cursor::new(Vec::new());
+                {
+                    let mut builder =
+                        GrfArchiveBuilder::create(&mut grf, *version_major, *version_minor)
+                            .unwrap();
+                    for (path, content) in &files {
+                        builder.add_file(path.clone(), content.as_slice()).unwrap();
+                    }
+                }
+                // Patch the archive in-place
+                grf.set_position(0);
+                {
+                    let mut builder = GrfArchiveBuilder::open(&mut grf).unwrap();
+                    for (path, _) in files.iter().step_by(3) {
+                        builder.remove_file(path).unwrap();
+                    }
+                    builder
+                        .add_file("data\\dir0\\file7.txt".to_string(), &[0xFFu8; 300][..])
+                        .unwrap();
+                    builder.compact().unwrap();
+                }
+                grf.into_inner()
+            };
+            let output = build();
+            for _ in 0..3 {
+                assert_eq!(output, build());
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_file_streamed() {
+        // Incompressible data, bigger than the streaming buffer
+        let mut state = 1u64;
+        let big_content: Vec<u8> = (0..3 * 1024 * 1024 / 2 + 5)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        let small_content = vec![0x42u8; 1000];
+        for (version_major, version_minor) in &[(3, 0), (2, 0), (1, 3)] {
+            let mut grf = Cursor::new(Vec::new());
+            {
+                let mut builder =
+                    GrfArchiveBuilder::create(&mut grf, *version_major, *version_minor).unwrap();
+                builder.set_encrypt_entries(true);
+                builder
+                    .add_file("data\\buffered.bin".to_string(), big_content.as_slice())
+                    .unwrap();
+                builder
+                    .add_file_streamed("data\\streamed.bin".to_string(), big_content.as_slice())
+                    .unwrap();
+                builder
+                    .add_file_spilled(
+                        "data\\spilled.bin".to_string(),
+                        big_content.as_slice(),
+                        Cursor::new(Vec::new()),
+                    )
+                    .unwrap();
+                // Replace entries
+                builder
+                    .add_file_streamed("data\\buffered.bin".to_string(), small_content.as_slice())
+                    .unwrap();
+                builder
+                    .add_file_spilled(
+                        "data\\streamed.bin".to_string(),
+                        small_content.as_slice(),
+                        Cursor::new(Vec::new()),
+                    )
+                    .unwrap();
+                builder.finish().unwrap();
+            }
+            grf.set_position(0);
+            let report = check(&mut grf).unwrap();
+            assert!(report.is_ok(), "{:?}", report.issues);
+            grf.set_position(0);
+            let mut grf = GrfArchive::new(grf).unwrap();
+            assert_eq!(grf.file_count(), 3);
+            let spilled_entry = grf.get_file_entry("data\\spilled.bin").unwrap().clone();
+            assert_eq!(spilled_entry.size, big_content.len());
+            assert_eq!(
+                spilled_entry.encryption,
+                determine_file_encryption_101("data\\spilled.bin", spilled_entry.size_compressed)
+            );
+            assert_eq!(
+                grf.read_file_content("data\\spilled.bin").unwrap(),
+                big_content
+            );
+            for path in &["data\\buffered.bin", "data\\streamed.bin"] {
+                assert_eq!(grf.read_file_content(path).unwrap(), small_content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_in_memory() {
+        let mut cursor = Cursor::new(Vec::new());
+        // Leading data that doesn't belong to the archive
+        cursor.write_all(&[0xFF; 100]).unwrap();
+        {
+            let mut builder = GrfArchiveBuilder::create(&mut cursor, 2, 0).unwrap();
+            builder
+                .add_file("data\\first.txt".to_string(), &b"first"[..])
+                .unwrap();
+        }
+        cursor.seek(SeekFrom::Start(100)).unwrap();
+        {
+            let mut builder = GrfArchiveBuilder::open(&mut cursor).unwrap();
+            builder
+                .add_file("data\\second.txt".to_string(), &b"second"[..])
+                .unwrap();
+        }
+        cursor.seek(SeekFrom::Start(100)).unwrap();
+        let mut grf_archive = GrfArchive::new(cursor).unwrap();
+        assert_eq!(grf_archive.file_count(), 2);
+        assert_eq!(
+            grf_archive.read_file_content("data\\first.txt").unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            grf_archive.read_file_content("data\\second.txt").unwrap(),
+            b"second"
+        );
+        assert_eq!(
+            &grf_archive.into_inner().into_inner()[..100],
+            &[0xFF; 100][..]
+        );
+    }
 }
@@ -1,30 +1,160 @@
-use std::cmp;
 use std::convert::TryInto;
 use std::result::Result;
 
 mod des;
 
-const DES_BLOCK_SIZE: usize = 8; // Block size in bytes
+pub const DES_BLOCK_SIZE: usize = 8; // Block size in bytes
 
+/// Decrypts an obfuscated file name found in GRF 1.x file tables.
 pub fn decrypt_file_name(file_name: &[u8]) -> Result<Vec<u8>, &str> {
     let mut mut_vec = file_name.to_vec();
     swap_nibbles(&mut mut_vec);
-    grf_decrypt_shuffled(0, 1, mut_vec.as_mut_slice());
+    ContentDecryptor::new(1).decrypt_blocks(mut_vec.as_mut_slice());
     remove_zero_padding(&mut mut_vec);
     Ok(mut_vec)
 }
 
+/// Obfuscates a file name the way it's done in GRF 1.x file tables.
+///
+/// The result is NUL-terminated and padded to a multiple of 8 bytes.
+pub fn encrypt_file_name(file_name: &[u8]) -> Vec<u8> {
+    let mut mut_vec = file_name.to_vec();
+    // Names are NUL-terminated and zero-padded to a multiple of the block size
+    mut_vec.push(0);
+    add_zero_padding(&mut mut_vec);
+    ContentEncryptor::new(1).encrypt_blocks(mut_vec.as_mut_slice());
+    swap_nibbles(&mut mut_vec);
+    mut_vec
+}
+
+/// Decrypts an entry's content in place.
+///
+/// A `cycle` of 0 indicates that only the first 20 blocks are encrypted.
+/// Otherwise, blocks are encrypted or shuffled depending on `cycle`.
 pub fn decrypt_file_content(data: &mut Vec<u8>, cycle: usize) {
-    if cycle == 0 {
-        grf_decrypt_first_blocks(0, data.as_mut_slice())
-    } else {
-        grf_decrypt_shuffled(0, cycle, data.as_mut_slice());
+    ContentDecryptor::new(cycle).decrypt_blocks(data.as_mut_slice());
+}
+
+/// Encrypts an entry's content in place, this is the inverse of
+/// `decrypt_file_content`.
+///
+/// `data` is padded with zeros to a multiple of 8 bytes beforehand.
+pub fn encrypt_file_content(data: &mut Vec<u8>, cycle: usize) {
+    add_zero_padding(data);
+    ContentEncryptor::new(cycle).encrypt_blocks(data.as_mut_slice());
+}
+
+/// Decrypts content block by block, keeping track of the position in the
+/// content.
+///
+/// This makes it possible to decrypt an entry's content in chunks instead of
+/// all at once.
+pub struct ContentDecryptor {
+    walker: BlockWalker,
+}
+
+impl ContentDecryptor {
+    pub fn new(cycle: usize) -> Self {
+        Self {
+            walker: BlockWalker::new(cycle),
+        }
+    }
+
+    /// Decrypts the next blocks of content in place.
+    ///
+    /// Trailing bytes that do not form a complete block are left untouched,
+    /// so `buffer`'s size should be a multiple of 8 bytes unless it contains
+    /// the end of the content.
+    pub fn decrypt_blocks(&mut self, buffer: &mut [u8]) {
+        self.walker.process_blocks(buffer, Direction::Decrypt);
+    }
+}
+
+/// Encrypts content block by block, keeping track of the position in the
+/// content. This is the inverse of `ContentDecryptor`.
+pub struct ContentEncryptor {
+    walker: BlockWalker,
+}
+
+impl ContentEncryptor {
+    pub fn new(cycle: usize) -> Self {
+        Self {
+            walker: BlockWalker::new(cycle),
+        }
+    }
+
+    /// Encrypts the next blocks of content in place, with the same
+    /// requirements on `buffer` as `ContentDecryptor::decrypt_blocks`.
+    pub fn encrypt_blocks(&mut self, buffer: &mut [u8]) {
+        self.walker.process_blocks(buffer, Direction::Encrypt);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Encrypt,
+    Decrypt,
+}
+
+/// Walks through content block by block, and applies 1 round of DES to or
+/// shuffles each block depending on its position in the content
+struct BlockWalker {
+    des_cipher: des::Des,
+    // 0 indicates that only the first 20 blocks are encrypted
+    updated_cycle: usize,
+    block_index: usize,
+    shuffle_counter: usize,
+}
+
+impl BlockWalker {
+    fn new(cycle: usize) -> Self {
+        Self {
+            des_cipher: des::Des {
+                keys: des::gen_keys(0),
+            },
+            updated_cycle: if cycle == 0 { 0 } else { update_cycle(cycle) },
+            block_index: 0,
+            shuffle_counter: 0,
+        }
+    }
+
+    fn process_blocks(&mut self, buffer: &mut [u8], direction: Direction) {
+        for block in buffer.chunks_exact_mut(DES_BLOCK_SIZE) {
+            let i = self.block_index;
+            self.block_index += 1;
+            if i < 20 || (self.updated_cycle != 0 && (i % self.updated_cycle) == 0) {
+                // Apply 1 round of DES to the block
+                let block_as_u64 = read_be_u64(block);
+                let processed_block = match direction {
+                    Direction::Encrypt => self.des_cipher.encrypt_block_1_round(block_as_u64),
+                    Direction::Decrypt => self.des_cipher.decrypt_block_1_round(block_as_u64),
+                };
+                block.copy_from_slice(&u64::to_be_bytes(processed_block));
+            } else if self.updated_cycle != 0 {
+                if self.shuffle_counter == 7 {
+                    self.shuffle_counter = 0;
+                    match direction {
+                        Direction::Encrypt => shuffle_block(block),
+                        Direction::Decrypt => unshuffle_block(block),
+                    }
+                }
+                self.shuffle_counter += 1;
+            }
+        }
     }
 }
 
 fn swap_nibbles(buffer: &mut Vec<u8>) {
     for b in buffer {
-        *b = (*b << 4) | (*b >> 4);
+        *b = b.rotate_left(4);
+    }
+}
+
+/// Pads `vec` with zeros so that its length is a multiple of the block size
+fn add_zero_padding(vec: &mut Vec<u8>) {
+    let remainder = vec.len() % DES_BLOCK_SIZE;
+    if remainder != 0 {
+        vec.resize(vec.len() + DES_BLOCK_SIZE - remainder, 0);
     }
 }
 
@@ -37,53 +167,28 @@ fn remove_zero_padding(vec: &mut Vec<u8>) {
     }
 }
 
-fn grf_decrypt_first_blocks(key: u64, buffer: &mut [u8]) {
-    let des_cipher = des::Des {
-        keys: des::gen_keys(key),
-    };
-    let buffer_size_in_blocks = buffer.len() / DES_BLOCK_SIZE;
-    for i in 0..cmp::min(buffer_size_in_blocks, 20) {
-        let cur_block_range = i * DES_BLOCK_SIZE..(i + 1) * DES_BLOCK_SIZE;
-        // Apply 1 round of DES to the block
-        let block_as_u64 = read_be_u64(&buffer[cur_block_range.clone()]);
-        let decrypted_block = des_cipher.decrypt_block_1_round(block_as_u64);
-        buffer[cur_block_range].copy_from_slice(&u64::to_be_bytes(decrypted_block));
-    }
-}
-
-fn grf_decrypt_shuffled(key: u64, cycle: usize, buffer: &mut [u8]) {
-    let des_cipher = des::Des {
-        keys: des::gen_keys(key),
-    };
-    let updated_cycle = update_cycle(cycle);
-    let buffer_size_in_blocks = buffer.len() / DES_BLOCK_SIZE;
-    // Process blocks
-    let mut j = 0;
-    for i in 0..buffer_size_in_blocks {
-        let cur_block_range = i * DES_BLOCK_SIZE..(i + 1) * DES_BLOCK_SIZE;
-        if i < 20 || (i % updated_cycle) == 0 {
-            // Apply 1 round of DES to the block
-            let block_as_u64 = read_be_u64(&buffer[cur_block_range.clone()]);
-            let decrypted_block = des_cipher.decrypt_block_1_round(block_as_u64);
-            buffer[cur_block_range].copy_from_slice(&u64::to_be_bytes(decrypted_block));
-        } else {
-            if j == 7 {
-                j = 0;
-                // Shuffle bytes in the block
-                let cur_block_copy: [u8; DES_BLOCK_SIZE] =
-                    buffer[cur_block_range.clone()].try_into().unwrap();
-                let cur_block_view = &mut buffer[cur_block_range];
-                // 3450162 (initial layout) to 0123456 (final layout)
-                cur_block_view[..2].copy_from_slice(&cur_block_copy[3..5]);
-                cur_block_view[2] = cur_block_copy[6];
-                cur_block_view[3..6].copy_from_slice(&cur_block_copy[..3]);
-                cur_block_view[6] = cur_block_copy[5];
-                // Mutate the 7th byte
-                cur_block_view[7] = permute_byte(cur_block_copy[7]);
-            }
-            j += 1;
-        }
-    }
+/// Shuffles bytes in the block, 0123456 (initial layout) to 3450162 (final
+/// layout)
+fn shuffle_block(block: &mut [u8]) {
+    let block_copy: [u8; DES_BLOCK_SIZE] = block.try_into().unwrap();
+    block[..3].copy_from_slice(&block_copy[3..6]);
+    block[3..5].copy_from_slice(&block_copy[..2]);
+    block[5] = block_copy[6];
+    block[6] = block_copy[2];
+    // Mutate the 7th byte
+    block[7] = permute_byte(block_copy[7]);
+}
+
+/// Shuffles bytes in the block, 3450162 (initial layout) to 0123456 (final
+/// layout)
+fn unshuffle_block(block: &mut [u8]) {
+    let block_copy: [u8; DES_BLOCK_SIZE] = block.try_into().unwrap();
+    block[..2].copy_from_slice(&block_copy[3..5]);
+    block[2] = block_copy[6];
+    block[3..6].copy_from_slice(&block_copy[..3]);
+    block[6] = block_copy[5];
+    // Mutate the 7th byte
+    block[7] = permute_byte(block_copy[7]);
 }
 
 fn update_cycle(cycle: usize) -> usize {
@@ -123,3 +228,96 @@ fn permute_byte(b: u8) -> u8 {
         _ => b,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn test_encrypt_file_name() {
+        // Names taken from the "103-small.grf" test archive
+        assert_eq!(
+            encrypt_file_name(b"data\\06guild_r.gnd"),
+            hex!("075253428413362312d3d316b172b733a643110110404544")
+        );
+        assert_eq!(
+            encrypt_file_name(b"data\\sprite\\\xb8\xf3\xbd\xba\xc5\xcd\\high_orc.act"),
+            hex!("1752534285275272d61717949e3e8beb4998d1c3826292b0a22226b343620350")
+        );
+    }
+
+    #[test]
+    fn test_file_name_round_trip() {
+        let names: [&[u8]; 4] = [
+            b"a",
+            b"data\\file.gat",
+            b"data\\texture\\\xc0\xaf\xc0\xfa\xc0\xce\xc5\xcd\xc6\xe4\xc0\xcc\xbd\xba\\icon.bmp",
+            b"data\\abcdefghijklmnopqrstuvwxyz\\abcdefghijklmnopqrstuvwxyz\\abcdefghijklmnopqrstuvwxyz\\abcdefghijklmnopqrstuvwxyz\\abcdefghijklmnopqrstuvwxyz\\abcdefghijklmnopqrstuvwxyz.bmp",
+        ];
+        for name in &names {
+            let encrypted_name = encrypt_file_name(name);
+            assert_eq!(encrypted_name.len() % DES_BLOCK_SIZE, 0);
+            assert_ne!(&encrypted_name[..name.len()], *name);
+            assert_eq!(decrypt_file_name(&encrypted_name).unwrap(), *name);
+        }
+    }
+
+    #[test]
+    fn test_file_content_round_trip() {
+        let sizes = [0, 1, 7, 8, 9, 159, 160, 161, 1000, 4099];
+        for &size in &sizes {
+            let content: Vec<u8> = (0..size as u32)
+                .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+                .collect();
+            for cycle in 0..10 {
+                let mut data = content.clone();
+                encrypt_file_content(&mut data, cycle);
+                assert_eq!(data.len() % DES_BLOCK_SIZE, 0);
+                assert!(data.len() >= size && data.len() < size + DES_BLOCK_SIZE);
+                if size >= DES_BLOCK_SIZE {
+                    assert_ne!(&data[..size], content.as_slice());
+                }
+                decrypt_file_content(&mut data, cycle);
+                assert_eq!(&data[..size], content.as_slice());
+                assert!(data[size..].iter().all(|&b| b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_content_encryptor() {
+        let content: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for cycle in 0..10 {
+            let mut expected_data = content.clone();
+            encrypt_file_content(&mut expected_data, cycle);
+            // Encrypt in chunks of various sizes
+            let mut data = content.clone();
+            let mut encryptor = ContentEncryptor::new(cycle);
+            let mut remaining_data = data.as_mut_slice();
+            let mut chunk_size = DES_BLOCK_SIZE;
+            while !remaining_data.is_empty() {
+                let (chunk, rest) =
+                    remaining_data.split_at_mut(std::cmp::min(chunk_size, remaining_data.len()));
+                encryptor.encrypt_blocks(chunk);
+                remaining_data = rest;
+                chunk_size += DES_BLOCK_SIZE;
+            }
+            assert_eq!(data, expected_data);
+        }
+    }
+
+    #[test]
+    fn test_shuffle_block_round_trip() {
+        for b in 0..=255u8 {
+            let block = [0, 1, 2, 3, 4, 5, 6, b];
+            let mut shuffled_block = block;
+            shuffle_block(&mut shuffled_block);
+            assert_eq!(shuffled_block[..7], [3, 4, 5, 0, 1, 6, 2]);
+            unshuffle_block(&mut shuffled_block);
+            assert_eq!(shuffled_block, block);
+        }
+    }
+}
@@ -1,56 +1,43 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::TryFrom;
+use std::io::{Read, Seek};
 
 use crate::error::{GrufError, Result};
-use crate::grf::reader::{GrfArchive, GrfFileEntry, GRF_HEADER_SIZE};
+use crate::grf::reader::{GrfArchive, GRF_HEADER_SIZE};
 
 #[derive(Debug)]
 pub struct AvailableChunk {
     pub size: usize,
 }
 
+#[derive(Debug)]
+pub struct SharedChunk {
+    pub size: usize,
+    pub ref_count: usize,
+}
+
 #[derive(Debug)]
 pub struct AvailableChunkList {
     end_offset: u64,
     sizes: BTreeSet<(usize, u64)>, // Indexed and ordered by size
     chunks: BTreeMap<u64, AvailableChunk>, // Indexed and ordered by offset
+    // Used chunks referenced by several entries, indexed by offset.
+    // Such chunks are released once all their references have been freed.
+    shared_chunks: HashMap<u64, SharedChunk>,
 }
 
-pub fn list_available_chunks(archive: &mut GrfArchive) -> Result<AvailableChunkList> {
-    if archive.file_count() == 0 {
-        return Ok(AvailableChunkList::new());
-    }
-
-    let mut entries: Vec<&GrfFileEntry> = archive.get_entries().collect();
-    entries.sort_unstable_by(|a, b| a.offset.cmp(&b.offset));
-    let mut chunks_sizes = BTreeSet::new();
-    let mut available_chunks = BTreeMap::new();
-    for i in 0..entries.len() - 1 {
-        let left_entry = entries[i];
-        let right_entry = entries[i + 1];
-        let expected_entry_offset = left_entry.offset + left_entry.size_compressed_aligned as u64;
-        let space_between_entries = right_entry
-            .offset
-            .checked_sub(expected_entry_offset)
-            .ok_or_else(|| GrufError::parsing_error("Archive is malformed"))?;
-        let space_between_entries = usize::try_from(space_between_entries)?;
-        chunks_sizes.insert((space_between_entries, expected_entry_offset));
-        available_chunks.insert(
-            expected_entry_offset,
-            AvailableChunk {
-                size: space_between_entries,
-            },
-        );
-    }
-    let last_entry = entries
-        .last()
-        .ok_or_else(|| GrufError::parsing_error("Cannot get last entry"))?;
-    let end_offset = last_entry.offset + last_entry.size_compressed_aligned as u64;
-    Ok(AvailableChunkList {
-        end_offset,
-        sizes: chunks_sizes,
-        chunks: available_chunks,
-    })
+pub fn list_available_chunks<R: Read + Seek>(
+    archive: &mut GrfArchive<R>,
+) -> Result<AvailableChunkList> {
+    // Data of shadowed entries is kept until a new file table is
+    // written, as other readers might still use it
+    let used_chunks: Vec<(u64, usize)> = archive
+        .get_entries()
+        .map(|e| (e.offset, e.size_compressed_aligned))
+        .chain(archive.shadowed_chunks().iter().copied())
+        .collect();
+    AvailableChunkList::from_used_chunks(used_chunks)
 }
 
 impl AvailableChunkList {
@@ -62,7 +49,138 @@ impl AvailableChunkList {
             end_offset,
             sizes,
             chunks,
+            shared_chunks: HashMap::new(),
+        }
+    }
+
+    /// Builds a list from the chunks of memory currently in use.
+    ///
+    /// Empty chunks are ignored. Chunks starting at the same offset are
+    /// considered shared. Fails if other chunks overlap.
+    pub fn from_used_chunks<I>(used_chunks: I) -> Result<AvailableChunkList>
+    where
+        I: IntoIterator<Item = (u64, usize)>,
+    {
+        let mut used_chunks: Vec<(u64, usize)> = used_chunks
+            .into_iter()
+            .filter(|(_, size)| *size > 0)
+            .collect();
+        used_chunks.sort_unstable();
+        let mut chunk_list = AvailableChunkList::new();
+        let mut previous_chunk: Option<(u64, usize)> = None;
+        for (offset, size) in used_chunks {
+            if let Some((previous_offset, previous_size)) = previous_chunk {
+                if previous_offset == offset {
+                    let shared_chunk =
+                        chunk_list
+                            .shared_chunks
+                            .entry(offset)
+                            .or_insert(SharedChunk {
+                                size: previous_size,
+                                ref_count: 1,
+                            });
+                    shared_chunk.ref_count += 1;
+                    shared_chunk.size = cmp::max(shared_chunk.size, size);
+                    chunk_list.end_offset = offset + shared_chunk.size as u64;
+                    continue;
+                }
+            }
+            previous_chunk = Some((offset, size));
+            let space_between_chunks = offset
+                .checked_sub(chunk_list.end_offset)
+                .ok_or_else(|| GrufError::parsing_error("Archive is malformed"))?;
+            if space_between_chunks > 0 {
+                chunk_list.insert_chunk_internal(
+                    chunk_list.end_offset,
+                    usize::try_from(space_between_chunks)?,
+                );
+            }
+            chunk_list.end_offset = offset + size as u64;
+        }
+        Ok(chunk_list)
+    }
+
+    /// Returns the offset right after the last used chunk
+    pub fn end_offset(&self) -> u64 {
+        self.end_offset
+    }
+
+    /// Returns the offset and size of the first available chunk, if any
+    pub fn first_chunk(&self) -> Option<(u64, usize)> {
+        self.chunks
+            .iter()
+            .next()
+            .map(|(offset, chunk)| (*offset, chunk.size))
+    }
+
+    /// Returns the offset of the first available chunk that can hold `size`
+    /// bytes and is located before `limit`, if any
+    pub fn find_chunk_before(&self, size: usize, limit: u64) -> Option<u64> {
+        // Avoid walking through the list when no chunk is big enough
+        let (max_size, _) = self.sizes.iter().next_back()?;
+        if *max_size < size {
+            return None;
+        }
+        self.chunks
+            .range(..limit)
+            .find(|(_, chunk)| chunk.size >= size)
+            .map(|(offset, _)| *offset)
+    }
+
+    /// Returns the offset of the first available chunk that can hold `size`
+    /// bytes and is located at or after `limit`, if any
+    pub fn find_chunk_after(&self, size: usize, limit: u64) -> Option<u64> {
+        // Avoid walking through the list when no chunk is big enough
+        let (max_size, _) = self.sizes.iter().next_back()?;
+        if *max_size < size {
+            return None;
+        }
+        self.chunks
+            .range(limit..)
+            .find(|(_, chunk)| chunk.size >= size)
+            .map(|(offset, _)| *offset)
+    }
+
+    /// Marks a specific chunk of memory as used.
+    ///
+    /// The chunk must either be located after the last used chunk or be
+    /// contained in an available chunk. The list is left untouched otherwise.
+    pub fn reserve_chunk(&mut self, offset: u64, size: usize) -> Result<()> {
+        let chunk_end_offset = offset + size as u64;
+        if offset >= self.end_offset {
+            if offset > self.end_offset {
+                self.insert_chunk_internal(
+                    self.end_offset,
+                    usize::try_from(offset - self.end_offset)?,
+                );
+            }
+            self.end_offset = chunk_end_offset;
+            return Ok(());
+        }
+
+        let (available_offset, available_size) = self
+            .chunks
+            .range(..=offset)
+            .next_back()
+            .map(|(o, c)| (*o, c.size))
+            .ok_or(GrufError::DynAllocError)?;
+        let available_end_offset = available_offset + available_size as u64;
+        if chunk_end_offset > available_end_offset {
+            return Err(GrufError::DynAllocError);
+        }
+        let _ = self
+            .remove_chunk_internal(available_offset)
+            .ok_or(GrufError::DynAllocError)?;
+        if offset > available_offset {
+            self.insert_chunk_internal(available_offset, (offset - available_offset) as usize);
         }
+        if available_end_offset > chunk_end_offset {
+            self.insert_chunk_internal(
+                chunk_end_offset,
+                (available_end_offset - chunk_end_offset) as usize,
+            );
+        }
+        Ok(())
     }
 
     /// Acquire a chunk of memory
@@ -93,10 +211,26 @@ impl AvailableChunkList {
         }
     }
 
+    /// Registers an additional reference to an already "allocated" chunk of
+    /// memory. The chunk must then be freed once per reference.
+    pub fn add_chunk_reference(&mut self, offset: u64, size: usize) {
+        let shared_chunk = self
+            .shared_chunks
+            .entry(offset)
+            .or_insert(SharedChunk { size, ref_count: 1 });
+        shared_chunk.ref_count += 1;
+        shared_chunk.size = cmp::max(shared_chunk.size, size);
+    }
+
     /// Resizes an already "allocated" chunk of memory
     /// This realloc method assumes all free chunks are merged (i.e. there can
     /// only be used chunks between 2 free chunks)
     pub fn realloc_chunk(&mut self, offset: u64, size: usize, new_size: usize) -> Result<u64> {
+        if self.shared_chunks.contains_key(&offset) {
+            // Shared chunks cannot be resized, drop the reference and move
+            let _ = self.free_chunk(offset, size)?;
+            return self.alloc_chunk(new_size);
+        }
         let end_offset = offset + size as u64;
         let new_end_offset = offset + new_size as u64;
         if end_offset == self.end_offset {
@@ -118,14 +252,42 @@ impl AvailableChunkList {
         }
 
         // Next chunk is used or free but too small, must move
-        self.free_chunk(offset, size)?;
+        let _ = self.free_chunk(offset, size)?;
         self.alloc_chunk(new_size)
     }
 
-    /// Releases a chunk of memory
+    /// Returns true if the used chunk located at `offset` is referenced more
+    /// than once
+    pub fn is_shared_chunk(&self, offset: u64) -> bool {
+        self.shared_chunks
+            .get(&offset)
+            .map(|shared_chunk| shared_chunk.ref_count > 1)
+            .unwrap_or(false)
+    }
+
+    /// Releases a chunk of memory, or drops a reference to it if it's shared
+    /// Returns true if the chunk has actually been released.
     /// This method trusts the input given by the caller.
     /// At the moment, passing bad parameters to this method can mess up the list.
-    pub fn free_chunk(&mut self, offset: u64, size: usize) -> Result<()> {
+    pub fn free_chunk(&mut self, offset: u64, size: usize) -> Result<bool> {
+        match self.shared_chunks.get_mut(&offset) {
+            None => self.release_chunk_internal(offset, size)?,
+            Some(shared_chunk) => {
+                shared_chunk.ref_count -= 1;
+                if shared_chunk.ref_count > 0 {
+                    return Ok(false);
+                }
+                let shared_chunk = self
+                    .shared_chunks
+                    .remove(&offset)
+                    .ok_or(GrufError::DynAllocError)?;
+                self.release_chunk_internal(offset, shared_chunk.size)?
+            }
+        }
+        Ok(true)
+    }
+
+    fn release_chunk_internal(&mut self, offset: u64, size: usize) -> Result<()> {
         let chunk_end_offset = offset + size as u64;
         let mut new_chunk_offset = offset;
         let mut new_chunk_size = size;
@@ -146,8 +308,9 @@ impl AvailableChunkList {
         }
         // Check right merge
         if chunk_end_offset == self.end_offset {
-            // "Merge" to the right
+            // "Merge" to the right, the chunk isn't listed anymore
             self.end_offset = new_chunk_offset;
+            return Ok(());
         } else if self.chunks.contains_key(&chunk_end_offset) {
             // Merge to the right with another chunk
             let chunk = self
@@ -268,6 +431,108 @@ mod tests {
         assert_eq!(offset5, offset1);
     }
 
+    #[test]
+    fn test_chunk_list_from_used_chunks() {
+        let chunk_size: usize = 64;
+        let used_chunks = vec![
+            (START_OFFSET + 4 * chunk_size as u64, chunk_size),
+            (START_OFFSET + chunk_size as u64, chunk_size),
+            (START_OFFSET + 3 * chunk_size as u64, 0),
+        ];
+        let chunk_list = AvailableChunkList::from_used_chunks(used_chunks).unwrap();
+        assert_eq!(
+            chunk_list.end_offset(),
+            START_OFFSET + 5 * chunk_size as u64
+        );
+        assert_eq!(chunk_list.first_chunk(), Some((START_OFFSET, chunk_size)));
+        // Only the second available chunk can hold 2 chunks
+        assert_eq!(
+            chunk_list.find_chunk_before(2 * chunk_size, chunk_list.end_offset()),
+            Some(START_OFFSET + 2 * chunk_size as u64)
+        );
+        assert_eq!(
+            chunk_list.find_chunk_before(2 * chunk_size, START_OFFSET + chunk_size as u64),
+            None
+        );
+
+        // Overlapping chunks are rejected
+        let used_chunks = vec![(START_OFFSET, chunk_size), (START_OFFSET + 1, chunk_size)];
+        assert!(AvailableChunkList::from_used_chunks(used_chunks).is_err());
+    }
+
+    #[test]
+    fn test_chunk_list_shared_chunks() {
+        let chunk_size: usize = 64;
+        let used_chunks = vec![
+            (START_OFFSET, chunk_size),
+            (START_OFFSET + chunk_size as u64, chunk_size),
+            (START_OFFSET, chunk_size),
+            (START_OFFSET, chunk_size),
+        ];
+        let mut chunk_list = AvailableChunkList::from_used_chunks(used_chunks).unwrap();
+        assert_eq!(
+            chunk_list.end_offset(),
+            START_OFFSET + 2 * chunk_size as u64
+        );
+        assert_eq!(chunk_list.first_chunk(), None);
+
+        // The shared chunk is only released with its last reference
+        assert!(chunk_list.is_shared_chunk(START_OFFSET));
+        assert!(!chunk_list.free_chunk(START_OFFSET, chunk_size).unwrap());
+        assert!(!chunk_list.free_chunk(START_OFFSET, chunk_size).unwrap());
+        assert!(!chunk_list.is_shared_chunk(START_OFFSET));
+        assert_eq!(chunk_list.first_chunk(), None);
+        assert!(chunk_list.free_chunk(START_OFFSET, chunk_size).unwrap());
+        assert_eq!(chunk_list.first_chunk(), Some((START_OFFSET, chunk_size)));
+
+        // Shared chunks are moved when resized
+        let offset = chunk_list.alloc_chunk(chunk_size).unwrap();
+        assert_eq!(offset, START_OFFSET);
+        chunk_list.add_chunk_reference(offset, chunk_size);
+        let res = chunk_list
+            .realloc_chunk(offset, chunk_size, chunk_size - 1)
+            .unwrap();
+        assert_eq!(res, START_OFFSET + 2 * chunk_size as u64);
+        chunk_list.free_chunk(offset, chunk_size).unwrap();
+        assert_eq!(chunk_list.first_chunk(), Some((START_OFFSET, chunk_size)));
+    }
+
+    #[test]
+    fn test_chunk_list_reserve() {
+        let chunk_size: usize = 64;
+        let mut chunk_list = AvailableChunkList::new();
+        // Reserve a chunk after the end of the list
+        chunk_list
+            .reserve_chunk(START_OFFSET + 3 * chunk_size as u64, chunk_size)
+            .unwrap();
+        assert_eq!(
+            chunk_list.end_offset(),
+            START_OFFSET + 4 * chunk_size as u64
+        );
+        assert_eq!(
+            chunk_list.first_chunk(),
+            Some((START_OFFSET, 3 * chunk_size))
+        );
+        // Reserve a chunk in the middle of an available chunk
+        chunk_list
+            .reserve_chunk(START_OFFSET + chunk_size as u64, chunk_size)
+            .unwrap();
+        assert_eq!(chunk_list.first_chunk(), Some((START_OFFSET, chunk_size)));
+        // Used chunks cannot be reserved
+        assert!(chunk_list
+            .reserve_chunk(START_OFFSET + chunk_size as u64, 1)
+            .is_err());
+        assert!(chunk_list
+            .reserve_chunk(START_OFFSET, 2 * chunk_size)
+            .is_err());
+
+        let res = chunk_list.alloc_chunk(chunk_size).unwrap();
+        assert_eq!(res, START_OFFSET);
+        let res = chunk_list.alloc_chunk(chunk_size).unwrap();
+        assert_eq!(res, START_OFFSET + 2 * chunk_size as u64);
+        assert_eq!(chunk_list.first_chunk(), None);
+    }
+
     #[test]
     fn test_chunk_list_left_merge() {
         let chunk_size: usize = 64;
@@ -0,0 +1,743 @@
+use std::cmp;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::archive::{normalize_path, read_exact_bounded, MAX_TABLE_SIZE};
+use crate::grf::crypto::DES_BLOCK_SIZE;
+use crate::grf::reader::{
+    entry_decoder, parse_grf_file_entry_101, parse_grf_file_entry_200, parse_grf_file_entry_300,
+    parse_grf_header, parse_grf_table_info_200, table_info_padding, GRF_HEADER_SIZE,
+    GRF_TABLE_INFO2_SIZE,
+};
+use crate::grf::{GrfFileEncryption, GrfFileEntry};
+use crate::{GrufError, Result};
+use flate2::read::ZlibDecoder;
+use serde::Serialize;
+
+/// Result of a consistency check of a GRF archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GrfCheckReport {
+    pub version_major: u32,
+    pub version_minor: u32,
+    /// Number of entries declared in the header
+    pub file_count: usize,
+    /// Number of entries that could be parsed from the file table
+    pub parsed_entry_count: usize,
+    pub archive_size: u64,
+    pub issues: Vec<GrfIssue>,
+}
+
+impl GrfCheckReport {
+    /// Returns true if no issue has been found
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Problem found while checking a GRF archive.
+///
+/// Offsets are relative to the beginning of the archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GrfIssue {
+    /// The header is missing or cannot be parsed
+    InvalidHeader { message: String },
+    UnsupportedVersion {
+        version_major: u32,
+        version_minor: u32,
+    },
+    /// The file table doesn't fit in the archive
+    TableOutOfBounds {
+        offset: u64,
+        size: u64,
+        archive_size: u64,
+    },
+    /// The file table is bigger than the supported maximum size
+    TableTooBig { size: u64, max_size: usize },
+    /// The file table cannot be decompressed
+    CorruptedTable { message: String },
+    /// The size of the decompressed file table doesn't match the size
+    /// declared in the archive
+    TableSizeMismatch {
+        expected_size: usize,
+        actual_size: usize,
+    },
+    /// An entry of the file table cannot be parsed, following entries are
+    /// ignored
+    MalformedTable { entry_index: usize, message: String },
+    /// Data is left in the file table after the declared number of entries
+    UnparsedTableData { size: usize },
+    /// The number of parsed entries doesn't match the header
+    FileCountMismatch { expected: usize, actual: usize },
+    /// Several entries share the same (normalized) path
+    DuplicatePath { path: String },
+    /// Several entries share the same data offset
+    DuplicateOffset {
+        path: String,
+        other_path: String,
+        offset: u64,
+    },
+    /// An entry's data overlaps another entry's data
+    OverlappingEntries {
+        path: String,
+        offset: u64,
+        other_path: String,
+        other_offset: u64,
+    },
+    /// An entry's data overlaps the file table
+    OverlapsFileTable { path: String, offset: u64 },
+    /// An entry's data goes past the end of the archive
+    EntryPastEof {
+        path: String,
+        offset: u64,
+        size: usize,
+        archive_size: u64,
+    },
+    /// An entry's aligned size is smaller than its compressed size (or isn't
+    /// a multiple of 8 bytes for encrypted entries)
+    AlignedSizeTooSmall {
+        path: String,
+        size_compressed: usize,
+        size_compressed_aligned: usize,
+    },
+    /// An entry's content cannot be decompressed
+    CorruptedContent { path: String, message: String },
+    /// An entry's content doesn't decompress to the declared size
+    ContentSizeMismatch {
+        path: String,
+        expected_size: usize,
+        actual_size: u64,
+    },
+    /// Data is present after the last entry and the file table
+    TrailingData { offset: u64, size: u64 },
+}
+
+/// Checks the consistency of the GRF archive located at the given path
+pub fn check_file<P: AsRef<Path>>(grf_path: P) -> Result<GrfCheckReport> {
+    let file = File::open(grf_path)?;
+    check(file)
+}
+
+/// Checks the consistency of a GRF archive.
+///
+/// The archive is expected to start at the object's current position. Every
+/// entry's content is decompressed during the check. Problems are reported in
+/// the returned report, errors are only returned when the underlying object
+/// cannot be read.
+pub fn check<R: Read + Seek>(mut obj: R) -> Result<GrfCheckReport> {
+    let start_offset = obj.seek(SeekFrom::Current(0))?;
+    let mut report = GrfCheckReport::new(obj.seek(SeekFrom::End(0))?.saturating_sub(start_offset));
+    let archive_size = report.archive_size;
+    let ParsedTable {
+        entries,
+        table_offset,
+        table_end,
+    } = match read_file_table(&mut obj, start_offset, &mut report)? {
+        Some(parsed_table) => parsed_table,
+        None => return Ok(report),
+    };
+
+    // Entries, empty files don't have any data to check
+    let mut file_entries: Vec<&GrfFileEntry> = entries
+        .iter()
+        .filter(|e| e.entry_type.is_file() && !is_empty_entry(e))
+        .collect();
+    file_entries.sort_by_key(|e| e.offset);
+    let mut data_end = table_end;
+    let mut previous_entry: Option<&GrfFileEntry> = None;
+    // Entry whose data spans the furthest so far
+    let mut furthest_entry: Option<&GrfFileEntry> = None;
+    for entry in file_entries {
+        if entry.size_compressed_aligned == 0 {
+            // No data to locate, the content checks report the entry
+            check_entry(&mut obj, start_offset, archive_size, entry, &mut report)?;
+            continue;
+        }
+        let entry_end = entry.offset + entry.size_compressed_aligned as u64;
+        match (previous_entry, furthest_entry) {
+            (Some(previous_entry), _) if previous_entry.offset == entry.offset => {
+                report.issues.push(GrfIssue::DuplicateOffset {
+                    path: entry.relative_path.clone(),
+                    other_path: previous_entry.relative_path.clone(),
+                    offset: entry.offset,
+                });
+            }
+            (_, Some(furthest_entry)) if entry_end_offset(furthest_entry) > entry.offset => {
+                report.issues.push(GrfIssue::OverlappingEntries {
+                    path: entry.relative_path.clone(),
+                    offset: entry.offset,
+                    other_path: furthest_entry.relative_path.clone(),
+                    other_offset: furthest_entry.offset,
+                });
+            }
+            _ => {}
+        }
+        previous_entry = Some(entry);
+        match furthest_entry {
+            Some(e) if entry_end_offset(e) >= entry_end => {}
+            _ => furthest_entry = Some(entry),
+        }
+        if entry.offset < table_end && table_offset < entry_end {
+            report.issues.push(GrfIssue::OverlapsFileTable {
+                path: entry.relative_path.clone(),
+                offset: entry.offset,
+            });
+        }
+        if entry_end <= archive_size {
+            data_end = data_end.max(entry_end);
+        }
+        check_entry(&mut obj, start_offset, archive_size, entry, &mut report)?;
+    }
+
+    if data_end < archive_size {
+        report.issues.push(GrfIssue::TrailingData {
+            offset: data_end,
+            size: archive_size - data_end,
+        });
+    }
+    Ok(report)
+}
+
+/// Entries read from an archive's file table
+pub(crate) struct ParsedTable {
+    pub entries: Vec<GrfFileEntry>,
+    pub table_offset: u64,
+    pub table_end: u64,
+}
+
+impl GrfCheckReport {
+    pub(crate) fn new(archive_size: u64) -> Self {
+        Self {
+            version_major: 0,
+            version_minor: 0,
+            file_count: 0,
+            parsed_entry_count: 0,
+            archive_size,
+            issues: Vec::new(),
+        }
+    }
+}
+
+/// Reads an archive's header and file table, problems found are added to the
+/// report.
+///
+/// Returns `None` if the archive's header cannot be used.
+pub(crate) fn read_file_table<R: Read + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    report: &mut GrfCheckReport,
+) -> Result<Option<ParsedTable>> {
+    // Header
+    if report.archive_size < GRF_HEADER_SIZE as u64 {
+        report.issues.push(GrfIssue::InvalidHeader {
+            message: "Archive is too small".to_string(),
+        });
+        return Ok(None);
+    }
+    let mut grf_header_buf = [0; GRF_HEADER_SIZE];
+    obj.seek(SeekFrom::Start(start_offset))?;
+    obj.read_exact(&mut grf_header_buf)?;
+    let grf_header = match parse_grf_header(&grf_header_buf) {
+        Ok((_, grf_header)) => grf_header,
+        Err(_) => {
+            report.issues.push(GrfIssue::InvalidHeader {
+                message: "Failed to parse header".to_string(),
+            });
+            return Ok(None);
+        }
+    };
+    report.version_major = grf_header.version_major;
+    report.version_minor = grf_header.version_minor;
+    report.file_count = grf_header.file_count;
+
+    // File table
+    let table_offset = GRF_HEADER_SIZE as u64 + grf_header.file_table_offset;
+    let (entries, table_end) = match (grf_header.version_major, grf_header.version_minor) {
+        (2, 0) | (3, 0) => check_table_200(obj, start_offset, table_offset, report)?,
+        (1, 1..=3) => check_table_101(obj, start_offset, table_offset, report)?,
+        _ => {
+            report.issues.push(GrfIssue::UnsupportedVersion {
+                version_major: grf_header.version_major,
+                version_minor: grf_header.version_minor,
+            });
+            return Ok(None);
+        }
+    };
+    report.parsed_entry_count = entries.len();
+    if entries.len() != grf_header.file_count {
+        report.issues.push(GrfIssue::FileCountMismatch {
+            expected: grf_header.file_count,
+            actual: entries.len(),
+        });
+    }
+    let mut paths = HashSet::with_capacity(entries.len());
+    for entry in &entries {
+        if !paths.insert(normalize_path(&entry.relative_path)) {
+            report.issues.push(GrfIssue::DuplicatePath {
+                path: entry.relative_path.clone(),
+            });
+        }
+    }
+    Ok(Some(ParsedTable {
+        entries,
+        table_offset,
+        table_end,
+    }))
+}
+
+/// Parses the file table of GRF 2.0 and 3.0 archives, returns the entries that
+/// could be parsed and the end offset of the table
+fn check_table_200<R: Read + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    table_offset: u64,
+    report: &mut GrfCheckReport,
+) -> Result<(Vec<GrfFileEntry>, u64)> {
+    let archive_size = report.archive_size;
+    let table_info_offset = table_offset + table_info_padding(report.version_major) as u64;
+    if table_info_offset + GRF_TABLE_INFO2_SIZE as u64 > archive_size {
+        report.issues.push(GrfIssue::TableOutOfBounds {
+            offset: table_offset,
+            size: table_info_offset - table_offset + GRF_TABLE_INFO2_SIZE as u64,
+            archive_size,
+        });
+        return Ok((Vec::new(), archive_size));
+    }
+    let mut table_info_buf = [0; GRF_TABLE_INFO2_SIZE];
+    obj.seek(SeekFrom::Start(start_offset + table_info_offset))?;
+    obj.read_exact(&mut table_info_buf)?;
+    // Parsing cannot fail since the buffer is big enough
+    let (_, table_info) = parse_grf_table_info_200(&table_info_buf)
+        .map_err(|_| GrufError::parsing_error("Failed to parse table info"))?;
+    let table_end =
+        table_info_offset + GRF_TABLE_INFO2_SIZE as u64 + table_info.table_size_compressed as u64;
+    if table_end > archive_size {
+        report.issues.push(GrfIssue::TableOutOfBounds {
+            offset: table_offset,
+            size: table_end - table_offset,
+            archive_size,
+        });
+        return Ok((Vec::new(), archive_size));
+    }
+    if table_info.table_size_compressed == 0 {
+        return Ok((Vec::new(), table_end));
+    }
+
+    // Decompress the table with zlib, stop right after the declared size
+    let mut decompressed_table = Vec::new();
+    let file_chunk = obj.by_ref().take(table_info.table_size_compressed as u64);
+    let max_table_size = cmp::min(table_info.table_size, MAX_TABLE_SIZE) as u64 + 1;
+    if let Err(e) = ZlibDecoder::new(file_chunk)
+        .take(max_table_size)
+        .read_to_end(&mut decompressed_table)
+    {
+        report.issues.push(GrfIssue::CorruptedTable {
+            message: e.to_string(),
+        });
+    }
+    if decompressed_table.len() != table_info.table_size {
+        report.issues.push(GrfIssue::TableSizeMismatch {
+            expected_size: table_info.table_size,
+            actual_size: decompressed_table.len(),
+        });
+    }
+    let entries = if report.version_major == 3 {
+        parse_entries(&decompressed_table, report, parse_grf_file_entry_300)
+    } else {
+        parse_entries(&decompressed_table, report, parse_grf_file_entry_200)
+    };
+    Ok((entries, table_end))
+}
+
+/// Parses the file table of GRF 1.x archives, returns the entries that could
+/// be parsed and the end offset of the table
+fn check_table_101<R: Read + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    table_offset: u64,
+    report: &mut GrfCheckReport,
+) -> Result<(Vec<GrfFileEntry>, u64)> {
+    let archive_size = report.archive_size;
+    if table_offset > archive_size {
+        report.issues.push(GrfIssue::TableOutOfBounds {
+            offset: table_offset,
+            size: 0,
+            archive_size,
+        });
+        return Ok((Vec::new(), archive_size));
+    }
+    // The table isn't compressed and spans until the end of the file
+    let table_size = archive_size - table_offset;
+    if table_size > MAX_TABLE_SIZE as u64 {
+        report.issues.push(GrfIssue::TableTooBig {
+            size: table_size,
+            max_size: MAX_TABLE_SIZE,
+        });
+        return Ok((Vec::new(), archive_size));
+    }
+    obj.seek(SeekFrom::Start(start_offset + table_offset))?;
+    let table = read_exact_bounded(obj.by_ref(), table_size)?;
+    let entries = parse_entries(&table, report, parse_grf_file_entry_101);
+    Ok((entries, archive_size))
+}
+
+fn parse_entries<F>(table: &[u8], report: &mut GrfCheckReport, parser: F) -> Vec<GrfFileEntry>
+where
+    F: Fn(&[u8]) -> nom::IResult<&[u8], GrfFileEntry>,
+{
+    let mut entries = Vec::new();
+    let mut input = table;
+    while entries.len() < report.file_count && !input.is_empty() {
+        match parser(input) {
+            Ok((output, entry)) => {
+                entries.push(entry);
+                input = output;
+            }
+            Err(_) => {
+                report.issues.push(GrfIssue::MalformedTable {
+                    entry_index: entries.len(),
+                    message: format!(
+                        "Failed to parse entry at offset {} of the table",
+                        table.len() - input.len()
+                    ),
+                });
+                return entries;
+            }
+        }
+    }
+    if !input.is_empty() {
+        report
+            .issues
+            .push(GrfIssue::UnparsedTableData { size: input.len() });
+    }
+    entries
+}
+
+fn is_empty_entry(entry: &GrfFileEntry) -> bool {
+    entry.size == 0 && entry.size_compressed == 0 && entry.size_compressed_aligned == 0
+}
+
+fn entry_end_offset(entry: &GrfFileEntry) -> u64 {
+    entry.offset + entry.size_compressed_aligned as u64
+}
+
+fn check_entry<R: Read + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    archive_size: u64,
+    entry: &GrfFileEntry,
+    report: &mut GrfCheckReport,
+) -> Result<()> {
+    let is_encrypted = entry.encryption != GrfFileEncryption::Unencrypted;
+    if entry.size_compressed_aligned < entry.size_compressed
+        || (is_encrypted && entry.size_compressed_aligned % DES_BLOCK_SIZE != 0)
+    {
+        report.issues.push(GrfIssue::AlignedSizeTooSmall {
+            path: entry.relative_path.clone(),
+            size_compressed: entry.size_compressed,
+            size_compressed_aligned: entry.size_compressed_aligned,
+        });
+    }
+    if entry.offset + entry.size_compressed_aligned as u64 > archive_size {
+        report.issues.push(GrfIssue::EntryPastEof {
+            path: entry.relative_path.clone(),
+            offset: entry.offset,
+            size: entry.size_compressed_aligned,
+            archive_size,
+        });
+        return Ok(());
+    }
+
+    // Decompress the content and measure its size
+    obj.seek(SeekFrom::Start(start_offset + entry.offset))?;
+    let file_chunk = obj.by_ref().take(entry.size_compressed_aligned as u64);
+    let mut decoder = entry_decoder(file_chunk, entry);
+    match io::copy(&mut decoder, &mut io::sink()) {
+        Ok(actual_size) => {
+            if actual_size != entry.size as u64 {
+                report.issues.push(GrfIssue::ContentSizeMismatch {
+                    path: entry.relative_path.clone(),
+                    expected_size: entry.size,
+                    actual_size,
+                });
+            }
+        }
+        Err(e) => report.issues.push(GrfIssue::CorruptedContent {
+            path: entry.relative_path.clone(),
+            message: e.to_string(),
+        }),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grf::{GrfArchiveBuilder, GrfEntryFlags, GRF_HEADER_MAGIC};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::{Cursor, Write};
+    use std::path::PathBuf;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Builds a GRF 0x200 archive from raw data and raw table entries
+    fn build_grf_200(
+        data: &[u8],
+        entries: &[(&str, u32, u32, u32, u32)],
+        table_size_delta: i64,
+        trailing_data: &[u8],
+    ) -> Vec<u8> {
+        let mut table = Vec::new();
+        for (path, size_compressed, size_compressed_aligned, size, offset) in entries {
+            table.extend_from_slice(path.as_bytes());
+            table.push(0);
+            table.extend_from_slice(&size_compressed.to_le_bytes());
+            table.extend_from_slice(&size_compressed_aligned.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+            table.push(GrfEntryFlags::FILE.bits());
+            table.extend_from_slice(&offset.to_le_bytes());
+        }
+        let compressed_table = compress(&table);
+        let mut grf = Vec::new();
+        grf.extend_from_slice(GRF_HEADER_MAGIC.as_bytes());
+        grf.extend_from_slice(&[0; 14]);
+        grf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&0i32.to_le_bytes());
+        grf.extend_from_slice(&(entries.len() as i32 + 7).to_le_bytes());
+        grf.extend_from_slice(&0x200u32.to_le_bytes());
+        grf.extend_from_slice(data);
+        grf.extend_from_slice(&(compressed_table.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&((table.len() as i64 + table_size_delta) as u32).to_le_bytes());
+        grf.extend_from_slice(&compressed_table);
+        grf.extend_from_slice(trailing_data);
+        grf
+    }
+
+    #[test]
+    fn test_check_valid_archives() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for grf_name in &[
+            "102-empty.grf",
+            "102-small.grf",
+            "103-empty.grf",
+            "103-small.grf",
+            "200-empty.grf",
+            "200-small.grf",
+        ] {
+            let report = check_file(grf_dir_path.join(grf_name)).unwrap();
+            assert!(report.is_ok(), "{}: {:?}", grf_name, report.issues);
+            assert_eq!(report.parsed_entry_count, report.file_count);
+        }
+    }
+
+    #[test]
+    fn test_check_valid_archive_300() {
+        let mut grf = Cursor::new(Vec::new());
+        {
+            let mut builder = GrfArchiveBuilder::create(&mut grf, 3, 0).unwrap();
+            builder
+                .add_file("data\\file.txt".to_string(), &b"some content"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        grf.set_position(0);
+        let report = check(grf).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.version_major, 3);
+        assert_eq!(report.parsed_entry_count, 1);
+    }
+
+    #[test]
+    fn test_check_corrupted_archive() {
+        let content = compress(b"some content");
+        let content_size = content.len() as u32;
+        let mut data = content.clone();
+        data.extend_from_slice(&content);
+        let entries = [
+            ("a", content_size, content_size, 12, 0),
+            // Same offset as "a"
+            ("b", content_size, content_size, 12, 0),
+            // Overlaps "b"
+            ("c", content_size, content_size, 12, 4),
+            // Past the end of the archive
+            ("d", content_size, content_size, 12, 0x10000),
+            // Aligned size is too small
+            ("e", content_size, content_size - 1, 12, content_size),
+            // Wrong uncompressed size
+            ("f", content_size, content_size, 13, content_size),
+        ];
+        let grf = build_grf_200(&data, &entries, 1, b"garbage");
+        let report = check(Cursor::new(grf)).unwrap();
+        assert_eq!(report.file_count, entries.len());
+        assert_eq!(report.parsed_entry_count, entries.len());
+        let archive_size = report.archive_size;
+        let expected_issues = [
+            GrfIssue::TableSizeMismatch {
+                expected_size: 6 * 19 + 1,
+                actual_size: 6 * 19,
+            },
+            GrfIssue::DuplicateOffset {
+                path: "b".to_string(),
+                other_path: "a".to_string(),
+                offset: GRF_HEADER_SIZE as u64,
+            },
+            GrfIssue::OverlappingEntries {
+                path: "c".to_string(),
+                offset: GRF_HEADER_SIZE as u64 + 4,
+                other_path: "a".to_string(),
+                other_offset: GRF_HEADER_SIZE as u64,
+            },
+            GrfIssue::AlignedSizeTooSmall {
+                path: "e".to_string(),
+                size_compressed: content_size as usize,
+                size_compressed_aligned: content_size as usize - 1,
+            },
+            GrfIssue::ContentSizeMismatch {
+                path: "f".to_string(),
+                expected_size: 13,
+                actual_size: 12,
+            },
+            GrfIssue::EntryPastEof {
+                path: "d".to_string(),
+                offset: GRF_HEADER_SIZE as u64 + 0x10000,
+                size: content_size as usize,
+                archive_size,
+            },
+            GrfIssue::TrailingData {
+                offset: archive_size - 7,
+                size: 7,
+            },
+        ];
+        for issue in &expected_issues {
+            assert!(
+                report.issues.contains(issue),
+                "missing {:?} in {:?}",
+                issue,
+                report.issues
+            );
+        }
+        assert!(report
+            .issues
+            .iter()
+            .all(|issue| !matches!(issue, GrfIssue::CorruptedTable { .. })));
+    }
+
+    #[test]
+    fn test_check_entries_without_data() {
+        let content = compress(b"some content");
+        let content_size = content.len() as u32;
+        let entries = [
+            ("a", content_size, content_size, 12, 0),
+            // Empty file
+            ("b", 0, 0, 0, 0),
+            // Aligned size is null but the compressed size isn't
+            ("c", content_size, 0, 12, 0),
+            // Sizes are null but the content isn't empty
+            ("d", 0, 0, 12, 0),
+        ];
+        let grf = build_grf_200(&content, &entries, 0, &[]);
+        let report = check(Cursor::new(grf)).unwrap();
+        assert_eq!(report.parsed_entry_count, entries.len());
+        assert!(report.issues.contains(&GrfIssue::AlignedSizeTooSmall {
+            path: "c".to_string(),
+            size_compressed: content_size as usize,
+            size_compressed_aligned: 0,
+        }));
+        let is_reported = |path: &str| {
+            report.issues.iter().any(|issue| match issue {
+                GrfIssue::ContentSizeMismatch { path: p, .. }
+                | GrfIssue::CorruptedContent { path: p, .. } => p == path,
+                _ => false,
+            })
+        };
+        assert!(!is_reported("a"));
+        assert!(!is_reported("b"));
+        assert!(is_reported("d"));
+    }
+
+    /// Archive whose content is zeroed after the header, so that big archives
+    /// can be checked without being allocated
+    struct SparseArchive {
+        header: Vec<u8>,
+        size: u64,
+        position: u64,
+    }
+
+    impl Read for SparseArchive {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let remaining = self.size.saturating_sub(self.position);
+            let read_size = cmp::min(buf.len() as u64, remaining) as usize;
+            for (i, byte) in buf[..read_size].iter_mut().enumerate() {
+                let offset = self.position as usize + i;
+                *byte = self.header.get(offset).copied().unwrap_or(0);
+            }
+            self.position += read_size as u64;
+            Ok(read_size)
+        }
+    }
+
+    impl Seek for SparseArchive {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => (self.size as i64 + offset) as u64,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+            };
+            Ok(self.position)
+        }
+    }
+
+    #[test]
+    fn test_check_table_too_big_101() {
+        let mut header = Vec::new();
+        header.extend_from_slice(GRF_HEADER_MAGIC.as_bytes());
+        header.extend_from_slice(&[0; 14]);
+        // The table starts right after the header
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&7i32.to_le_bytes());
+        header.extend_from_slice(&0x102u32.to_le_bytes());
+        let size = GRF_HEADER_SIZE as u64 + MAX_TABLE_SIZE as u64 + 1;
+        let archive = SparseArchive {
+            header,
+            size,
+            position: 0,
+        };
+        let report = check(archive).unwrap();
+        assert_eq!(
+            report.issues,
+            vec![GrfIssue::TableTooBig {
+                size: MAX_TABLE_SIZE as u64 + 1,
+                max_size: MAX_TABLE_SIZE,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_bad_table() {
+        let grf = build_grf_200(&[], &[("a", 0, 0, 0, 0)], 0, &[]);
+        // Truncate the archive in the middle of the table
+        let truncated_grf = grf[..grf.len() - 4].to_vec();
+        let report = check(Cursor::new(truncated_grf)).unwrap();
+        assert!(matches!(
+            report.issues.as_slice(),
+            [
+                GrfIssue::TableOutOfBounds { .. },
+                GrfIssue::FileCountMismatch { .. }
+            ]
+        ));
+        // Invalid header
+        let report = check(Cursor::new(b"not a GRF".to_vec())).unwrap();
+        assert!(matches!(
+            report.issues.as_slice(),
+            [GrfIssue::InvalidHeader { .. }]
+        ));
+    }
+}
@@ -0,0 +1,303 @@
+use std::convert::{TryFrom, TryInto};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{GrufError, Result};
+use twox_hash::xxh3;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"GRFJRNL\x01";
+const JOURNAL_EXTENSION: &str = "journal";
+// Magic, header offset and header size
+const JOURNAL_RECORD_PREFIX_SIZE: usize = 8 + 8 + 4;
+const JOURNAL_CHECKSUM_SIZE: usize = 8;
+// Records only ever contain a GRF header
+const JOURNAL_MAX_RECORD_SIZE: u64 = 4096;
+
+/// Write-ahead journal used to commit in-place modifications of a GRF archive
+/// atomically.
+///
+/// Modifications never overwrite data referenced by the archive's current
+/// header, so the header is the only thing that has to be replaced
+/// atomically. The new header is first written into a journal file next to
+/// the archive, then into the archive, and the journal file is removed once
+/// the archive has been synced. An interrupted commit is completed (or
+/// discarded, if the journal itself is incomplete) by `recover_file`.
+pub(crate) struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub(crate) fn new<P: AsRef<Path>>(grf_path: P) -> Self {
+        Self {
+            path: journal_path(grf_path),
+        }
+    }
+
+    /// Durably replaces the archive's header.
+    ///
+    /// Everything written into the archive beforehand is synced before the
+    /// header starts referencing it.
+    pub(crate) fn commit(
+        &self,
+        archive: &mut File,
+        header_offset: u64,
+        header: &[u8],
+    ) -> Result<()> {
+        archive.sync_data()?;
+        let record = serialize_record(header_offset, header)?;
+        {
+            let mut journal_file = File::create(&self.path)?;
+            journal_file.write_all(&record)?;
+            journal_file.sync_all()?;
+        }
+        sync_parent_directory(&self.path)?;
+        write_header(archive, header_offset, header)?;
+        remove_journal(&self.path)
+    }
+}
+
+/// Returns the path of the journal used when modifying the GRF archive located
+/// at the given path
+pub fn journal_path<P: AsRef<Path>>(grf_path: P) -> PathBuf {
+    let mut path = OsString::from(grf_path.as_ref().as_os_str());
+    path.push(".");
+    path.push(JOURNAL_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Completes or discards a commit of the GRF archive located at the given path
+/// that has been interrupted (by a crash or a power loss for example).
+///
+/// Returns true if the archive's header has been restored from the journal.
+/// Journals that haven't been entirely written are discarded, the archive's
+/// previous header is still valid in that case.
+pub fn recover_file<P: AsRef<Path>>(grf_path: P) -> Result<bool> {
+    let path = journal_path(&grf_path);
+    let record = match read_record(&path)? {
+        Some(record) => record,
+        None => return Ok(false),
+    };
+    let recovered = match parse_record(&record) {
+        Some((header_offset, header)) => {
+            let mut archive = OpenOptions::new().write(true).open(&grf_path)?;
+            write_header(&mut archive, header_offset, header)?;
+            true
+        }
+        None => false,
+    };
+    remove_journal(&path)?;
+    Ok(recovered)
+}
+
+/// Returns true if a commit of the GRF archive located at the given path has
+/// been interrupted after its journal was entirely written. The archive's
+/// header cannot be trusted until `recover_file` is called in that case.
+pub(crate) fn has_interrupted_commit<P: AsRef<Path>>(grf_path: P) -> Result<bool> {
+    let record = read_record(&journal_path(grf_path))?;
+    Ok(record.map_or(false, |record| parse_record(&record).is_some()))
+}
+
+/// Reads the journal located at the given path, if any
+fn read_record(path: &Path) -> Result<Option<Vec<u8>>> {
+    match File::open(path) {
+        Ok(journal_file) => {
+            let mut record = Vec::new();
+            journal_file
+                .take(JOURNAL_MAX_RECORD_SIZE)
+                .read_to_end(&mut record)?;
+            Ok(Some(record))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn serialize_record(header_offset: u64, header: &[u8]) -> Result<Vec<u8>> {
+    let mut record =
+        Vec::with_capacity(JOURNAL_RECORD_PREFIX_SIZE + header.len() + JOURNAL_CHECKSUM_SIZE);
+    record.extend_from_slice(JOURNAL_MAGIC);
+    record.extend_from_slice(&header_offset.to_le_bytes());
+    record.extend_from_slice(&u32::try_from(header.len())?.to_le_bytes());
+    record.extend_from_slice(header);
+    let checksum = xxh3::hash64(&record);
+    record.extend_from_slice(&checksum.to_le_bytes());
+    if record.len() as u64 > JOURNAL_MAX_RECORD_SIZE {
+        return Err(GrufError::serialization_error("Journal record is too big"));
+    }
+    Ok(record)
+}
+
+/// Returns the header offset and the header contained in a record, if it's
+/// complete and intact
+fn parse_record(record: &[u8]) -> Option<(u64, &[u8])> {
+    if record.len() < JOURNAL_RECORD_PREFIX_SIZE + JOURNAL_CHECKSUM_SIZE
+        || !record.starts_with(JOURNAL_MAGIC)
+    {
+        return None;
+    }
+    let (content, checksum) = record.split_at(record.len() - JOURNAL_CHECKSUM_SIZE);
+    if xxh3::hash64(content).to_le_bytes() != checksum {
+        return None;
+    }
+    let header_offset = u64::from_le_bytes(content[8..16].try_into().ok()?);
+    let header_size = u32::from_le_bytes(content[16..20].try_into().ok()?) as usize;
+    let header = &content[JOURNAL_RECORD_PREFIX_SIZE..];
+    if header.len() != header_size {
+        return None;
+    }
+    Some((header_offset, header))
+}
+
+fn write_header(archive: &mut File, header_offset: u64, header: &[u8]) -> Result<()> {
+    archive.seek(SeekFrom::Start(header_offset))?;
+    archive.write_all(header)?;
+    archive.sync_data()?;
+    Ok(())
+}
+
+fn remove_journal(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => sync_parent_directory(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Makes the creation or removal of a file durable
+fn sync_parent_directory(path: &Path) -> Result<()> {
+    // Directories can only be opened (and synced) this way on
+    // Unix, other platforms persist directory entries with the file itself
+    if cfg!(unix) {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grf::fsck::check_file;
+    use crate::grf::{compact_file, GrfArchive, GrfArchiveBuilder, GRF_HEADER_SIZE};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_recover_file() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("archive.grf");
+        fs::write(&grf_path, vec![0u8; 64]).unwrap();
+        // No journal
+        assert!(!recover_file(&grf_path).unwrap());
+        // Complete journal
+        let header = [0x42u8; 46];
+        fs::write(
+            journal_path(&grf_path),
+            serialize_record(4, &header).unwrap(),
+        )
+        .unwrap();
+        assert!(recover_file(&grf_path).unwrap());
+        assert!(!journal_path(&grf_path).exists());
+        let content = fs::read(&grf_path).unwrap();
+        assert_eq!(&content[..4], &[0; 4]);
+        assert_eq!(&content[4..50], &header[..]);
+        assert_eq!(&content[50..], &[0; 14]);
+        // Incomplete journals are discarded
+        let record = serialize_record(0, &[0x43u8; 46]).unwrap();
+        for size in &[0, 8, record.len() - 1] {
+            fs::write(journal_path(&grf_path), &record[..*size]).unwrap();
+            assert!(!recover_file(&grf_path).unwrap());
+            assert!(!journal_path(&grf_path).exists());
+            assert_eq!(fs::read(&grf_path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_recover_interrupted_commit() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-small.grf");
+        fs::copy(grf_dir_path.join("200-small.grf"), &grf_path).unwrap();
+        let added_file = "data\\added.txt";
+        let previous_header = fs::read(&grf_path).unwrap()[..GRF_HEADER_SIZE].to_vec();
+        {
+            let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+            builder
+                .add_file(added_file.to_string(), &[0x42u8; 1000][..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        let new_header = fs::read(&grf_path).unwrap()[..GRF_HEADER_SIZE].to_vec();
+        let record = serialize_record(0, &new_header).unwrap();
+        let interrupt_commit = |record: &[u8]| {
+            let mut grf_file = OpenOptions::new().write(true).open(&grf_path).unwrap();
+            write_header(&mut grf_file, 0, &previous_header).unwrap();
+            fs::write(journal_path(&grf_path), record).unwrap();
+        };
+        // Interrupted while writing the journal
+        interrupt_commit(&record[..record.len() / 2]);
+        assert!(!has_interrupted_commit(&grf_path).unwrap());
+        drop(GrfArchiveBuilder::open_journaled(&grf_path).unwrap());
+        assert!(!journal_path(&grf_path).exists());
+        assert!(!GrfArchive::open(&grf_path)
+            .unwrap()
+            .contains_file(added_file));
+        // Interrupted while writing the header, the archive cannot be read
+        // until it's been recovered
+        interrupt_commit(&record);
+        assert!(has_interrupted_commit(&grf_path).unwrap());
+        assert!(matches!(
+            GrfArchive::open(&grf_path),
+            Err(GrufError::InterruptedCommit)
+        ));
+        assert!(matches!(
+            GrfArchive::open_mmap(&grf_path),
+            Err(GrufError::InterruptedCommit)
+        ));
+        drop(GrfArchiveBuilder::open_journaled(&grf_path).unwrap());
+        assert!(!journal_path(&grf_path).exists());
+        let mut grf = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf.read_file_content(added_file).unwrap(), [0x42u8; 1000]);
+    }
+
+    #[test]
+    fn test_compact_interrupted_commit() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-small.grf");
+        fs::copy(grf_dir_path.join("200-small.grf"), &grf_path).unwrap();
+        let removed_file = "data\\06guild_r.gnd";
+        let added_file = "data\\added.txt";
+        let previous_header = fs::read(&grf_path).unwrap()[..GRF_HEADER_SIZE].to_vec();
+        {
+            let mut builder = GrfArchiveBuilder::open_journaled(&grf_path).unwrap();
+            assert!(builder.remove_file(removed_file).unwrap());
+            builder
+                .add_file(added_file.to_string(), &[0x42u8; 1000][..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+        // Leave a journal behind, as if the commit had been interrupted
+        let new_header = fs::read(&grf_path).unwrap()[..GRF_HEADER_SIZE].to_vec();
+        {
+            let mut grf_file = OpenOptions::new().write(true).open(&grf_path).unwrap();
+            write_header(&mut grf_file, 0, &previous_header).unwrap();
+        }
+        fs::write(
+            journal_path(&grf_path),
+            serialize_record(0, &new_header).unwrap(),
+        )
+        .unwrap();
+
+        assert!(compact_file(&grf_path).unwrap() > 0);
+        assert!(!journal_path(&grf_path).exists());
+        assert!(check_file(&grf_path).unwrap().is_ok());
+        let mut grf = GrfArchive::open(&grf_path).unwrap();
+        assert!(!grf.contains_file(removed_file));
+        assert_eq!(grf.read_file_content(added_file).unwrap(), [0x42u8; 1000]);
+    }
+}
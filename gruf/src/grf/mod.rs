@@ -1,10 +1,37 @@
 pub mod builder;
+pub mod crypto;
+pub mod fsck;
+pub mod journal;
 pub mod reader;
+pub mod salvage;
 
-pub use builder::GrfArchiveBuilder;
-pub use reader::{GrfArchive, GrfFileEntry};
+pub use builder::{compact_file, GrfArchiveBuilder};
+pub use fsck::{GrfCheckReport, GrfIssue};
+pub use journal::recover_file;
+pub use reader::{GrfArchive, GrfEntryFlags, GrfFileEncryption, GrfFileEntry};
+pub use salvage::{SalvageOptions, SalvageReport};
 
-mod crypto;
 mod dyn_alloc;
 
 use reader::{GRF_HEADER_MAGIC, GRF_HEADER_SIZE};
+
+/// Builds an in-memory archive containing the given files, used as a fixture
+/// in tests
+#[cfg(test)]
+pub(crate) fn build_test_archive<C: AsRef<[u8]>>(
+    version_major: u32,
+    version_minor: u32,
+    files: &[(&str, C)],
+) -> Vec<u8> {
+    let mut cursor = std::io::Cursor::new(Vec::new());
+    {
+        let mut builder = GrfArchiveBuilder::create(&mut cursor, version_major, version_minor)
+            .expect("failed to create archive");
+        for (path, content) in files {
+            builder
+                .add_file(path.to_string(), content.as_ref())
+                .expect("failed to add file");
+        }
+    }
+    cursor.into_inner()
+}
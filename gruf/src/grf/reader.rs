@@ -1,122 +1,102 @@
 use std::boxed::Box;
+use std::cmp;
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::ops::BitOr;
 use std::path::Path;
 use std::str;
 
-use crate::grf::crypto::{decrypt_file_content, decrypt_file_name};
+use crate::archive::{
+    display_path, normalize_path, read_exact_bounded, serialize_to_win1252, SizeCheckedReader,
+    MAX_TABLE_SIZE,
+};
+use crate::grf::crypto::{decrypt_file_name, ContentDecryptor, DES_BLOCK_SIZE};
+use crate::grf::journal::has_interrupted_commit;
+use crate::tree::{ArchiveEntry, DirectoryTree};
 use crate::{GrufError, Result};
 use encoding::label::encoding_from_whatwg_label;
 use encoding::DecoderTrap;
 use flate2::read::ZlibDecoder;
+use memmap2::Mmap;
 use nom::error::ErrorKind;
-use nom::number::complete::{le_i32, le_u32, le_u8};
+use nom::number::complete::{le_i32, le_u32, le_u64, le_u8};
 use nom::*;
 
 pub const GRF_HEADER_MAGIC: &str = "Master of Magic\0";
 // Packed structs' sizes in bytes
 pub const GRF_HEADER_SIZE: usize = GRF_HEADER_MAGIC.len() + 0x1E;
-const GRF_TABLE_INFO2_SIZE: usize = 2 * std::mem::size_of::<u32>();
+pub(crate) const GRF_TABLE_INFO2_SIZE: usize = 2 * std::mem::size_of::<u32>();
+// GRF 0x300 file tables start with 4 unknown bytes (usually zeros)
+pub(crate) const GRF_TABLE_PADDING_300: usize = std::mem::size_of::<u32>();
+// Offsets past this one cannot exist in files
+const MAX_OFFSET: u64 = i64::MAX as u64;
 
 #[derive(Debug)]
-pub struct GrfArchive {
-    obj: Box<File>,
+pub struct GrfArchive<R: ?Sized> {
+    // Offset of the archive in the underlying object
+    start_offset: u64,
+    // Size of the archive, from its start to the end of the underlying object
+    archive_size: u64,
+    obj: Box<R>,
     container: GrfContainer,
 }
 
-impl GrfArchive {
+impl GrfArchive<File> {
+    /// Opens the archive located at the given path.
+    ///
+    /// Fails with `GrufError::InterruptedCommit` if a journaled commit of the
+    /// archive has been interrupted, see `recover_file`.
+    pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<GrfArchive<File>> {
+        if has_interrupted_commit(&grf_path)? {
+            return Err(GrufError::InterruptedCommit);
+        }
+        let file = File::open(grf_path)?;
+        GrfArchive::new(file)
+    }
+}
+
+impl<R: Read + Seek> GrfArchive<R> {
     /// Create a new archive with the underlying object as the reader.
-    pub fn open<P: AsRef<Path>>(grf_path: P) -> Result<Self> {
-        let mut file = File::open(grf_path)?;
-        let mut grf_header_buf = [0; GRF_HEADER_SIZE];
-        file.read_exact(&mut grf_header_buf)?;
-        let (parser_output, grf_header) = parse_grf_header(&grf_header_buf)
-            .map_err(|_| GrufError::parsing_error("Failed to parse archive (header)"))?;
-
-        match grf_header.version_major {
-            2 => {
-                let mut table_info_buf = [0; GRF_TABLE_INFO2_SIZE];
-                file.seek(SeekFrom::Start(
-                    GRF_HEADER_SIZE as u64 + grf_header.file_table_offset,
-                ))?;
-                file.read_exact(&mut table_info_buf)?;
-                let (_parser_output, grf_table_info) = parse_grf_table_info_200(&table_info_buf)
-                    .map_err(|_| {
-                        GrufError::parsing_error("Failed to parse archive (table info)")
-                    })?;
-                if grf_table_info.table_size_compressed == 0 || grf_table_info.table_size == 0 {
-                    return Ok(Self {
-                        obj: Box::new(file),
-                        container: GrfContainer {
-                            header: grf_header,
-                            table_info: GrfTableInfo::Compressed(grf_table_info),
-                            entries: HashMap::new(),
-                        },
-                    });
-                }
-                // Decompress the table with zlib
-                let mut compressed_table: Vec<u8> =
-                    Vec::with_capacity(grf_table_info.table_size_compressed);
-                let mut file_chunk = file.by_ref().take(compressed_table.capacity() as u64);
-                file_chunk.read_to_end(&mut compressed_table)?;
-                let mut decoder = ZlibDecoder::new(compressed_table.as_slice());
-                let mut decompressed_table = vec![];
-                let _decompressed_size =
-                    decoder.read_to_end(&mut decompressed_table).map_err(|e| {
-                        GrufError::ParsingError(format!("Failed to decompress file table: {}", e))
-                    })?;
-                // Parse entries
-                let (_output, entries) = parse_grf_file_entries_200(
-                    decompressed_table.as_slice(),
-                    grf_header.file_count,
-                )
-                .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
-                Ok(Self {
-                    obj: Box::new(file),
-                    container: GrfContainer {
-                        header: grf_header,
-                        table_info: GrfTableInfo::Compressed(grf_table_info),
-                        entries,
-                    },
-                })
-            }
-            1 => {
-                // Only versions 1.1, 1.2 and 1.3 are supported
-                if grf_header.version_minor < 1 || grf_header.version_minor > 3 {
-                    return Err(GrufError::parsing_error("Unsupported archive version"));
-                }
-                let table_size = parser_output.len();
-                if table_size == 0 {
-                    return Ok(Self {
-                        obj: Box::new(file),
-                        container: GrfContainer {
-                            header: grf_header,
-                            table_info: GrfTableInfo::Uncompressed(GrfTableInfo1 { table_size }),
-                            entries: HashMap::new(),
-                        },
-                    });
-                }
-                // Parse entries
-                let (_parser_output, entries) = parse_grf_file_entries_101(
-                    &parser_output[grf_header.file_table_offset as usize..],
-                    grf_header.file_count,
-                )
-                .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
-
-                Ok(Self {
-                    obj: Box::new(file),
-                    container: GrfContainer {
-                        header: grf_header,
-                        table_info: GrfTableInfo::Uncompressed(GrfTableInfo1 { table_size }),
-                        entries,
-                    },
-                })
+    ///
+    /// The archive is expected to start at the object's current position.
+    pub fn new(mut obj: R) -> Result<GrfArchive<R>> {
+        let start_offset = obj.seek(SeekFrom::Current(0))?;
+        let archive_size = obj.seek(SeekFrom::End(0))?.saturating_sub(start_offset);
+        obj.seek(SeekFrom::Start(start_offset))?;
+        let container = parse_grf_container(&mut obj, start_offset, archive_size)?;
+        Ok(GrfArchive {
+            start_offset,
+            archive_size,
+            obj: Box::new(obj),
+            container,
+        })
+    }
+
+    /// Unwraps this archive, returning the underlying object.
+    pub fn into_inner(self) -> R {
+        *self.obj
+    }
+
+    pub(crate) fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    /// Returns the offset (relative to the archive's start) and size of the
+    /// file table
+    pub(crate) fn table_region(&self) -> (u64, usize) {
+        let table_offset = GRF_HEADER_SIZE as u64 + self.container.header.file_table_offset;
+        let table_size = match &self.container.table_info {
+            GrfTableInfo::Uncompressed(table_info) => table_info.table_size,
+            GrfTableInfo::Compressed(table_info) => {
+                table_info_padding(self.version_major())
+                    + GRF_TABLE_INFO2_SIZE
+                    + table_info.table_size_compressed
             }
-            _ => Err(GrufError::parsing_error("Unsupported archive version")),
-        }
+        };
+        (table_offset, table_size)
     }
 
     pub fn file_count(&self) -> usize {
@@ -140,54 +120,371 @@ impl GrfArchive {
             return Ok(vec![]);
         }
 
-        self.obj.seek(SeekFrom::Start(file_entry.offset))?;
-        let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed_aligned);
-        let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
-        file_chunk.read_to_end(&mut content)?;
-        Ok(content)
+        let raw_size = self.check_entry_bounds(&file_entry)?;
+        self.obj
+            .seek(SeekFrom::Start(self.start_offset + file_entry.offset))?;
+        read_exact_bounded(self.obj.as_mut(), raw_size)
+    }
+
+    /// Opens a file entry's raw (compressed and possibly encrypted) data for
+    /// reading, without loading it into memory.
+    pub fn open_raw_entry<S: AsRef<str> + Hash>(
+        &mut self,
+        file_path: S,
+    ) -> Result<io::Take<&mut R>> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?;
+        let raw_size = if file_entry.size == 0 {
+            0
+        } else {
+            self.check_entry_bounds(file_entry)?
+        };
+        let raw_offset = self.start_offset + file_entry.offset;
+        self.obj.seek(SeekFrom::Start(raw_offset))?;
+        Ok(self.obj.as_mut().take(raw_size))
     }
 
     pub fn read_file_content<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open_entry(file_path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Opens a file entry for reading.
+    ///
+    /// The content is decrypted and decompressed on the fly, which avoids
+    /// loading whole entries into memory.
+    pub fn open_entry<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<impl Read + '_> {
         let file_entry = self
             .get_file_entry(file_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
         if file_entry.size == 0 {
-            return Ok(vec![]);
+            return Ok(SizeCheckedReader::empty());
         }
 
-        self.obj.seek(SeekFrom::Start(file_entry.offset))?;
-        let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed_aligned);
-        let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
-        file_chunk.read_to_end(&mut content)?;
-        match file_entry.encryption {
-            GrfFileEncryption::Unencrypted => {}
-            GrfFileEncryption::Encrypted(cycle) => {
-                decrypt_file_content(&mut content, cycle);
-            }
-        }
-        // Decompress the content with zlib
-        let mut decoder = ZlibDecoder::new(content.as_slice());
-        let mut decompressed_content = Vec::new();
-        let decompressed_size = decoder.read_to_end(&mut decompressed_content)?;
-        if decompressed_size != file_entry.size {
-            return Err(GrufError::parsing_error(
-                "Decompressed content is not as expected",
-            ));
+        let raw_size = self.check_entry_bounds(&file_entry)?;
+        self.obj
+            .seek(SeekFrom::Start(self.start_offset + file_entry.offset))?;
+        let file_chunk = self.obj.by_ref().take(raw_size);
+        Ok(entry_content_reader(file_chunk, &file_entry))
+    }
+
+    /// Makes sure an entry's raw data is located inside the archive, returns
+    /// the size of the data
+    fn check_entry_bounds(&self, file_entry: &GrfFileEntry) -> Result<u64> {
+        let raw_size = file_entry.size_compressed_aligned as u64;
+        match file_entry.offset.checked_add(raw_size) {
+            Some(end_offset) if end_offset <= self.archive_size => Ok(raw_size),
+            _ => Err(GrufError::parsing_error("Entry data is out of bounds")),
         }
-        Ok(decompressed_content)
     }
 
     pub fn contains_file<S: AsRef<str> + Hash>(&self, file_path: S) -> bool {
-        self.container.entries.contains_key(file_path.as_ref())
+        self.get_file_entry(file_path).is_some()
     }
 
     pub fn get_file_entry<S: AsRef<str> + Hash>(&self, file_path: S) -> Option<&GrfFileEntry> {
-        self.container.entries.get(file_path.as_ref())
+        self.container
+            .table
+            .entries
+            .get(&normalize_path(file_path.as_ref()))
+            .filter(|e| e.entry_type.is_file())
     }
 
+    /// Returns file entries, directory entries are excluded
     pub fn get_entries(&self) -> impl Iterator<Item = &'_ GrfFileEntry> {
-        self.container.entries.values()
+        self.container
+            .table
+            .entries
+            .values()
+            .filter(|e| e.entry_type.is_file())
+    }
+
+    /// Returns the offsets and sizes of the data of entries shadowed by
+    /// another entry with the same normalized path
+    pub(crate) fn shadowed_chunks(&self) -> &[(u64, usize)] {
+        &self.container.table.shadowed_chunks
+    }
+
+    /// Returns directory entries (i.e. entries without content)
+    pub fn get_directory_entries(&self) -> impl Iterator<Item = &'_ GrfFileEntry> {
+        self.container
+            .table
+            .entries
+            .values()
+            .filter(|e| !e.entry_type.is_file())
+    }
+
+    /// Returns a hierarchical index of the archive's entries, which includes
+    /// empty directories
+    pub fn directory_tree(&self) -> DirectoryTree<'_, GrfFileEntry> {
+        let mut tree = DirectoryTree::new(self.get_entries());
+        for directory_entry in self.get_directory_entries() {
+            tree.insert_directory(&directory_entry.relative_path);
+        }
+        tree
+    }
+}
+
+impl GrfArchive<Cursor<Mmap>> {
+    /// Maps an archive into memory.
+    ///
+    /// Entries' raw data can then be borrowed without copies and read from
+    /// several threads at once. Fails like `GrfArchive::open` if a commit of
+    /// the archive has been interrupted.
+    pub fn open_mmap<P: AsRef<Path>>(grf_path: P) -> Result<GrfArchive<Cursor<Mmap>>> {
+        if has_interrupted_commit(&grf_path)? {
+            return Err(GrufError::InterruptedCommit);
+        }
+        let file = File::open(grf_path)?;
+        // The file must not be modified while it's mapped
+        let mmap = unsafe { Mmap::map(&file)? };
+        GrfArchive::new(Cursor::new(mmap))
+    }
+}
+
+impl<T: AsRef<[u8]>> GrfArchive<Cursor<T>> {
+    /// Returns an entry's raw data as a slice of the underlying buffer.
+    pub fn get_entry_raw_slice<S: AsRef<str> + Hash>(&self, file_path: S) -> Result<&[u8]> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?;
+        if file_entry.size == 0 {
+            return Ok(&[]);
+        }
+
+        let data_start = usize::try_from(self.start_offset + file_entry.offset)?;
+        data_start
+            .checked_add(file_entry.size_compressed_aligned)
+            .and_then(|data_end| self.obj.get_ref().as_ref().get(data_start..data_end))
+            .ok_or_else(|| GrufError::parsing_error("Entry data is out of bounds"))
+    }
+
+    /// Opens a file entry for reading without requiring exclusive access to
+    /// the archive.
+    pub fn open_entry_shared<S: AsRef<str> + Hash>(&self, file_path: S) -> Result<impl Read + '_> {
+        let file_entry = self
+            .get_file_entry(file_path.as_ref())
+            .ok_or(GrufError::EntryNotFound)?;
+        if file_entry.size == 0 {
+            return Ok(SizeCheckedReader::empty());
+        }
+
+        let raw_data = self.get_entry_raw_slice(file_path)?;
+        Ok(entry_content_reader(raw_data, file_entry))
+    }
+
+    pub fn read_file_content_shared<S: AsRef<str> + Hash>(&self, file_path: S) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open_entry_shared(file_path)?
+            .read_to_end(&mut content)?;
+        Ok(content)
+    }
+}
+
+/// Decrypts and decompresses an entry's raw data on the fly
+fn entry_content_reader<R: Read>(
+    raw_data: R,
+    file_entry: &GrfFileEntry,
+) -> SizeCheckedReader<ZlibDecoder<DecryptingReader<R>>> {
+    SizeCheckedReader::new(entry_decoder(raw_data, file_entry), file_entry.size as u64)
+}
+
+/// Same as `entry_content_reader` but doesn't check the size of the content
+pub(crate) fn entry_decoder<R: Read>(
+    raw_data: R,
+    file_entry: &GrfFileEntry,
+) -> ZlibDecoder<DecryptingReader<R>> {
+    let decryptor = match file_entry.encryption {
+        GrfFileEncryption::Unencrypted => None,
+        GrfFileEncryption::Encrypted(cycle) => Some(ContentDecryptor::new(cycle)),
+    };
+    // Decompress the content with zlib
+    ZlibDecoder::new(DecryptingReader::new(raw_data, decryptor))
+}
+
+fn parse_grf_container<R: Read + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    archive_size: u64,
+) -> Result<GrfContainer> {
+    let mut grf_header_buf = [0; GRF_HEADER_SIZE];
+    obj.read_exact(&mut grf_header_buf)?;
+    let (_parser_output, grf_header) = parse_grf_header(&grf_header_buf)
+        .map_err(|_| GrufError::parsing_error("Failed to parse archive (header)"))?;
+    // Header parsing guarantees that offsets cannot overflow here
+    let table_offset = GRF_HEADER_SIZE as u64 + grf_header.file_table_offset;
+    if table_offset > archive_size {
+        return Err(GrufError::parsing_error("File table is out of bounds"));
+    }
+
+    match grf_header.version_major {
+        2 | 3 => {
+            let table_info_offset =
+                table_offset + table_info_padding(grf_header.version_major) as u64;
+            if table_info_offset + GRF_TABLE_INFO2_SIZE as u64 > archive_size {
+                return Err(GrufError::parsing_error("File table is out of bounds"));
+            }
+            let mut table_info_buf = [0; GRF_TABLE_INFO2_SIZE];
+            obj.seek(SeekFrom::Start(start_offset + table_info_offset))?;
+            obj.read_exact(&mut table_info_buf)?;
+            let (_parser_output, grf_table_info) = parse_grf_table_info_200(&table_info_buf)
+                .map_err(|_| GrufError::parsing_error("Failed to parse archive (table info)"))?;
+            if grf_table_info.table_size_compressed == 0 || grf_table_info.table_size == 0 {
+                return Ok(GrfContainer {
+                    header: grf_header,
+                    table_info: GrfTableInfo::Compressed(grf_table_info),
+                    table: GrfFileTable::default(),
+                });
+            }
+            let table_end_offset = table_info_offset
+                + GRF_TABLE_INFO2_SIZE as u64
+                + grf_table_info.table_size_compressed as u64;
+            if table_end_offset > archive_size {
+                return Err(GrufError::parsing_error("File table is out of bounds"));
+            }
+            if grf_table_info.table_size > MAX_TABLE_SIZE {
+                return Err(GrufError::parsing_error("File table is too big"));
+            }
+            // Decompress the table with zlib
+            let compressed_table =
+                read_exact_bounded(obj.by_ref(), grf_table_info.table_size_compressed as u64)?;
+            // Don't decompress more than the declared size
+            let mut decoder = ZlibDecoder::new(compressed_table.as_slice())
+                .take(grf_table_info.table_size as u64);
+            let mut decompressed_table = vec![];
+            let _decompressed_size = decoder.read_to_end(&mut decompressed_table).map_err(|e| {
+                GrufError::ParsingError(format!("Failed to decompress file table: {}", e))
+            })?;
+            // Parse entries
+            let parse_result = if grf_header.version_major == 3 {
+                parse_grf_file_entries_300(decompressed_table.as_slice(), grf_header.file_count)
+            } else {
+                parse_grf_file_entries_200(decompressed_table.as_slice(), grf_header.file_count)
+            };
+            let (_output, table) =
+                parse_result.map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
+            Ok(GrfContainer {
+                header: grf_header,
+                table_info: GrfTableInfo::Compressed(grf_table_info),
+                table,
+            })
+        }
+        1 => {
+            // Only versions 1.1, 1.2 and 1.3 are supported
+            if grf_header.version_minor < 1 || grf_header.version_minor > 3 {
+                return Err(GrufError::parsing_error("Unsupported archive version"));
+            }
+            // The table isn't compressed and spans until the end of the file
+            let table_size = usize::try_from(archive_size - table_offset)?;
+            if table_size > MAX_TABLE_SIZE {
+                return Err(GrufError::parsing_error("File table is too big"));
+            }
+            obj.seek(SeekFrom::Start(start_offset + table_offset))?;
+            let table = read_exact_bounded(obj.by_ref(), table_size as u64)?;
+            if table_size == 0 || grf_header.file_count == 0 {
+                return Ok(GrfContainer {
+                    header: grf_header,
+                    table_info: GrfTableInfo::Uncompressed(GrfTableInfo1 { table_size }),
+                    table: GrfFileTable::default(),
+                });
+            }
+            // Parse entries
+            let (_parser_output, table) =
+                parse_grf_file_entries_101(table.as_slice(), grf_header.file_count)
+                    .map_err(|_| GrufError::parsing_error("Failed to parse file table"))?;
+
+            Ok(GrfContainer {
+                header: grf_header,
+                table_info: GrfTableInfo::Uncompressed(GrfTableInfo1 { table_size }),
+                table,
+            })
+        }
+        _ => Err(GrufError::parsing_error("Unsupported archive version")),
+    }
+}
+
+/// Returns the size of the data located before the table info in GRF 2.x and
+/// 3.x file tables
+pub(crate) fn table_info_padding(version_major: u32) -> usize {
+    match version_major {
+        3 => GRF_TABLE_PADDING_300,
+        _ => 0,
+    }
+}
+
+/// Reader that decrypts the content of an entry block by block
+pub(crate) struct DecryptingReader<R: Read> {
+    inner: R,
+    decryptor: Option<ContentDecryptor>,
+    buffer: Box<[u8]>,
+    // Bytes in `pos..decrypted_end` are ready to be consumed
+    pos: usize,
+    decrypted_end: usize,
+    // Bytes in `decrypted_end..end` form an incomplete block
+    end: usize,
+    reached_eof: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    fn new(inner: R, decryptor: Option<ContentDecryptor>) -> Self {
+        // Use an 8KiB buffer
+        const BUFFER_SIZE: usize = 8 * 1024;
+        Self {
+            inner,
+            decryptor,
+            buffer: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            decrypted_end: 0,
+            end: 0,
+            reached_eof: false,
+        }
+    }
+
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        // Move the incomplete block to the beginning of the buffer
+        self.buffer.copy_within(self.decrypted_end..self.end, 0);
+        self.end -= self.decrypted_end;
+        self.pos = 0;
+        self.decrypted_end = 0;
+        // Read until at least one block is available
+        while !self.reached_eof && self.end < DES_BLOCK_SIZE {
+            match self.inner.read(&mut self.buffer[self.end..]) {
+                Ok(0) => self.reached_eof = true,
+                Ok(len) => self.end += len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let blocks_end = self.end - self.end % DES_BLOCK_SIZE;
+        if let Some(decryptor) = self.decryptor.as_mut() {
+            decryptor.decrypt_blocks(&mut self.buffer[..blocks_end]);
+        }
+        // The trailing incomplete block (if any) isn't encrypted
+        self.decrypted_end = if self.reached_eof {
+            self.end
+        } else {
+            blocks_end
+        };
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.decryptor.is_none() {
+            // Unencrypted content, read directly from the underlying reader
+            return self.inner.read(buf);
+        }
+        if self.pos == self.decrypted_end {
+            self.fill_buffer()?;
+        }
+        let len = cmp::min(buf.len(), self.decrypted_end - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
     }
 }
 
@@ -195,7 +492,15 @@ impl GrfArchive {
 struct GrfContainer {
     pub header: GrfHeader,
     pub table_info: GrfTableInfo,
+    pub table: GrfFileTable,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct GrfFileTable {
+    // Entries are indexed by their normalized path
     pub entries: HashMap<String, GrfFileEntry>,
+    // Offsets and sizes of the data of entries shadowed by another entry
+    pub shadowed_chunks: Vec<(u64, usize)>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -220,7 +525,7 @@ struct GrfTableInfo1 {
 }
 
 #[derive(Debug, PartialEq, Eq)]
-struct GrfTableInfo2 {
+pub(crate) struct GrfTableInfo2 {
     pub table_size_compressed: usize,
     pub table_size: usize,
 }
@@ -231,11 +536,37 @@ pub struct GrfFileEntry {
     pub size_compressed: usize,
     pub size_compressed_aligned: usize,
     pub size: usize,
-    pub entry_type: u8,
+    pub entry_type: GrfEntryFlags,
     pub offset: u64,
     pub encryption: GrfFileEncryption,
 }
 
+impl GrfFileEntry {
+    /// Returns the entry's path as stored in the archive
+    pub fn raw_path(&self) -> Result<Vec<u8>> {
+        serialize_to_win1252(&self.relative_path)
+    }
+
+    /// Returns the entry's path decoded as CP949
+    pub fn display_path(&self) -> String {
+        display_path(&self.relative_path)
+    }
+}
+
+impl ArchiveEntry for GrfFileEntry {
+    fn relative_path(&self) -> &str {
+        &self.relative_path
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn size_compressed(&self) -> u64 {
+        self.size_compressed as u64
+    }
+}
+
 impl Hash for GrfFileEntry {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.relative_path.hash(state);
@@ -248,32 +579,117 @@ impl PartialEq for GrfFileEntry {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum GrfFileEncryption {
     Unencrypted,
     Encrypted(usize), // Contains the cycle as usize
 }
 
-named!(parse_grf_header<&[u8], GrfHeader>,
-    do_parse!(
-        tag!(GRF_HEADER_MAGIC)
-            >> key: take!(14)
-            >> file_table_offset: le_u32
-            >> seed: le_i32
-            >> v_files_count: le_i32
-            >> version: le_u32
-            >> (GrfHeader {
-                key: key.try_into().unwrap(),
-                file_table_offset: file_table_offset as u64,
-                seed,
-                file_count: (v_files_count - seed - 7) as usize,
-                version_major: (version >> 8) & 0xFF,
-                version_minor: version & 0xFF
-            }
+/// Flags of GRF file entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GrfEntryFlags {
+    bits: u8,
+}
+
+impl GrfEntryFlags {
+    /// Entry is a file, entries without this flag are directories
+    pub const FILE: Self = Self { bits: 0x01 };
+    /// Content is encrypted with DES and shuffled depending on its size
+    pub const MIXCRYPT: Self = Self { bits: 0x02 };
+    /// Only the first 0x14 blocks of the content are encrypted with DES
+    pub const DES_0X14: Self = Self { bits: 0x04 };
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self { bits }
+    }
+
+    /// Returns the flags that describe a file with the given encryption
+    pub fn from_encryption(encryption: &GrfFileEncryption) -> Self {
+        match encryption {
+            GrfFileEncryption::Unencrypted => Self::FILE,
+            GrfFileEncryption::Encrypted(0) => Self::FILE | Self::DES_0X14,
+            GrfFileEncryption::Encrypted(_) => Self::FILE | Self::MIXCRYPT,
+        }
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.bits
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        (self.bits & other.bits) == other.bits
+    }
+
+    pub const fn is_file(self) -> bool {
+        self.contains(Self::FILE)
+    }
+}
+
+impl BitOr for GrfEntryFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self::from_bits(self.bits | rhs.bits)
+    }
+}
+
+named!(pub(crate) parse_grf_header<&[u8], GrfHeader>,
+    map_opt!(
+        do_parse!(
+            tag!(GRF_HEADER_MAGIC)
+                >> key: take!(14)
+                >> file_table_offset: le_u32
+                >> seed: le_i32
+                >> v_files_count: le_i32
+                >> version: le_u32
+                >> ((key, file_table_offset, seed, v_files_count, version))
+        ),
+        |(key, file_table_offset, seed, v_files_count, version)| {
+            grf_header_from_fields(key, file_table_offset, seed, v_files_count, version)
+        }
     )
-));
+);
+
+/// Builds a header from its raw fields, returns None if they're inconsistent
+fn grf_header_from_fields(
+    key: &[u8],
+    file_table_offset: u32,
+    seed: i32,
+    v_files_count: i32,
+    version: u32,
+) -> Option<GrfHeader> {
+    let version_major = (version >> 8) & 0xFF;
+    let version_minor = version & 0xFF;
+    let key = key.try_into().ok()?;
+    if version_major == 3 {
+        // GRF 0x300 has no seed, the file table's offset is
+        // stored on 64 bits instead
+        let file_table_offset = ((seed as u32 as u64) << 32) | file_table_offset as u64;
+        if file_table_offset > MAX_OFFSET - GRF_HEADER_SIZE as u64 {
+            return None;
+        }
+        Some(GrfHeader {
+            key,
+            file_table_offset,
+            seed: 0,
+            file_count: usize::try_from(v_files_count.checked_sub(7)?).ok()?,
+            version_major,
+            version_minor,
+        })
+    } else {
+        let file_count = v_files_count.checked_sub(seed)?.checked_sub(7)?;
+        Some(GrfHeader {
+            key,
+            file_table_offset: file_table_offset as u64,
+            seed,
+            file_count: usize::try_from(file_count).ok()?,
+            version_major,
+            version_minor,
+        })
+    }
+}
 
-named!(parse_grf_table_info_200<&[u8], GrfTableInfo2>,
+named!(pub(crate) parse_grf_table_info_200<&[u8], GrfTableInfo2>,
     do_parse!(
         table_size_compressed: le_u32
             >> table_size: le_u32
@@ -305,61 +721,124 @@ macro_rules! take_obfuscated_name_101 (
      );
 );
 
-fn determine_file_encryption_101(file_name: &str, size_compressed: usize) -> GrfFileEncryption {
+pub(crate) fn determine_file_encryption_101(
+    file_name: &str,
+    size_compressed: usize,
+) -> GrfFileEncryption {
     const SPECIAL_EXTENSIONS: [&str; 4] = [".gnd", ".gat", ".act", ".str"];
-    let file_name_len = file_name.len();
-    if file_name_len < 4 {
+    if file_name.len() < 4 {
         return GrfFileEncryption::Encrypted(0);
     }
-    let file_extension = &file_name[file_name_len - 4..];
-    match SPECIAL_EXTENSIONS.iter().position(|&r| r == file_extension) {
-        Some(_) => GrfFileEncryption::Encrypted(0),
-        None => GrfFileEncryption::Encrypted(digit_count(size_compressed)),
+    // Names aren't necessarily ASCII, they cannot be sliced at
+    // arbitrary positions
+    if SPECIAL_EXTENSIONS
+        .iter()
+        .any(|extension| file_name.ends_with(extension))
+    {
+        GrfFileEncryption::Encrypted(0)
+    } else {
+        GrfFileEncryption::Encrypted(digit_count(size_compressed))
+    }
+}
+
+fn determine_file_encryption_200(
+    flags: GrfEntryFlags,
+    size_compressed: usize,
+) -> GrfFileEncryption {
+    if flags.contains(GrfEntryFlags::MIXCRYPT) {
+        GrfFileEncryption::Encrypted(digit_count(size_compressed))
+    } else if flags.contains(GrfEntryFlags::DES_0X14) {
+        GrfFileEncryption::Encrypted(0)
+    } else {
+        GrfFileEncryption::Unencrypted
     }
 }
 
 /// Counts digits naively
 fn digit_count(n: usize) -> usize {
     let mut result = 1;
-    let mut acc = 10;
-    loop {
-        if n < acc {
-            break;
-        }
-        acc *= 10;
+    let mut acc: usize = 10;
+    while n >= acc {
         result += 1;
+        // Stop once the next power of ten doesn't fit, it's bigger than n
+        acc = match acc.checked_mul(10) {
+            Some(next_acc) => next_acc,
+            None => break,
+        };
     }
 
     result
 }
 
 // Parses file table entries for GRF 1.1, 1.2 and 1.3
-named!(parse_grf_file_entry_101<&[u8], GrfFileEntry>,
+named!(pub(crate) parse_grf_file_entry_101<&[u8], GrfFileEntry>,
+    map_opt!(
+        do_parse!(
+            path_size_padded: verify!(le_u32, |size: &u32| *size >= 6)
+                >> take!(2) // Null chars
+                >> relative_path: take_obfuscated_name_101!(path_size_padded - 6)
+                >> take!(4) // Null chars
+                >> size_tot_enc: le_u32
+                >> size_compressed_aligned_enc: le_u32
+                >> size: le_u32
+                >> entry_type: le_u8
+                >> offset: le_u32
+                >> ((relative_path, size_tot_enc, size_compressed_aligned_enc, size, entry_type, offset))
+        ),
+        grf_file_entry_from_fields_101
+    )
+);
+
+/// Builds a GRF 1.x entry from its raw fields, returns None if its sizes
+/// cannot be deobfuscated
+fn grf_file_entry_from_fields_101(
+    (relative_path, size_tot_enc, size_compressed_aligned_enc, size, entry_type, offset): (
+        String,
+        u32,
+        u32,
+        u32,
+        u8,
+        u32,
+    ),
+) -> Option<GrfFileEntry> {
+    let size_compressed = size_tot_enc.checked_sub(size)?.checked_sub(0x02CB)? as usize;
+    let size_compressed_aligned = size_compressed_aligned_enc.checked_sub(0x92CB)? as usize;
+    Some(GrfFileEntry {
+        size_compressed,
+        size_compressed_aligned,
+        size: size as usize,
+        entry_type: GrfEntryFlags::from_bits(entry_type),
+        offset: GRF_HEADER_SIZE as u64 + offset as u64,
+        encryption: determine_file_encryption_101(&relative_path, size_compressed),
+        relative_path,
+    })
+}
+
+// Parses file table entries for GRF 2.0
+named!(pub(crate) parse_grf_file_entry_200<&[u8], GrfFileEntry>,
     do_parse!(
-        path_size_padded: le_u32
-            >> take!(2) // Null chars
-            >> relative_path: take_obfuscated_name_101!(path_size_padded - 6)
-            >> take!(4) // Null chars
-            >> size_tot_enc: le_u32
-            >> size_compressed_aligned_enc: le_u32
+        relative_path: map_res!(take_while!(|ch: u8| ch != 0), string_from_win_1252)
+            >> take!(1) // Null char terminator
+            >> size_compressed: le_u32
+            >> size_compressed_aligned: le_u32
             >> size: le_u32
             >> entry_type: le_u8
             >> offset: le_u32
             >> (GrfFileEntry {
-                size_compressed: (size_tot_enc - size - 0x02CB) as usize,
-                size_compressed_aligned: (size_compressed_aligned_enc - 0x92CB) as usize,
+                relative_path,
+                size_compressed: size_compressed as usize,
+                size_compressed_aligned: size_compressed_aligned as usize,
                 size: size as usize,
-                entry_type,
+                entry_type: GrfEntryFlags::from_bits(entry_type),
                 offset: GRF_HEADER_SIZE as u64 + offset as u64,
-                encryption: determine_file_encryption_101(&relative_path, (size_tot_enc - size - 0x02CB) as usize),
-                relative_path,
+                encryption: determine_file_encryption_200(GrfEntryFlags::from_bits(entry_type), size_compressed as usize),
             }
         )
     )
 );
 
-// Parses file table entries for GRF 2.0
-named!(parse_grf_file_entry_200<&[u8], GrfFileEntry>,
+// Parses file table entries for GRF 3.0, offsets are stored on 64 bits
+named!(pub(crate) parse_grf_file_entry_300<&[u8], GrfFileEntry>,
     do_parse!(
         relative_path: map_res!(take_while!(|ch: u8| ch != 0), string_from_win_1252)
             >> take!(1) // Null char terminator
@@ -367,39 +846,61 @@ named!(parse_grf_file_entry_200<&[u8], GrfFileEntry>,
             >> size_compressed_aligned: le_u32
             >> size: le_u32
             >> entry_type: le_u8
-            >> offset: le_u32
+            >> offset: verify!(le_u64, |offset: &u64| *offset <= MAX_OFFSET - GRF_HEADER_SIZE as u64)
             >> (GrfFileEntry {
                 relative_path,
                 size_compressed: size_compressed as usize,
                 size_compressed_aligned: size_compressed_aligned as usize,
                 size: size as usize,
-                entry_type,
-                offset: GRF_HEADER_SIZE as u64 + offset as u64,
-                encryption: GrfFileEncryption::Unencrypted,
+                entry_type: GrfEntryFlags::from_bits(entry_type),
+                offset: GRF_HEADER_SIZE as u64 + offset,
+                encryption: determine_file_encryption_200(GrfEntryFlags::from_bits(entry_type), size_compressed as usize),
             }
         )
     )
 );
 
-named_args!(parse_grf_file_entries_101(files_count: usize)<&[u8], HashMap<String, GrfFileEntry>>,
-fold_many_m_n!(1, files_count - 1, parse_grf_file_entry_101, HashMap::new(), |mut acc: HashMap<_, _>, item| {
-        acc.insert(item.relative_path.clone(), item);
-        acc
-    })
+/// Indexes an entry by its normalized path. Entries whose paths only differ
+/// by case or separators shadow each other, the last one wins.
+fn insert_file_entry(mut table: GrfFileTable, item: GrfFileEntry) -> GrfFileTable {
+    if let Some(shadowed_entry) = table
+        .entries
+        .insert(normalize_path(&item.relative_path), item)
+    {
+        if shadowed_entry.entry_type.is_file() {
+            table.shadowed_chunks.push((
+                shadowed_entry.offset,
+                shadowed_entry.size_compressed_aligned,
+            ));
+        }
+    }
+    table
+}
+
+named_args!(parse_grf_file_entries_101(files_count: usize)<&[u8], GrfFileTable>,
+fold_many_m_n!(1, files_count, parse_grf_file_entry_101, GrfFileTable::default(), insert_file_entry)
 );
 
-named_args!(parse_grf_file_entries_200(files_count: usize)<&[u8], HashMap<String, GrfFileEntry>>,
-fold_many_m_n!(1, files_count, parse_grf_file_entry_200, HashMap::new(), |mut acc: HashMap<_, _>, item| {
-        acc.insert(item.relative_path.clone(), item);
-        acc
-    })
+named_args!(parse_grf_file_entries_200(files_count: usize)<&[u8], GrfFileTable>,
+fold_many_m_n!(1, files_count, parse_grf_file_entry_200, GrfFileTable::default(), insert_file_entry)
+);
+
+named_args!(parse_grf_file_entries_300(files_count: usize)<&[u8], GrfFileTable>,
+fold_many_m_n!(1, files_count, parse_grf_file_entry_300, GrfFileTable::default(), insert_file_entry)
 );
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::grf::{build_test_archive, GrfArchiveBuilder};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
     use hex_literal::hex;
+    use std::fs::OpenOptions;
+    use std::io::Write;
     use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
     use twox_hash::XxHash64;
 
     #[test]
@@ -442,8 +943,9 @@ mod tests {
         .iter()
         .cloned()
         .collect();
-        let check_small_grf_entries = |grf: &mut GrfArchive| {
+        let check_small_grf_entries = |grf: &mut GrfArchive<File>| {
             let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+            assert_eq!(file_entries.len(), expected_content.len());
             for file_entry in file_entries {
                 let file_path: &str = &file_entry.relative_path[..];
                 assert!(expected_content.contains_key(file_path));
@@ -510,6 +1012,229 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_open_in_memory() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let grf_path = grf_dir_path.join("200-small.grf");
+        let mut grf = GrfArchive::open(&grf_path).unwrap();
+        let mut grf_in_memory =
+            GrfArchive::new(Cursor::new(std::fs::read(&grf_path).unwrap())).unwrap();
+        assert_eq!(grf_in_memory.file_count(), grf.file_count());
+        let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+        for file_entry in file_entries {
+            assert_eq!(
+                grf_in_memory
+                    .read_file_content(&file_entry.relative_path)
+                    .unwrap(),
+                grf.read_file_content(&file_entry.relative_path).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let mut grf = GrfArchive::open(grf_dir_path.join("200-small.grf")).unwrap();
+        let expected_content = grf
+            .read_file_content("data\\texture\\chdesk-side1.bmp")
+            .unwrap();
+        for file_path in &[
+            "DATA\\TEXTURE\\CHDESK-SIDE1.BMP",
+            "data/texture/chdesk-side1.bmp",
+            "Data/Texture\\ChDesk-Side1.bmp",
+        ] {
+            assert!(grf.contains_file(file_path));
+            let entry = grf.get_file_entry(file_path).unwrap();
+            // The original path is kept
+            assert_eq!(entry.relative_path, "data\\texture\\chdesk-side1.bmp");
+            assert_eq!(grf.read_file_content(file_path).unwrap(), expected_content);
+        }
+        assert!(!grf.contains_file("data\\texture\\chdesk-side4.bmp"));
+    }
+
+    #[test]
+    fn test_open_mmap() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for grf_name in &["103-small.grf", "200-small.grf"] {
+            let grf_path = grf_dir_path.join(grf_name);
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            let mapped_grf = Arc::new(GrfArchive::open_mmap(&grf_path).unwrap());
+            assert_eq!(mapped_grf.file_count(), grf.file_count());
+            let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+            let mut expected_contents = HashMap::new();
+            for file_entry in &file_entries {
+                let file_path = &file_entry.relative_path;
+                assert_eq!(
+                    mapped_grf.get_entry_raw_slice(file_path).unwrap(),
+                    grf.get_entry_raw_data(file_path).unwrap().as_slice()
+                );
+                expected_contents
+                    .insert(file_path.clone(), grf.read_file_content(file_path).unwrap());
+            }
+            // Read entries from several threads at once
+            let expected_contents = Arc::new(expected_contents);
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let mapped_grf = Arc::clone(&mapped_grf);
+                    let expected_contents = Arc::clone(&expected_contents);
+                    thread::spawn(move || {
+                        for (file_path, expected_content) in expected_contents.iter() {
+                            assert_eq!(
+                                &mapped_grf.read_file_content_shared(file_path).unwrap(),
+                                expected_content
+                            );
+                        }
+                    })
+                })
+                .collect();
+            for handle in threads {
+                handle.join().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_entry() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        for grf_name in &["102-small.grf", "103-small.grf", "200-small.grf"] {
+            let mut grf = GrfArchive::open(grf_dir_path.join(grf_name)).unwrap();
+            let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+            for file_entry in file_entries {
+                let expected_content = grf.read_file_content(&file_entry.relative_path).unwrap();
+                // Read the entry in small chunks that do not match DES blocks
+                let mut entry_reader = grf.open_entry(&file_entry.relative_path).unwrap();
+                let mut content = Vec::new();
+                let mut buf = [0; 13];
+                loop {
+                    let read_size = entry_reader.read(&mut buf).unwrap();
+                    if read_size == 0 {
+                        break;
+                    }
+                    content.extend_from_slice(&buf[..read_size]);
+                }
+                assert_eq!(content.len(), file_entry.size);
+                assert_eq!(content, expected_content);
+            }
+            assert!(matches!(
+                grf.open_entry("data\\missing.txt").err().unwrap(),
+                GrufError::EntryNotFound
+            ));
+        }
+    }
+
+    #[test]
+    fn test_directory_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let grf_path = temp_dir.path().join("200-directories.grf");
+        let file_content = b"content".to_vec();
+        // Build an archive that contains a directory entry manually
+        {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&file_content).unwrap();
+            let compressed_content = encoder.finish().unwrap();
+            let mut table = Vec::new();
+            table.extend_from_slice(b"data\\dir\0");
+            table.extend_from_slice(&[0; 12]); // Sizes
+            table.push(0x00); // Flags
+            table.extend_from_slice(&0u32.to_le_bytes()); // Offset
+            table.extend_from_slice(b"data\\dir\\file.txt\0");
+            table.extend_from_slice(&(compressed_content.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(compressed_content.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(file_content.len() as u32).to_le_bytes());
+            table.push(0x01); // Flags
+            table.extend_from_slice(&0u32.to_le_bytes()); // Offset
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&table).unwrap();
+            let compressed_table = encoder.finish().unwrap();
+
+            let mut grf = Vec::new();
+            grf.extend_from_slice(GRF_HEADER_MAGIC.as_bytes());
+            grf.extend_from_slice(&[0; 14]); // Key
+            grf.extend_from_slice(&(compressed_content.len() as u32).to_le_bytes());
+            grf.extend_from_slice(&0i32.to_le_bytes()); // Seed
+            grf.extend_from_slice(&(2i32 + 7).to_le_bytes());
+            grf.extend_from_slice(&0x200u32.to_le_bytes());
+            grf.extend_from_slice(&compressed_content);
+            grf.extend_from_slice(&(compressed_table.len() as u32).to_le_bytes());
+            grf.extend_from_slice(&(table.len() as u32).to_le_bytes());
+            grf.extend_from_slice(&compressed_table);
+            std::fs::write(&grf_path, grf).unwrap();
+        }
+
+        let mut grf = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf.file_count(), 2);
+        assert_eq!(grf.get_entries().count(), 1);
+        assert!(grf.contains_file("data\\dir\\file.txt"));
+        assert!(!grf.contains_file("data\\dir"));
+        assert!(grf.get_file_entry("data\\dir").is_none());
+        let directories: Vec<&GrfFileEntry> = grf.get_directory_entries().collect();
+        assert_eq!(directories.len(), 1);
+        assert_eq!(directories[0].relative_path, "data\\dir");
+        assert!(!directories[0].entry_type.is_file());
+        let tree = grf.directory_tree();
+        let directory = tree.get_directory("data\\dir").unwrap();
+        assert_eq!(directory.stats().file_count, 1);
+        assert_eq!(directory.stats().size, file_content.len() as u64);
+        assert!(matches!(
+            grf.read_file_content("data\\dir").unwrap_err(),
+            GrufError::EntryNotFound
+        ));
+        assert_eq!(
+            grf.read_file_content("data\\dir\\file.txt").unwrap(),
+            file_content
+        );
+
+        // Directory entries should be kept when patching in-place
+        {
+            let mut builder = GrfArchiveBuilder::open(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&grf_path)
+                    .unwrap(),
+            )
+            .unwrap();
+            builder
+                .add_file("data\\file.txt".to_string(), file_content.as_slice())
+                .unwrap();
+        }
+        let grf = GrfArchive::open(&grf_path).unwrap();
+        assert_eq!(grf.file_count(), 3);
+        assert_eq!(grf.get_entries().count(), 2);
+        assert_eq!(grf.get_directory_entries().count(), 1);
+    }
+
+    #[test]
+    fn test_entry_flags() {
+        let flags = GrfEntryFlags::FILE | GrfEntryFlags::MIXCRYPT;
+        assert_eq!(flags.bits(), 0x03);
+        assert!(flags.is_file());
+        assert!(flags.contains(GrfEntryFlags::MIXCRYPT));
+        assert!(!flags.contains(GrfEntryFlags::DES_0X14));
+        assert!(!GrfEntryFlags::from_bits(0x02).is_file());
+        assert_eq!(
+            determine_file_encryption_200(GrfEntryFlags::from_bits(0x01), 1234),
+            GrfFileEncryption::Unencrypted
+        );
+        assert_eq!(
+            determine_file_encryption_200(GrfEntryFlags::from_bits(0x03), 1234),
+            GrfFileEncryption::Encrypted(4)
+        );
+        assert_eq!(
+            determine_file_encryption_200(GrfEntryFlags::from_bits(0x05), 1234),
+            GrfFileEncryption::Encrypted(0)
+        );
+        for encryption in &[
+            GrfFileEncryption::Unencrypted,
+            GrfFileEncryption::Encrypted(0),
+            GrfFileEncryption::Encrypted(3),
+        ] {
+            let flags = GrfEntryFlags::from_encryption(encryption);
+            assert!(flags.is_file());
+            assert_eq!(&determine_file_encryption_200(flags, 100), encryption);
+        }
+    }
+
     #[test]
     fn test_digit_count() {
         assert_eq!(1, digit_count(0));
@@ -518,5 +1243,168 @@ mod tests {
         assert_eq!(2, digit_count(99));
         assert_eq!(3, digit_count(100));
         assert_eq!(8, digit_count(87654321));
+        assert_eq!(10, digit_count(1_000_000_000));
+        assert_eq!(10, digit_count(u32::MAX as usize));
+        assert_eq!(usize::MAX.to_string().len(), digit_count(usize::MAX));
+    }
+
+    const TEST_FILES: [(&str, &[u8]); 3] = [
+        ("data\\file.gat", &[0x42; 300]),
+        ("data\\file.txt", b"content"),
+        ("data\\empty.txt", b""),
+    ];
+
+    fn build_header(
+        file_table_offset: u32,
+        seed: i32,
+        v_files_count: i32,
+        version: u32,
+    ) -> Vec<u8> {
+        let mut header = GRF_HEADER_MAGIC.as_bytes().to_vec();
+        header.extend_from_slice(&[0; 14]);
+        header.extend_from_slice(&file_table_offset.to_le_bytes());
+        header.extend_from_slice(&seed.to_le_bytes());
+        header.extend_from_slice(&v_files_count.to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+        header
+    }
+
+    /// Opens an archive and reads all of its entries. Errors are expected,
+    /// panics aren't.
+    fn read_all_entries(data: &[u8]) {
+        if let Ok(mut grf) = GrfArchive::new(Cursor::new(data)) {
+            let paths: Vec<String> = grf
+                .get_entries()
+                .map(|entry| entry.relative_path.clone())
+                .collect();
+            for path in paths {
+                let _ = grf.get_entry_raw_slice(&path);
+                let _ = grf.read_file_content_shared(&path);
+                let _ = grf.get_entry_raw_data(&path);
+                let _ = grf.read_file_content(&path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_directory_tree() {
+        let grf = GrfArchive::new(Cursor::new(build_test_archive(2, 0, &TEST_FILES))).unwrap();
+        let tree = grf.directory_tree();
+        let stats = tree.root().stats();
+        assert_eq!(stats.file_count, 3);
+        assert_eq!(stats.size, 307);
+        assert_eq!(tree.get_directory("DATA").unwrap().stats(), stats);
+        let paths: Vec<&str> = tree
+            .read_dir("data")
+            .unwrap()
+            .iter()
+            .map(|e| e.relative_path())
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["data\\empty.txt", "data\\file.gat", "data\\file.txt"]
+        );
+        let paths: Vec<&str> = tree
+            .glob("data/*.txt")
+            .unwrap()
+            .map(|e| e.relative_path.as_str())
+            .collect();
+        assert_eq!(paths, vec!["data\\empty.txt", "data\\file.txt"]);
+        assert_eq!(tree.find_regex(r"\.gat$").unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_malformed_header() {
+        for (seed, v_files_count, version) in &[
+            (i32::MAX, i32::MIN, 0x200),
+            (i32::MIN, i32::MAX, 0x200),
+            (0, 6, 0x200),
+            (0, 0, 0x103),
+            (0, i32::MIN, 0x300),
+            (-1, 7, 0x300),
+        ] {
+            let header = build_header(0, *seed, *v_files_count, *version);
+            assert!(parse_grf_header(&header).is_err());
+            assert!(GrfArchive::new(Cursor::new(header)).is_err());
+        }
+        // File table out of bounds
+        let header = build_header(1000, 0, 7, 0x200);
+        assert!(GrfArchive::new(Cursor::new(header)).is_err());
+        let header = build_header(u32::MAX, 0x7FFF_FFFF, 7, 0x300);
+        assert!(GrfArchive::new(Cursor::new(header)).is_err());
+    }
+
+    #[test]
+    fn test_malformed_table() {
+        let with_table = |table_info: &[u32], table: &[u8]| -> Vec<u8> {
+            let mut grf = build_header(0, 0, 8, 0x200);
+            for value in table_info {
+                grf.extend_from_slice(&value.to_le_bytes());
+            }
+            grf.extend_from_slice(table);
+            grf
+        };
+        let compress = |data: &[u8]| -> Vec<u8> {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        // Sizes that don't match the archive's content
+        let table = compress(&[0; 64]);
+        let table_size = table.len() as u32;
+        for table_info in &[[u32::MAX, 64], [table_size + 1, 64], [table_size, u32::MAX]] {
+            assert!(GrfArchive::new(Cursor::new(with_table(table_info, &table))).is_err());
+        }
+        // Tables are decompressed up to their declared size only
+        let bomb = compress(&vec![0; 64 * 1024 * 1024]);
+        let grf = with_table(&[bomb.len() as u32, 16], &bomb);
+        assert!(GrfArchive::new(Cursor::new(grf)).is_err());
+        // Entries' data out of bounds
+        for (offset, size_compressed_aligned) in &[(1_000_000u32, 16u32), (0, u32::MAX)] {
+            let mut entry = b"data\\file.txt\0".to_vec();
+            for value in &[16, *size_compressed_aligned, 10] {
+                entry.extend_from_slice(&u32::to_le_bytes(*value));
+            }
+            entry.push(GrfEntryFlags::FILE.bits());
+            entry.extend_from_slice(&offset.to_le_bytes());
+            let table = compress(&entry);
+            let grf = with_table(&[table.len() as u32, entry.len() as u32], &table);
+            let mut grf = GrfArchive::new(Cursor::new(grf)).unwrap();
+            assert_eq!(grf.file_count(), 1);
+            assert!(grf.get_entry_raw_data("data\\file.txt").is_err());
+            assert!(grf.get_entry_raw_slice("data\\file.txt").is_err());
+            assert!(grf.open_raw_entry("data\\file.txt").is_err());
+            assert!(grf.open_entry("data\\file.txt").is_err());
+        }
+        // GRF 1.x entries whose sizes cannot be deobfuscated
+        let mut entry = 6u32.to_le_bytes().to_vec();
+        entry.extend_from_slice(&[0; 6]);
+        for value in &[0u32, 0, 10] {
+            entry.extend_from_slice(&value.to_le_bytes());
+        }
+        entry.extend_from_slice(&[GrfEntryFlags::FILE.bits(), 0, 0, 0, 0]);
+        assert!(parse_grf_file_entry_101(&entry).is_err());
+        entry[..4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(parse_grf_file_entry_101(&entry).is_err());
+    }
+
+    #[test]
+    fn test_mutated_archives() {
+        for (version_major, version_minor) in &[(1, 3), (2, 0), (3, 0)] {
+            let grf = build_test_archive(*version_major, *version_minor, &TEST_FILES);
+            read_all_entries(&grf);
+            // Truncated archives
+            for size in 0..grf.len() {
+                read_all_entries(&grf[..size]);
+            }
+            // Corrupted bytes
+            for i in 0..grf.len() {
+                for mask in &[0x01u8, 0x80, 0xFF] {
+                    let mut mutated_grf = grf.clone();
+                    mutated_grf[i] ^= mask;
+                    read_all_entries(&mutated_grf);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,437 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::grf::fsck::{read_file_table, GrfCheckReport, GrfIssue, ParsedTable};
+use crate::grf::reader::{entry_decoder, GRF_HEADER_SIZE};
+use crate::grf::{GrfArchiveBuilder, GrfFileEntry};
+use crate::Result;
+use flate2::bufread::ZlibDecoder;
+use serde::Serialize;
+
+// Size of the chunks read when scanning for zlib streams
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+// Maximum size of the content recovered from a single zlib stream found when
+// scanning, avoids decompression bombs
+const MAX_SCANNED_CONTENT_SIZE: u64 = 256 * 1024 * 1024;
+const SCANNED_ENTRIES_PREFIX: &str = "salvaged";
+
+#[derive(Debug, Clone, Default)]
+pub struct SalvageOptions {
+    /// If set, the raw data of broken entries is stored in the new archive
+    /// under this prefix instead of being dropped
+    pub quarantine_prefix: Option<String>,
+}
+
+/// Summary of a salvage operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SalvageReport {
+    /// Problems found while reading the original archive's file table
+    pub check_report: GrfCheckReport,
+    /// Paths of the entries copied into the new archive
+    pub recovered_entries: Vec<String>,
+    /// Entries that couldn't be recovered
+    pub lost_entries: Vec<LostEntry>,
+    /// Number of entries declared in the header but absent from the table
+    pub missing_entry_count: usize,
+    /// True if the file table was unreadable and entries were recovered by
+    /// scanning the archive for zlib streams. Names of such entries are lost.
+    pub scanned: bool,
+}
+
+impl SalvageReport {
+    /// Returns true if every entry of the original archive has been recovered
+    pub fn is_complete(&self) -> bool {
+        self.lost_entries.is_empty() && self.missing_entry_count == 0 && !self.scanned
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LostEntry {
+    pub path: String,
+    pub reason: String,
+    /// Path of the entry's raw data in the new archive, if it's been
+    /// quarantined
+    pub quarantine_path: Option<String>,
+}
+
+/// Rebuilds a damaged GRF archive into a new GRF 0x200 archive.
+///
+/// See `salvage`.
+pub fn salvage_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    grf_path: P,
+    output_path: Q,
+    options: &SalvageOptions,
+) -> Result<SalvageReport> {
+    let grf_file = File::open(grf_path)?;
+    let output_file = File::create(output_path)?;
+    let mut builder = GrfArchiveBuilder::create(output_file, 2, 0)?;
+    let report = salvage(grf_file, &mut builder, options)?;
+    builder.finish()?;
+    Ok(report)
+}
+
+/// Copies every entry of a (possibly damaged) GRF archive that can still be
+/// decompressed correctly into `builder`.
+///
+/// The archive is expected to start at the object's current position. Broken
+/// entries are dropped or quarantined, depending on `options`. When the file
+/// table cannot be read, the archive is scanned for zlib streams and their
+/// content is added under generated names. Encrypted entries cannot be
+/// recovered that way.
+pub fn salvage<R: Read + Seek, W: Write + Seek>(
+    mut obj: R,
+    builder: &mut GrfArchiveBuilder<W>,
+    options: &SalvageOptions,
+) -> Result<SalvageReport> {
+    let start_offset = obj.seek(SeekFrom::Current(0))?;
+    let archive_size = obj.seek(SeekFrom::End(0))?.saturating_sub(start_offset);
+    let mut check_report = GrfCheckReport::new(archive_size);
+    let parsed_table = read_file_table(&mut obj, start_offset, &mut check_report)?;
+    let mut report = SalvageReport {
+        missing_entry_count: check_report
+            .file_count
+            .saturating_sub(check_report.parsed_entry_count),
+        check_report,
+        recovered_entries: Vec::new(),
+        lost_entries: Vec::new(),
+        scanned: false,
+    };
+    // Region of the file table, if it's located inside the archive
+    let table_region = match &parsed_table {
+        Some(ParsedTable {
+            table_offset,
+            table_end,
+            ..
+        }) if !report
+            .check_report
+            .issues
+            .iter()
+            .any(|issue| matches!(issue, GrfIssue::TableOutOfBounds { .. })) =>
+        {
+            Some((*table_offset, *table_end))
+        }
+        _ => None,
+    };
+
+    match parsed_table {
+        Some(ParsedTable { entries, .. })
+            if !entries.is_empty() || report.check_report.file_count == 0 =>
+        {
+            let mut file_entries: Vec<GrfFileEntry> = entries
+                .into_iter()
+                .filter(|e| e.entry_type.is_file())
+                .collect();
+            file_entries.sort_by_key(|e| e.offset);
+            for entry in file_entries {
+                salvage_entry(
+                    &mut obj,
+                    start_offset,
+                    archive_size,
+                    entry,
+                    builder,
+                    options,
+                    &mut report,
+                )?;
+            }
+        }
+        _ => {
+            // The table is unusable, try to find entries' content
+            report.scanned = true;
+            let scan_start = if report.check_report.version_major == 0 {
+                0
+            } else {
+                GRF_HEADER_SIZE as u64
+            };
+            // Don't salvage the file table itself
+            let scanned_regions = match table_region {
+                Some((table_offset, table_end)) => {
+                    vec![(scan_start, table_offset), (table_end, archive_size)]
+                }
+                None => vec![(scan_start, archive_size)],
+            };
+            for (scan_start, scan_end) in scanned_regions {
+                scan_zlib_streams(
+                    &mut obj,
+                    start_offset,
+                    scan_start,
+                    scan_end,
+                    builder,
+                    &mut report,
+                )?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn salvage_entry<R: Read + Seek, W: Write + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    archive_size: u64,
+    entry: GrfFileEntry,
+    builder: &mut GrfArchiveBuilder<W>,
+    options: &SalvageOptions,
+    report: &mut SalvageReport,
+) -> Result<()> {
+    if entry.size == 0 {
+        builder.add_file(entry.relative_path.clone(), &[][..])?;
+        report.recovered_entries.push(entry.relative_path);
+        return Ok(());
+    }
+
+    // Read whatever is available of the entry's data
+    let mut raw_data = Vec::new();
+    if entry.offset < archive_size {
+        obj.seek(SeekFrom::Start(start_offset + entry.offset))?;
+        obj.by_ref()
+            .take(entry.size_compressed_aligned as u64)
+            .read_to_end(&mut raw_data)?;
+    }
+    let failure_reason = if raw_data.len() < entry.size_compressed_aligned {
+        Some("Entry's data goes past the end of the archive".to_string())
+    } else {
+        let mut content = Vec::new();
+        match entry_decoder(raw_data.as_slice(), &entry)
+            .take(entry.size as u64 + 1)
+            .read_to_end(&mut content)
+        {
+            Err(e) => Some(format!("Failed to decompress content: {}", e)),
+            Ok(size) if size != entry.size => Some(format!(
+                "Content size mismatch (expected {}, got {})",
+                entry.size, size
+            )),
+            Ok(_) => {
+                if entry.size_compressed <= entry.size_compressed_aligned {
+                    builder.import_raw_grf_content(
+                        entry.relative_path.clone(),
+                        &entry,
+                        Cow::Owned(raw_data),
+                    )?;
+                } else {
+                    builder.add_file(entry.relative_path.clone(), content.as_slice())?;
+                }
+                report.recovered_entries.push(entry.relative_path);
+                return Ok(());
+            }
+        }
+    };
+
+    let quarantine_path = match &options.quarantine_prefix {
+        Some(prefix) => {
+            let quarantine_path = format!("{}\\{}", prefix, entry.relative_path);
+            builder.add_file(quarantine_path.clone(), raw_data.as_slice())?;
+            Some(quarantine_path)
+        }
+        None => None,
+    };
+    report.lost_entries.push(LostEntry {
+        path: entry.relative_path,
+        reason: failure_reason.unwrap_or_default(),
+        quarantine_path,
+    });
+    Ok(())
+}
+
+/// Scans a region of the archive for zlib streams and adds their content to
+/// the builder
+fn scan_zlib_streams<R: Read + Seek, W: Write + Seek>(
+    obj: &mut R,
+    start_offset: u64,
+    scan_start: u64,
+    scan_end: u64,
+    builder: &mut GrfArchiveBuilder<W>,
+    report: &mut SalvageReport,
+) -> Result<()> {
+    let mut chunk = Vec::with_capacity(SCAN_CHUNK_SIZE);
+    let mut offset = scan_start;
+    while offset + 2 <= scan_end {
+        chunk.clear();
+        obj.seek(SeekFrom::Start(start_offset + offset))?;
+        obj.by_ref()
+            .take(cmp::min(SCAN_CHUNK_SIZE as u64, scan_end - offset))
+            .read_to_end(&mut chunk)?;
+        if chunk.len() < 2 {
+            break;
+        }
+        let mut next_offset = offset + chunk.len() as u64 - 1;
+        for i in 0..chunk.len() - 1 {
+            if !is_zlib_header(chunk[i], chunk[i + 1]) {
+                continue;
+            }
+            let stream_offset = offset + i as u64;
+            if let Some((content, stream_size)) =
+                try_inflate(obj, start_offset + stream_offset, scan_end - stream_offset)
+            {
+                let relative_path = format!(
+                    "{}\\{:010x}.{}",
+                    SCANNED_ENTRIES_PREFIX,
+                    stream_offset,
+                    guess_extension(&content)
+                );
+                builder.add_file(relative_path.clone(), content.as_slice())?;
+                report.recovered_entries.push(relative_path);
+                next_offset = stream_offset + stream_size;
+                break;
+            }
+        }
+        offset = next_offset;
+    }
+    Ok(())
+}
+
+fn is_zlib_header(cmf: u8, flg: u8) -> bool {
+    // Deflate with a 32K window, no preset dictionary and a valid checksum
+    cmf == 0x78 && (flg & 0x20) == 0 && ((u16::from(cmf) << 8) | u16::from(flg)) % 31 == 0
+}
+
+/// Tries to decompress a zlib stream located at the given offset, returns the
+/// content and the size of the stream on success
+fn try_inflate<R: Read + Seek>(obj: &mut R, offset: u64, max_size: u64) -> Option<(Vec<u8>, u64)> {
+    obj.seek(SeekFrom::Start(offset)).ok()?;
+    let mut decoder = ZlibDecoder::new(BufReader::new(obj.by_ref().take(max_size)));
+    let mut content = Vec::new();
+    decoder
+        .by_ref()
+        .take(MAX_SCANNED_CONTENT_SIZE + 1)
+        .read_to_end(&mut content)
+        .ok()?;
+    if content.is_empty() || content.len() as u64 > MAX_SCANNED_CONTENT_SIZE {
+        return None;
+    }
+    Some((content, decoder.total_in()))
+}
+
+/// Guesses a file's extension from its content
+fn guess_extension(content: &[u8]) -> &'static str {
+    const SIGNATURES: [(&[u8], &str); 10] = [
+        (b"GRAT", "gat"),
+        (b"GRGN", "gnd"),
+        (b"GRSW", "rsw"),
+        (b"RIFF", "wav"),
+        (b"\x89PNG", "png"),
+        (b"\x1BLua", "lub"),
+        (b"\xFF\xD8\xFF", "jpg"),
+        (b"BM", "bmp"),
+        (b"SP", "spr"),
+        (b"AC", "act"),
+    ];
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| content.starts_with(signature))
+        .map_or("bin", |(_, extension)| extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grf::{build_test_archive, GrfArchive};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn salvage_to_archive(
+        grf: Vec<u8>,
+        options: &SalvageOptions,
+    ) -> (SalvageReport, GrfArchive<Cursor<Vec<u8>>>) {
+        let mut output = Cursor::new(Vec::new());
+        let report = {
+            let mut builder = GrfArchiveBuilder::create(&mut output, 2, 0).unwrap();
+            salvage(Cursor::new(grf), &mut builder, options).unwrap()
+        };
+        output.set_position(0);
+        (report, GrfArchive::new(output).unwrap())
+    }
+
+    #[test]
+    fn test_salvage_valid_archive() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let temp_dir = tempfile::tempdir().unwrap();
+        for grf_name in &["103-small.grf", "200-small.grf"] {
+            let grf_path = grf_dir_path.join(grf_name);
+            let output_path = temp_dir.path().join(grf_name);
+            let report = salvage_file(&grf_path, &output_path, &SalvageOptions::default()).unwrap();
+            assert!(report.is_complete());
+            assert_eq!(report.recovered_entries.len(), 8);
+            let mut grf = GrfArchive::open(&grf_path).unwrap();
+            let mut output_grf = GrfArchive::open(&output_path).unwrap();
+            assert_eq!(output_grf.file_count(), grf.file_count());
+            let file_entries: Vec<GrfFileEntry> = grf.get_entries().cloned().collect();
+            for entry in file_entries {
+                assert_eq!(
+                    grf.read_file_content(&entry.relative_path).unwrap(),
+                    output_grf.read_file_content(&entry.relative_path).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_salvage_broken_entry() {
+        let files = [
+            ("data\\a.txt", b"first file content".repeat(10)),
+            ("data\\b.txt", b"second file content".repeat(10)),
+            ("data\\c.txt", b"third file content".repeat(10)),
+        ];
+        let mut grf = build_test_archive(2, 0, &files);
+        // Corrupt the middle of the second entry's data
+        let entry_offset = {
+            let grf_archive = GrfArchive::new(Cursor::new(grf.clone())).unwrap();
+            let entry = grf_archive.get_file_entry("data\\b.txt").unwrap();
+            (entry.offset as usize, entry.size_compressed)
+        };
+        for byte in &mut grf[entry_offset.0 + 2..entry_offset.0 + entry_offset.1 - 4] {
+            *byte = 0xFF;
+        }
+        let options = SalvageOptions {
+            quarantine_prefix: Some("quarantine".to_string()),
+        };
+        let (report, mut output_grf) = salvage_to_archive(grf, &options);
+        assert!(!report.is_complete());
+        assert!(!report.scanned);
+        assert_eq!(report.recovered_entries.len(), 2);
+        assert_eq!(report.lost_entries.len(), 1);
+        let lost_entry = &report.lost_entries[0];
+        assert_eq!(lost_entry.path, "data\\b.txt");
+        assert_eq!(
+            lost_entry.quarantine_path.as_deref(),
+            Some("quarantine\\data\\b.txt")
+        );
+        assert!(!output_grf.contains_file("data\\b.txt"));
+        assert!(output_grf.contains_file("quarantine\\data\\b.txt"));
+        for (path, content) in &[&files[0], &files[2]] {
+            assert_eq!(&output_grf.read_file_content(path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_salvage_unreadable_table() {
+        let files = [
+            ("data\\a.bmp", b"BM bitmap content".repeat(10)),
+            ("data\\b.txt", b"text content".repeat(10)),
+        ];
+        let mut grf = build_test_archive(2, 0, &files);
+        // Declare an empty decompressed table, the compressed table is intact
+        let mut table_offset_buf = [0; 4];
+        table_offset_buf.copy_from_slice(&grf[30..34]);
+        let table_size_offset = GRF_HEADER_SIZE + u32::from_le_bytes(table_offset_buf) as usize + 4;
+        grf[table_size_offset..table_size_offset + 4].copy_from_slice(&0u32.to_le_bytes());
+        let (report, mut output_grf) = salvage_to_archive(grf, &SalvageOptions::default());
+        assert!(report.scanned);
+        assert!(!report.is_complete());
+        // The compressed file table isn't salvaged
+        assert_eq!(report.recovered_entries.len(), files.len());
+        let recovered_contents: HashMap<Vec<u8>, String> = report
+            .recovered_entries
+            .iter()
+            .map(|path| (output_grf.read_file_content(path).unwrap(), path.clone()))
+            .collect();
+        for (_, content) in &files {
+            assert!(recovered_contents.contains_key(content));
+        }
+        assert!(recovered_contents[&files[0].1].ends_with(".bmp"));
+        assert!(recovered_contents[&files[1].1].ends_with(".bin"));
+    }
+}
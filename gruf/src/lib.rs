@@ -1,6 +1,13 @@
 mod archive;
+mod compression;
+pub mod data_ini;
 mod error;
 pub mod grf;
+mod parallel;
 pub mod thor;
+pub mod tree;
+pub mod vfs;
 
+pub use archive::{display_path, normalize_path, to_archive_path};
+pub use compression::CompressionPolicy;
 pub use error::{GrufError, Result};
@@ -0,0 +1,135 @@
+use std::panic;
+use std::sync::Mutex;
+
+use crossbeam_utils::thread;
+
+use crate::Result;
+
+// Number of items processed at once per worker thread. Results of a window of
+// items are kept in memory until they all have been consumed.
+const WINDOW_SIZE_PER_THREAD: usize = 4;
+
+/// Returns the number of worker threads used by default
+pub(crate) fn default_thread_count() -> usize {
+    num_cpus::get()
+}
+
+/// Applies `process` to items on `thread_count` worker threads, and passes the
+/// results to `consume` on the calling thread, in the items' order.
+///
+/// Stops at the first error, items of the current window that come after the
+/// failed item are processed but not consumed.
+pub(crate) fn process_in_order<I, T, U, P, C>(
+    items: I,
+    thread_count: usize,
+    process: P,
+    mut consume: C,
+) -> Result<()>
+where
+    I: IntoIterator<Item = T>,
+    T: Send,
+    U: Send,
+    P: Fn(T) -> Result<U> + Sync,
+    C: FnMut(U) -> Result<()>,
+{
+    let thread_count = thread_count.max(1);
+    let mut items = items.into_iter();
+    if thread_count == 1 {
+        return items.try_for_each(|item| consume(process(item)?));
+    }
+    loop {
+        let window: Vec<T> = items
+            .by_ref()
+            .take(thread_count * WINDOW_SIZE_PER_THREAD)
+            .collect();
+        if window.is_empty() {
+            return Ok(());
+        }
+        for result in process_window(window, thread_count, &process) {
+            consume(result?)?;
+        }
+    }
+}
+
+fn process_window<T, U, P>(window: Vec<T>, thread_count: usize, process: &P) -> Vec<Result<U>>
+where
+    T: Send,
+    U: Send,
+    P: Fn(T) -> Result<U> + Sync,
+{
+    let window_size = window.len();
+    let queue = Mutex::new(window.into_iter().enumerate());
+    let results: Mutex<Vec<Option<Result<U>>>> =
+        Mutex::new((0..window_size).map(|_| None).collect());
+    let scope_result = thread::scope(|scope| {
+        for _ in 0..thread_count.min(window_size) {
+            scope.spawn(|_| loop {
+                let next_item = queue.lock().unwrap().next();
+                match next_item {
+                    None => break,
+                    Some((i, item)) => {
+                        let result = process(item);
+                        results.lock().unwrap()[i] = Some(result);
+                    }
+                }
+            });
+        }
+    });
+    // Propagate panics of worker threads
+    if let Err(panic_payload) = scope_result {
+        panic::resume_unwind(panic_payload);
+    }
+    // All the items have been processed once the scope ends
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GrufError;
+
+    #[test]
+    fn test_process_in_order() {
+        for thread_count in &[0, 1, 3, 8] {
+            let mut output = Vec::new();
+            process_in_order(
+                0..100u32,
+                *thread_count,
+                |i| Ok(i * 2),
+                |i| {
+                    output.push(i);
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(output, (0..100u32).map(|i| i * 2).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_process_in_order_error() {
+        let mut output = Vec::new();
+        let result = process_in_order(
+            0..100u32,
+            4,
+            |i| {
+                if i == 42 {
+                    Err(GrufError::EntryNotFound)
+                } else {
+                    Ok(i)
+                }
+            },
+            |i| {
+                output.push(i);
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(output, (0..42u32).collect::<Vec<_>>());
+    }
+}
@@ -1,13 +1,17 @@
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
-use crate::archive::{serialize_as_win1252_str_into, serialize_to_win1252, GenericFileEntry};
+use crate::archive::{
+    serialize_as_win1252_str_into, serialize_to_win1252, to_archive_path, GenericFileEntry,
+};
+use crate::compression::compress;
+use crate::parallel::{default_thread_count, process_in_order};
 use crate::thor::{
     ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
 };
-use crate::Result;
+use crate::{CompressionPolicy, Result};
 use crc::crc32::{self, Hasher32};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
@@ -17,11 +21,15 @@ const THOR_HEADER_FIXED_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8;
 
 pub struct ThorArchiveBuilder<W: Write + Seek> {
     obj: Box<W>,
-    entries: HashMap<String, Option<BuilderFileEntry>>,
+    // Entries are ordered so that the generated archives are
+    // reproducible
+    entries: BTreeMap<String, Option<BuilderFileEntry>>,
     finished: bool,
     use_grf_merging: bool,
     target_grf_name: String,
     include_checksums: bool,
+    compression: CompressionPolicy,
+    thread_count: usize,
 }
 
 struct BuilderFileEntry {
@@ -29,6 +37,14 @@ struct BuilderFileEntry {
     checksum: u32,
 }
 
+// Compressed file update, ready to be written
+struct PreparedFileUpdate {
+    entry_path: String,
+    size: u64,
+    content: Vec<u8>,
+    checksum: u32,
+}
+
 #[derive(Debug, Serialize)]
 pub struct SerializableThorHeader<'a> {
     pub magic: &'a [u8; THOR_HEADER_MAGIC.len()],
@@ -74,38 +90,104 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         obj.write_all(place_holder.as_slice())?;
         Ok(Self {
             obj: Box::new(obj),
-            entries: HashMap::new(),
+            entries: BTreeMap::new(),
             finished: false,
             use_grf_merging,
             target_grf_name,
             include_checksums,
+            compression: CompressionPolicy::default(),
+            thread_count: default_thread_count(),
         })
     }
 
-    pub fn append_file_update<R>(&mut self, entry_path: String, mut data: R) -> Result<()>
+    /// Sets the compression policy used for files appended afterwards with
+    /// `append_file_update`.
+    pub fn set_compression(&mut self, compression: CompressionPolicy) {
+        self.compression = compression;
+    }
+
+    /// Sets the number of worker threads used to compress files appended with
+    /// `append_file_updates`. Defaults to the available parallelism.
+    pub fn set_thread_count(&mut self, thread_count: usize) {
+        self.thread_count = thread_count;
+    }
+
+    pub fn append_file_update<R>(&mut self, entry_path: String, data: R) -> Result<()>
     where
         R: Read,
     {
-        // Compress it
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        let (data_size, data_checksum) = if self.include_checksums {
-            copy_and_measure_crc32(data.by_ref(), &mut encoder)?
-        } else {
-            (io::copy(data.by_ref(), &mut encoder)?, 0)
-        };
+        self.append_file_update_with_compression(entry_path, data, self.compression)
+    }
+
+    /// Appends a file update, compressed with the given policy instead of the
+    /// builder's policy
+    pub fn append_file_update_with_compression<R>(
+        &mut self,
+        entry_path: String,
+        data: R,
+        compression: CompressionPolicy,
+    ) -> Result<()>
+    where
+        R: Read,
+    {
+        let file_update = prepare_file_update(entry_path, data, compression)?;
+        self.write_file_update(file_update)
+    }
+
+    /// Appends several file updates, compressed on a pool of worker threads.
+    ///
+    /// Files are written in order, the resulting archive is the same as the
+    /// one obtained by calling `append_file_update` on each file.
+    pub fn append_file_updates<I, R>(&mut self, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, R)>,
+        R: Read + Send,
+    {
+        let compression = self.compression;
+        self.append_file_updates_with_compression(
+            files
+                .into_iter()
+                .map(|(entry_path, data)| (entry_path, data, compression)),
+        )
+    }
+
+    /// Appends several file updates, compressed on a pool of worker threads
+    /// with their own compression policy.
+    ///
+    /// Files are written in order, the resulting archive is the same as the
+    /// one obtained by calling `append_file_update_with_compression` on each
+    /// file.
+    pub fn append_file_updates_with_compression<I, R>(&mut self, files: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, R, CompressionPolicy)>,
+        R: Read + Send,
+    {
+        process_in_order(
+            files,
+            self.thread_count,
+            |(entry_path, data, compression)| prepare_file_update(entry_path, data, compression),
+            |file_update| self.write_file_update(file_update),
+        )
+    }
+
+    fn write_file_update(&mut self, file_update: PreparedFileUpdate) -> Result<()> {
         // Write compressed data
-        let compressed_data = encoder.finish()?;
-        let compressed_data_size = compressed_data.len();
+        let compressed_data_size = file_update.content.len();
 
         let offset = self.obj.seek(SeekFrom::Current(0))?;
-        let mut compressed_reader = Cursor::new(compressed_data);
+        let mut compressed_reader = Cursor::new(file_update.content);
         let _ = io::copy(&mut compressed_reader, self.obj.by_ref())?;
+        let data_checksum = if self.include_checksums {
+            file_update.checksum
+        } else {
+            0
+        };
         self.entries.insert(
-            entry_path,
+            to_archive_path(&file_update.entry_path).into_owned(),
             Some(BuilderFileEntry {
                 generic: GenericFileEntry {
                     offset,
-                    size: u32::try_from(data_size)?,
+                    size: u32::try_from(file_update.size)?,
                     size_compressed: u32::try_from(compressed_data_size)?,
                 },
                 checksum: data_checksum,
@@ -115,7 +197,8 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
     }
 
     pub fn append_file_removal(&mut self, entry_path: String) {
-        self.entries.insert(entry_path, None);
+        self.entries
+            .insert(to_archive_path(&entry_path).into_owned(), None);
     }
 
     pub fn finish(&mut self) -> Result<()> {
@@ -207,6 +290,23 @@ impl<W: Write + Seek> Drop for ThorArchiveBuilder<W> {
     }
 }
 
+/// Compresses a file's content and computes its checksum before it gets
+/// written
+fn prepare_file_update<R: Read>(
+    entry_path: String,
+    data: R,
+    compression: CompressionPolicy,
+) -> Result<PreparedFileUpdate> {
+    let mut data = Crc32Reader::new(data);
+    let (size, content) = compress(data.by_ref(), compression)?;
+    Ok(PreparedFileUpdate {
+        entry_path,
+        size,
+        content,
+        checksum: data.sum32(),
+    })
+}
+
 fn write_thor_header<W: Write>(
     writer: &mut W,
     use_grf_merging: bool,
@@ -262,29 +362,30 @@ fn serialize_thor_slice_into<W: Write>(mut writer: W, slice: &[u8]) -> Result<()
     Ok(())
 }
 
-/// Computes a CRC32 checksum from a reader.
-fn copy_and_measure_crc32<R: ?Sized, W: ?Sized>(
-    reader: &mut R,
-    writer: &mut W,
-) -> Result<(u64, u32)>
-where
-    R: Read,
-    W: Write,
-{
-    // Use an 8KiB buffer
-    let mut buf = [0_u8; 8 * 1024];
-    let mut digest = crc32::Digest::new(crc::crc32::IEEE);
-    let mut written = 0;
-    loop {
-        let len = match reader.read(&mut buf) {
-            Ok(0) => return Ok((written, digest.sum32())),
-            Ok(len) => len,
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e.into()),
-        };
-        digest.write(&buf[..len]);
-        writer.write_all(&buf[..len])?;
-        written += len as u64;
+/// Reader that computes a CRC32 checksum of the data read through it.
+struct Crc32Reader<R: Read> {
+    inner: R,
+    digest: crc32::Digest,
+}
+
+impl<R: Read> Crc32Reader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            digest: crc32::Digest::new(crc32::IEEE),
+        }
+    }
+
+    fn sum32(&self) -> u32 {
+        self.digest.sum32()
+    }
+}
+
+impl<R: Read> Read for Crc32Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.digest.write(&buf[..len]);
+        Ok(len)
     }
 }
 
@@ -292,7 +393,8 @@ where
 mod tests {
     use super::*;
     use crate::thor::{ThorArchive, ThorFileEntry};
-    use std::fs::File;
+    use std::collections::HashMap;
+    use std::fs::{self, File};
     use tempfile::tempdir;
 
     #[test]
@@ -389,6 +491,146 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_compression() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        let content = vec![0x42u8; 4096];
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+            builder.set_compression(CompressionPolicy::Store);
+            builder
+                .append_file_update("data\\stored.txt".to_string(), content.as_slice())
+                .unwrap();
+            builder
+                .append_file_update_with_compression(
+                    "data\\compressed.txt".to_string(),
+                    content.as_slice(),
+                    CompressionPolicy::Auto,
+                )
+                .unwrap();
+        }
+        let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+        let stored = thor_archive.get_file_entry("data\\stored.txt").unwrap();
+        assert!(stored.size_compressed > content.len());
+        let compressed = thor_archive.get_file_entry("data\\compressed.txt").unwrap();
+        assert!(compressed.size_compressed < content.len());
+        for path in &["data\\stored.txt", "data\\compressed.txt"] {
+            assert_eq!(thor_archive.read_file_content(path).unwrap(), content);
+        }
+        // Checksums are computed from the uncompressed content
+        let checksums = thor_archive.read_file_content(INTEGRITY_FILE_NAME).unwrap();
+        let checksums = String::from_utf8(checksums).unwrap();
+        let expected_checksum = format!("=0x{:08x}", crc32::checksum_ieee(&content));
+        assert_eq!(checksums.matches(&expected_checksum).count(), 2);
+    }
+
+    #[test]
+    fn test_append_file_updates() {
+        let temp_dir = tempdir().unwrap();
+        let files: Vec<(String, Vec<u8>)> = (0..50u32)
+            .map(|i| {
+                let content = (0..(i * 97))
+                    .map(|j| (j.wrapping_mul(2654435761 + i) >> 13) as u8)
+                    .collect();
+                (format!("data\\file{}.bin", i), content)
+            })
+            .collect();
+        let build = |parallel: bool| {
+            let output_path = temp_dir.path().join(format!("builder-{}.thor", parallel));
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+                if parallel {
+                    builder.set_thread_count(4);
+                    builder
+                        .append_file_updates(
+                            files
+                                .iter()
+                                .map(|(path, content)| (path.clone(), content.as_slice())),
+                        )
+                        .unwrap();
+                } else {
+                    for (path, content) in &files {
+                        builder
+                            .append_file_update(path.clone(), content.as_slice())
+                            .unwrap();
+                    }
+                }
+            }
+            output_path
+        };
+        let sequential_path = build(false);
+        let parallel_path = build(true);
+        // Same output
+        assert_eq!(
+            fs::read(&sequential_path).unwrap(),
+            fs::read(&parallel_path).unwrap()
+        );
+        let mut parallel = ThorArchive::open(&parallel_path).unwrap();
+        assert_eq!(parallel.file_count(), files.len() + 1);
+        for (path, content) in &files {
+            assert_eq!(&parallel.read_file_content(path).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let temp_dir = tempdir().unwrap();
+        let build = |name: &str| {
+            let output_path = temp_dir.path().join(name);
+            {
+                let output_file = File::create(&output_path).unwrap();
+                let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+                for i in 0..100u8 {
+                    let content = vec![i; 100 + i as usize];
+                    builder
+                        .append_file_update(format!("data\\file{}.txt", i), content.as_slice())
+                        .unwrap();
+                    if i % 10 == 0 {
+                        builder.append_file_removal(format!("data\\removed{}.txt", i));
+                    }
+                }
+            }
+            fs::read(output_path).unwrap()
+        };
+        let output = build("builder.thor");
+        for i in 0..3 {
+            assert_eq!(output, build(&format!("builder{}.thor", i)));
+        }
+    }
+
+    #[test]
+    fn test_korean_paths() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        let display_path = "data\\유저인터페이스\\a.bmp";
+        let raw_path: &[u8] = &[
+            0x64, 0x61, 0x74, 0x61, 0x5C, 0xC0, 0xAF, 0xC0, 0xFA, 0xC0, 0xCE, 0xC5, 0xCD, 0xC6,
+            0xE4, 0xC0, 0xCC, 0xBD, 0xBA, 0x5C, 0x61, 0x2E, 0x62, 0x6D, 0x70,
+        ];
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+            builder
+                .append_file_update(display_path.to_string(), &[1u8, 2, 3][..])
+                .unwrap();
+        }
+        let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+        assert!(thor_archive.is_valid().unwrap());
+        let file_entry = thor_archive.get_file_entry(display_path).unwrap().clone();
+        assert_eq!(file_entry.raw_path().unwrap(), raw_path);
+        assert_eq!(file_entry.display_path(), display_path);
+        // Both forms can be used for lookups
+        assert_eq!(
+            thor_archive
+                .read_file_content(&file_entry.relative_path)
+                .unwrap(),
+            vec![1, 2, 3]
+        );
+    }
+
     #[test]
     fn test_data_integrity() {
         let temp_dir = tempdir().unwrap();
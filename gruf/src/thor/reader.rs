@@ -1,13 +1,19 @@
 use std::boxed::Box;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom};
 use std::path::Path;
 
+use crate::archive::{
+    display_path, normalize_path, read_exact_bounded, serialize_to_win1252, SizeCheckedReader,
+    MAX_TABLE_SIZE,
+};
 use crate::thor::{
     ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
 };
+use crate::tree::{ArchiveEntry, DirectoryTree};
 use crate::{GrufError, Result};
 use crc::crc32;
 use encoding::label::encoding_from_whatwg_label;
@@ -123,36 +129,60 @@ impl<R: Read + Seek> ThorArchive<R> {
             return Ok(vec![]);
         }
 
+        let raw_size = self.check_entry_bounds(&file_entry)?;
         self.obj.seek(SeekFrom::Start(file_entry.offset))?;
-        let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed);
-        let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
-        file_chunk.read_to_end(&mut content)?;
-        Ok(content)
+        read_exact_bounded(self.obj.as_mut(), raw_size)
+    }
+
+    /// Opens a file entry's raw (compressed) data for reading, without
+    /// loading it into memory.
+    pub fn open_raw_entry<S: AsRef<str> + Hash>(
+        &mut self,
+        file_path: S,
+    ) -> Result<io::Take<&mut R>> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?;
+        let (raw_offset, raw_size) = (file_entry.offset, self.check_entry_bounds(file_entry)?);
+        self.obj.seek(SeekFrom::Start(raw_offset))?;
+        Ok(self.obj.as_mut().take(raw_size))
     }
 
     pub fn read_file_content<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open_entry(file_path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Opens a file entry for reading.
+    ///
+    /// The content is decompressed on the fly, which avoids loading whole
+    /// entries into memory.
+    pub fn open_entry<S: AsRef<str> + Hash>(&mut self, file_path: S) -> Result<impl Read + '_> {
         let file_entry = self
             .get_file_entry(file_path)
             .ok_or(GrufError::EntryNotFound)?
             .clone();
         if file_entry.size_compressed == 0 {
-            return Ok(vec![]);
+            return Ok(SizeCheckedReader::empty());
         }
 
+        let raw_size = self.check_entry_bounds(&file_entry)?;
         self.obj.seek(SeekFrom::Start(file_entry.offset))?;
-        let mut content: Vec<u8> = Vec::with_capacity(file_entry.size_compressed);
-        let mut file_chunk = self.obj.by_ref().take(content.capacity() as u64);
-        file_chunk.read_to_end(&mut content)?;
+        let file_chunk = self.obj.by_ref().take(raw_size);
         // Decompress the content with zlib
-        let mut decoder = ZlibDecoder::new(content.as_slice());
-        let mut decompressed_content = Vec::new();
-        let decompressed_size = decoder.read_to_end(&mut decompressed_content)?;
-        if decompressed_size != file_entry.size {
-            return Err(GrufError::parsing_error(
-                "Decompressed content is not as expected",
-            ));
+        let decoder = ZlibDecoder::new(file_chunk);
+        Ok(SizeCheckedReader::new(decoder, file_entry.size as u64))
+    }
+
+    /// Makes sure an entry's raw data is located inside the archive, returns
+    /// the size of the data
+    fn check_entry_bounds(&self, file_entry: &ThorFileEntry) -> Result<u64> {
+        let raw_size = file_entry.size_compressed as u64;
+        match file_entry.offset.checked_add(raw_size) {
+            Some(end_offset) if end_offset <= self.container.archive_size => Ok(raw_size),
+            _ => Err(GrufError::parsing_error("Entry data is out of bounds")),
         }
-        Ok(decompressed_content)
     }
 
     pub fn extract_file<S: AsRef<str> + Hash>(
@@ -160,19 +190,28 @@ impl<R: Read + Seek> ThorArchive<R> {
         file_path: S,
         destination_path: &Path,
     ) -> Result<()> {
-        let content = self.read_file_content(file_path)?;
+        let mut entry_reader = self.open_entry(file_path)?;
         let mut file = File::create(destination_path)?;
-        Ok(file.write_all(content.as_slice())?)
+        io::copy(&mut entry_reader, &mut file)?;
+        Ok(())
     }
 
     pub fn get_file_entry<S: AsRef<str> + Hash>(&self, file_path: S) -> Option<&ThorFileEntry> {
-        self.container.entries.get(file_path.as_ref())
+        self.container
+            .entries
+            .get(&normalize_path(file_path.as_ref()))
     }
 
     pub fn get_entries(&self) -> impl Iterator<Item = &'_ ThorFileEntry> {
         self.container.entries.values()
     }
 
+    /// Returns a hierarchical index of the archive's entries, entries marked
+    /// for removal included
+    pub fn directory_tree(&self) -> DirectoryTree<'_, ThorFileEntry> {
+        DirectoryTree::new(self.get_entries())
+    }
+
     /// Checks if the container has been unintentionnaly corrupted
     pub fn is_valid(&mut self) -> Result<bool> {
         let integrity_data = self.read_file_content(INTEGRITY_FILE_NAME)?;
@@ -195,6 +234,9 @@ impl<R: Read + Seek> ThorArchive<R> {
 pub struct ThorContainer {
     pub header: ThorHeader,
     table: ThorTable,
+    // Size of the underlying object
+    archive_size: u64,
+    // Entries are indexed by their normalized path
     pub entries: HashMap<String, ThorFileEntry>,
 }
 
@@ -233,11 +275,35 @@ pub struct ThorFileEntry {
 }
 
 impl ThorFileEntry {
+    /// Returns the entry's path as stored in the archive
+    pub fn raw_path(&self) -> Result<Vec<u8>> {
+        serialize_to_win1252(&self.relative_path)
+    }
+
+    /// Returns the entry's path decoded as CP949
+    pub fn display_path(&self) -> String {
+        display_path(&self.relative_path)
+    }
+
     pub fn is_internal(&self) -> bool {
         self.relative_path == INTEGRITY_FILE_NAME
     }
 }
 
+impl ArchiveEntry for ThorFileEntry {
+    fn relative_path(&self) -> &str {
+        &self.relative_path
+    }
+
+    fn size(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn size_compressed(&self) -> u64 {
+        self.size_compressed as u64
+    }
+}
+
 impl Hash for ThorFileEntry {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.relative_path.hash(state);
@@ -292,15 +358,20 @@ named!(parse_single_file_table<&[u8], SingleFileTableDesc>,
 
 named!(parse_multiple_files_table<&[u8], MultipleFilesTableDesc>,
     do_parse!(
-        file_table_compressed_size: le_i32
-        >> file_table_offset: le_i32
+        file_table_compressed_size: le_size
+        >> file_table_offset: le_size
         >> (MultipleFilesTableDesc {
-            file_table_compressed_size: file_table_compressed_size as usize,
+            file_table_compressed_size,
             file_table_offset: file_table_offset as u64, // Offset in the 'data' field
         }
     )
 ));
 
+// Sizes and offsets are stored as signed integers, negative values are invalid
+named!(le_size<&[u8], usize>,
+    map_opt!(le_i32, |value: i32| usize::try_from(value).ok())
+);
+
 fn string_from_win_1252(v: &[u8]) -> Result<String> {
     let decoder = encoding_from_whatwg_label("windows-1252")
         .ok_or_else(|| GrufError::parsing_error("Decoder unavailable"))?;
@@ -320,13 +391,13 @@ macro_rules! take_string_ansi (
 
 named!(parse_single_file_entry<&[u8], ThorFileEntry>,
     do_parse!(
-        size_compressed: le_i32
-        >> size: le_i32
+        size_compressed: le_size
+        >> size: le_size
         >> relative_path_size: le_u8
         >> relative_path: take_string_ansi!(relative_path_size)
         >> (ThorFileEntry {
-            size_compressed: size_compressed as usize,
-            size: size as usize,
+            size_compressed,
+            size,
             relative_path,
             is_removed: false,
             offset: 0, // This field is set outside the parser
@@ -355,11 +426,11 @@ named!(parse_multiple_files_entry<&[u8], ThorFileEntry>,
         >> relative_path: take_string_ansi!(relative_path_size)
         >> flags: le_u8
         >> offset: take_if_not_removed!(le_u32, flags)
-        >> size_compressed: take_if_not_removed!(le_i32, flags)
-        >> size: take_if_not_removed!(le_i32, flags)
+        >> size_compressed: take_if_not_removed!(le_size, flags)
+        >> size: take_if_not_removed!(le_size, flags)
         >> (ThorFileEntry {
-            size_compressed: size_compressed as usize,
-            size: size as usize,
+            size_compressed,
+            size,
             relative_path,
             is_removed: is_file_removed(flags),
             offset: offset as u64,
@@ -369,7 +440,7 @@ named!(parse_multiple_files_entry<&[u8], ThorFileEntry>,
 
 named!(parse_multiple_files_entries<&[u8], HashMap<String, ThorFileEntry>>,
     fold_many1!(parse_multiple_files_entry, HashMap::new(), |mut acc: HashMap<_, _>, item| {
-        acc.insert(item.relative_path.clone(), item);
+        acc.insert(normalize_path(&item.relative_path), item);
         acc
     })
 );
@@ -377,6 +448,9 @@ named!(parse_multiple_files_entries<&[u8], HashMap<String, ThorFileEntry>>,
 pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer> {
     const HEADER_EXTENDED_MAX_SIZE: usize =
         HEADER_MAX_SIZE + MULTIPLE_FILES_TABLE_DESC_SIZE + SINGLE_FILE_ENTRY_MAX_SIZE;
+    let start_offset = reader.seek(SeekFrom::Current(0))?;
+    let archive_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(start_offset))?;
     let mut thor_header_buf = Vec::with_capacity(HEADER_EXTENDED_MAX_SIZE);
     let mut reader_chunk = reader.take(thor_header_buf.capacity() as u64);
     reader_chunk.read_to_end(&mut thor_header_buf)?;
@@ -395,7 +469,8 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
             Ok(ThorContainer {
                 header,
                 table: ThorTable::SingleFile(table),
-                entries: [(entry.relative_path.clone(), entry)]
+                archive_size,
+                entries: [(normalize_path(&entry.relative_path), entry)]
                     .iter()
                     .cloned()
                     .collect(),
@@ -408,15 +483,20 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
             if table.file_table_offset < consumed_bytes {
                 return Err(GrufError::parsing_error("Invalid THOR file table offset"));
             }
+            if table.file_table_offset + table.file_table_compressed_size as u64 > archive_size {
+                return Err(GrufError::parsing_error("THOR file table is out of bounds"));
+            }
             // Decompress the table with zlib
             reader.seek(SeekFrom::Start(table.file_table_offset))?;
-            let mut compressed_table: Vec<u8> =
-                Vec::with_capacity(table.file_table_compressed_size);
-            let mut file_chunk = reader.take(compressed_table.capacity() as u64);
-            file_chunk.read_to_end(&mut compressed_table)?;
-            let mut decoder = ZlibDecoder::new(compressed_table.as_slice());
+            let compressed_table =
+                read_exact_bounded(reader.by_ref(), table.file_table_compressed_size as u64)?;
+            let mut decoder =
+                ZlibDecoder::new(compressed_table.as_slice()).take(MAX_TABLE_SIZE as u64 + 1);
             let mut decompressed_table = vec![];
             let decompressed_size = decoder.read_to_end(&mut decompressed_table)?;
+            if decompressed_size > MAX_TABLE_SIZE {
+                return Err(GrufError::parsing_error("THOR file table is too big"));
+            }
             // Parse multiple entries
             let entries = match decompressed_size {
                 0 => HashMap::new(), // No entries
@@ -431,6 +511,7 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
             Ok(ThorContainer {
                 header,
                 table: ThorTable::MultipleFiles(table),
+                archive_size,
                 entries,
             })
         }
@@ -440,6 +521,9 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::{Cursor, Write};
     use std::path::PathBuf;
 
     #[test]
@@ -575,4 +659,172 @@ mod tests {
             assert!(thor_archive.is_valid().unwrap());
         }
     }
+
+    #[test]
+    fn test_case_insensitive_lookup() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let thor_archive = ThorArchive::open(&thor_dir_path.join("dir2.thor")).unwrap();
+        for file_path in &[
+            "savedata\\OptionInfo.lua",
+            "SAVEDATA\\optioninfo.LUA",
+            "savedata/OptionInfo.lua",
+        ] {
+            let entry = thor_archive.get_file_entry(file_path).unwrap();
+            assert_eq!(entry.relative_path, "savedata\\OptionInfo.lua");
+            assert_eq!(entry.size, 2703);
+        }
+    }
+
+    #[test]
+    fn test_directory_tree() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let thor_archive = ThorArchive::open(&thor_dir_path.join("dir2.thor")).unwrap();
+        let tree = thor_archive.directory_tree();
+        let root: Vec<(&str, bool)> = tree
+            .read_dir("")
+            .unwrap()
+            .iter()
+            .map(|e| (e.relative_path(), e.is_directory()))
+            .collect();
+        assert_eq!(root, vec![("ASPLnchr.exe", false), ("savedata", true)]);
+        let savedata = tree.get_directory("SaveData").unwrap();
+        assert_eq!(savedata.stats().file_count, 3);
+        assert_eq!(savedata.stats().size, 2723);
+        assert_eq!(tree.root().stats().size, 2723 + 248568);
+        let paths: Vec<&str> = tree
+            .glob("savedata\\*info.lua")
+            .unwrap()
+            .map(|e| e.relative_path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["savedata\\MiniPartyInfo.lua", "savedata\\OptionInfo.lua"]
+        );
+        assert_eq!(tree.walk("savedata").unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_open_entry() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let temp_dir = tempfile::tempdir().unwrap();
+        let thor_file_path = thor_dir_path.join("small.thor");
+        let mut thor_archive = ThorArchive::open(&thor_file_path).unwrap();
+        let file_entries: Vec<ThorFileEntry> = thor_archive
+            .get_entries()
+            .filter(|e| !e.is_removed)
+            .cloned()
+            .collect();
+        for (i, file_entry) in file_entries.iter().enumerate() {
+            let mut content = Vec::new();
+            thor_archive
+                .open_entry(&file_entry.relative_path)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content.len(), file_entry.size);
+            // Extracted files must match the streamed content
+            let destination_path = temp_dir.path().join(i.to_string());
+            thor_archive
+                .extract_file(&file_entry.relative_path, &destination_path)
+                .unwrap();
+            assert_eq!(std::fs::read(&destination_path).unwrap(), content);
+        }
+        assert!(matches!(
+            thor_archive.open_entry("missing.txt").err().unwrap(),
+            GrufError::EntryNotFound
+        ));
+    }
+
+    fn build_header(mode: i16) -> Vec<u8> {
+        let mut header = THOR_HEADER_MAGIC.to_vec();
+        header.push(0);
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&mode.to_le_bytes());
+        header.push(0);
+        header
+    }
+
+    /// Opens an archive and reads all of its entries. Errors are expected,
+    /// panics aren't.
+    fn read_all_entries(data: &[u8]) {
+        if let Ok(mut thor) = ThorArchive::new(Cursor::new(data)) {
+            let _ = thor.is_valid();
+            let paths: Vec<String> = thor
+                .get_entries()
+                .map(|entry| entry.relative_path.clone())
+                .collect();
+            for path in paths {
+                let _ = thor.get_entry_raw_data(&path);
+                let _ = thor.read_file_content(&path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_table() {
+        // Negative sizes in single file archives
+        for (size_compressed, size) in &[(-1i32, 10i32), (10, -1), (i32::MIN, i32::MIN)] {
+            let mut thor = build_header(33);
+            thor.push(0);
+            thor.extend_from_slice(&size_compressed.to_le_bytes());
+            thor.extend_from_slice(&size.to_le_bytes());
+            thor.push(1);
+            thor.push(b'a');
+            assert!(ThorArchive::new(Cursor::new(thor)).is_err());
+        }
+        // Negative or out of bounds file tables
+        for (compressed_size, offset) in &[(-1i32, 34i32), (4, -1), (4, 1_000_000), (1_000_000, 34)]
+        {
+            let mut thor = build_header(48);
+            thor.extend_from_slice(&compressed_size.to_le_bytes());
+            thor.extend_from_slice(&offset.to_le_bytes());
+            thor.extend_from_slice(&[0; 4]);
+            assert!(ThorArchive::new(Cursor::new(thor)).is_err());
+        }
+        // Entries' data out of bounds
+        for (offset, size_compressed) in &[(1_000_000u32, 16i32), (0, i32::MAX), (0, -1)] {
+            let mut entry = vec![1, b'a', 0];
+            entry.extend_from_slice(&offset.to_le_bytes());
+            entry.extend_from_slice(&size_compressed.to_le_bytes());
+            entry.extend_from_slice(&10i32.to_le_bytes());
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&entry).unwrap();
+            let table = encoder.finish().unwrap();
+            let mut thor = build_header(48);
+            let table_offset = (thor.len() + MULTIPLE_FILES_TABLE_DESC_SIZE) as i32;
+            thor.extend_from_slice(&(table.len() as i32).to_le_bytes());
+            thor.extend_from_slice(&table_offset.to_le_bytes());
+            thor.extend_from_slice(&table);
+            match ThorArchive::new(Cursor::new(thor)) {
+                Ok(mut thor) => {
+                    assert_eq!(thor.file_count(), 1);
+                    assert!(thor.get_entry_raw_data("a").is_err());
+                    assert!(thor.open_raw_entry("a").is_err());
+                    assert!(thor.open_entry("a").is_err());
+                }
+                Err(_) => assert!(*size_compressed < 0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mutated_archives() {
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        for thor_file_name in &["empty.thor", "tiny.thor"] {
+            let thor = std::fs::read(thor_dir_path.join(thor_file_name)).unwrap();
+            read_all_entries(&thor);
+            // Truncated archives
+            for size in 0..thor.len() {
+                read_all_entries(&thor[..size]);
+            }
+            // Corrupted bytes
+            for i in 0..thor.len() {
+                for mask in &[0x01u8, 0x80, 0xFF] {
+                    let mut mutated_thor = thor.clone();
+                    mutated_thor[i] ^= mask;
+                    read_all_entries(&mutated_thor);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,496 @@
+use std::collections::BTreeMap;
+
+use crate::archive::normalize_path;
+use crate::{GrufError, Result};
+use regex::Regex;
+
+/// Entry of an archive that can be indexed in a `DirectoryTree`
+pub trait ArchiveEntry {
+    /// Returns the entry's path, in the archives' form
+    fn relative_path(&self) -> &str;
+    /// Returns the size of the entry's content
+    fn size(&self) -> u64;
+    /// Returns the size of the entry's compressed content
+    fn size_compressed(&self) -> u64;
+}
+
+/// Aggregated sizes of the files located in a directory and its
+/// subdirectories
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectoryStats {
+    pub file_count: usize,
+    pub size: u64,
+    pub size_compressed: u64,
+}
+
+/// Hierarchical index of an archive's entries.
+///
+/// Paths are split on both `\` and `/`, and looked up case-insensitively.
+/// Entries are ordered by their normalized path.
+pub struct DirectoryTree<'a, E> {
+    root: DirectoryNode<'a, E>,
+}
+
+/// Directory of a `DirectoryTree`
+pub struct DirectoryNode<'a, E> {
+    // Casing of the first entry found in the directory
+    relative_path: String,
+    directories: BTreeMap<String, DirectoryNode<'a, E>>,
+    files: BTreeMap<String, &'a E>,
+    stats: DirectoryStats,
+}
+
+/// Entry returned when listing a `DirectoryTree`
+pub enum TreeEntry<'a, E> {
+    Directory(&'a DirectoryNode<'a, E>),
+    File(&'a E),
+}
+
+impl<'a, E> Clone for TreeEntry<'a, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, E> Copy for TreeEntry<'a, E> {}
+
+impl<'a, E: ArchiveEntry> DirectoryTree<'a, E> {
+    /// Builds the tree containing the given file entries
+    pub fn new<I: IntoIterator<Item = &'a E>>(entries: I) -> Self {
+        let mut tree = Self {
+            root: DirectoryNode::new(String::new()),
+        };
+        for entry in entries {
+            tree.insert_file(entry);
+        }
+        tree
+    }
+
+    fn insert_file(&mut self, entry: &'a E) {
+        let relative_path = entry.relative_path();
+        let (directory_path, file_name) = match relative_path.rfind(is_separator) {
+            Some(i) => (&relative_path[..i], &relative_path[i + 1..]),
+            None => ("", relative_path),
+        };
+        let stats = DirectoryStats {
+            file_count: 1,
+            size: entry.size(),
+            size_compressed: entry.size_compressed(),
+        };
+        let mut directory = &mut self.root;
+        directory.stats.add(&stats);
+        for directory_name in split_path(directory_path) {
+            directory = directory.subdirectory_or_insert(directory_name);
+            directory.stats.add(&stats);
+        }
+        directory.files.insert(normalize_path(file_name), entry);
+    }
+
+    /// Adds an empty directory to the tree, if it doesn't exist already
+    pub(crate) fn insert_directory(&mut self, relative_path: &str) {
+        let mut directory = &mut self.root;
+        for directory_name in split_path(relative_path) {
+            directory = directory.subdirectory_or_insert(directory_name);
+        }
+    }
+
+    pub fn root(&self) -> &DirectoryNode<'a, E> {
+        &self.root
+    }
+
+    /// Returns the directory located at the given path
+    pub fn get_directory<S: AsRef<str>>(&self, directory_path: S) -> Option<&DirectoryNode<'a, E>> {
+        split_path(directory_path.as_ref()).try_fold(&self.root, |directory, directory_name| {
+            directory.directories.get(&normalize_path(directory_name))
+        })
+    }
+
+    /// Lists the files and directories located directly in `directory_path`
+    pub fn read_dir<S: AsRef<str>>(&self, directory_path: S) -> Result<Vec<TreeEntry<'_, E>>> {
+        let directory = self
+            .get_directory(directory_path)
+            .ok_or(GrufError::EntryNotFound)?;
+        Ok(directory.children())
+    }
+
+    /// Lists the files and directories located in `directory_path` and its
+    /// subdirectories, depth-first
+    pub fn walk<S: AsRef<str>>(
+        &self,
+        directory_path: S,
+    ) -> Result<impl Iterator<Item = TreeEntry<'_, E>>> {
+        let directory = self
+            .get_directory(directory_path)
+            .ok_or(GrufError::EntryNotFound)?;
+        let mut pending_entries = directory.children();
+        pending_entries.reverse();
+        Ok(Walk { pending_entries })
+    }
+
+    /// Returns the files whose path matches the given glob pattern.
+    ///
+    /// Patterns are matched case-insensitively against whole paths, with `\`
+    /// or `/` as separators. `?` matches any character but a separator, `*`
+    /// matches any sequence of characters without separators and `**` matches
+    /// any sequence of directories. `[...]` and `[!...]` match characters
+    /// from (or not from) a set.
+    pub fn glob<S: AsRef<str>>(&self, pattern: S) -> Result<impl Iterator<Item = &'_ E>> {
+        let regex = glob_to_regex(&normalize_path(pattern.as_ref()))?;
+        Ok(self
+            .files()
+            .filter(move |entry| regex.is_match(&normalize_path(entry.relative_path()))))
+    }
+
+    /// Returns the files whose path (in the archives' form) matches the given
+    /// regular expression
+    pub fn find_regex<S: AsRef<str>>(&self, pattern: S) -> Result<impl Iterator<Item = &'_ E>> {
+        let regex =
+            Regex::new(pattern.as_ref()).map_err(|e| GrufError::invalid_pattern(e.to_string()))?;
+        Ok(self
+            .files()
+            .filter(move |entry| regex.is_match(entry.relative_path())))
+    }
+
+    fn files(&self) -> impl Iterator<Item = &'_ E> {
+        let mut pending_entries = self.root.children();
+        pending_entries.reverse();
+        Walk { pending_entries }.filter_map(|entry| match entry {
+            TreeEntry::File(entry) => Some(entry),
+            TreeEntry::Directory(_) => None,
+        })
+    }
+}
+
+impl<'a, E> DirectoryNode<'a, E> {
+    fn new(relative_path: String) -> Self {
+        Self {
+            relative_path,
+            directories: BTreeMap::new(),
+            files: BTreeMap::new(),
+            stats: DirectoryStats::default(),
+        }
+    }
+
+    /// Returns the directory's path, without a trailing separator
+    pub fn relative_path(&self) -> &str {
+        &self.relative_path
+    }
+
+    /// Returns the directory's name (i.e. the last component of its path)
+    pub fn name(&self) -> &str {
+        match self.relative_path.rfind('\\') {
+            Some(i) => &self.relative_path[i + 1..],
+            None => &self.relative_path,
+        }
+    }
+
+    /// Returns the aggregated sizes of the files located in this directory and
+    /// its subdirectories
+    pub fn stats(&self) -> DirectoryStats {
+        self.stats
+    }
+
+    fn subdirectory_or_insert(&mut self, name: &str) -> &mut DirectoryNode<'a, E> {
+        let relative_path = if self.relative_path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}\\{}", self.relative_path, name)
+        };
+        self.directories
+            .entry(normalize_path(name))
+            .or_insert_with(|| DirectoryNode::new(relative_path))
+    }
+
+    fn children(&self) -> Vec<TreeEntry<'_, E>> {
+        let mut children: Vec<(&String, TreeEntry<'_, E>)> = self
+            .directories
+            .iter()
+            .map(|(key, directory)| (key, TreeEntry::Directory(directory)))
+            .chain(
+                self.files
+                    .iter()
+                    .map(|(key, entry)| (key, TreeEntry::File(*entry))),
+            )
+            .collect();
+        children.sort_by(|a, b| a.0.cmp(b.0));
+        children.into_iter().map(|(_, entry)| entry).collect()
+    }
+}
+
+impl<'a, E: ArchiveEntry> TreeEntry<'a, E> {
+    /// Returns the entry's path, directories' paths don't have a trailing
+    /// separator
+    pub fn relative_path(&self) -> &'a str {
+        match *self {
+            TreeEntry::Directory(directory) => directory.relative_path(),
+            TreeEntry::File(entry) => entry.relative_path(),
+        }
+    }
+
+    pub fn is_directory(&self) -> bool {
+        matches!(self, TreeEntry::Directory(_))
+    }
+}
+
+struct Walk<'a, E> {
+    // Entries are popped from the end
+    pending_entries: Vec<TreeEntry<'a, E>>,
+}
+
+impl<'a, E> Iterator for Walk<'a, E> {
+    type Item = TreeEntry<'a, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.pending_entries.pop()?;
+        if let TreeEntry::Directory(directory) = entry {
+            let mut children = directory.children();
+            children.reverse();
+            self.pending_entries.extend(children);
+        }
+        Some(entry)
+    }
+}
+
+impl DirectoryStats {
+    fn add(&mut self, other: &DirectoryStats) {
+        self.file_count += other.file_count;
+        self.size += other.size;
+        self.size_compressed += other.size_compressed;
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    c == '\\' || c == '/'
+}
+
+fn split_path(directory_path: &str) -> impl Iterator<Item = &'_ str> {
+    directory_path
+        .split(is_separator)
+        .filter(|component| !component.is_empty())
+}
+
+/// Translates a normalized glob pattern into an anchored regular expression
+fn glob_to_regex(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'\\') {
+                    chars.next();
+                    regex.push_str(r"(?:.*\\)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str(r"[^\\]*"),
+            '?' => regex.push_str(r"[^\\]"),
+            '[' => {
+                regex.push('[');
+                if let Some('!') | Some('^') = chars.peek() {
+                    chars.next();
+                    regex.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('-') => regex.push('-'),
+                        Some(c) => regex.push_str(&regex::escape(&c.to_string())),
+                        None => {
+                            return Err(GrufError::invalid_pattern(format!(
+                                "Unclosed character class in '{}'",
+                                pattern
+                            )))
+                        }
+                    }
+                }
+                regex.push(']');
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|e| GrufError::invalid_pattern(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestEntry {
+        relative_path: &'static str,
+        size: u64,
+    }
+
+    impl ArchiveEntry for TestEntry {
+        fn relative_path(&self) -> &str {
+            self.relative_path
+        }
+
+        fn size(&self) -> u64 {
+            self.size
+        }
+
+        fn size_compressed(&self) -> u64 {
+            self.size / 2
+        }
+    }
+
+    fn test_entries() -> Vec<TestEntry> {
+        [
+            ("data\\sprite\\monster\\poring.spr", 100),
+            ("data\\sprite\\monster\\poring.act", 50),
+            ("data\\Sprite\\npc\\kafra.spr", 200),
+            ("data/texture/a.bmp", 10),
+            ("data\\texture\\B.BMP", 20),
+            ("data\\clientinfo.xml", 4),
+            ("readme.txt", 2),
+        ]
+        .iter()
+        .map(|(relative_path, size)| TestEntry {
+            relative_path,
+            size: *size,
+        })
+        .collect()
+    }
+
+    fn paths<'a, E: ArchiveEntry + 'a>(entries: impl Iterator<Item = &'a E>) -> Vec<&'a str> {
+        entries.map(|entry| entry.relative_path()).collect()
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let entries = test_entries();
+        let tree = DirectoryTree::new(&entries);
+        let root: Vec<(&str, bool)> = tree
+            .read_dir("")
+            .unwrap()
+            .iter()
+            .map(|e| (e.relative_path(), e.is_directory()))
+            .collect();
+        assert_eq!(root, vec![("data", true), ("readme.txt", false)]);
+        let data: Vec<&str> = tree
+            .read_dir("DATA/")
+            .unwrap()
+            .iter()
+            .map(|e| e.relative_path())
+            .collect();
+        assert_eq!(
+            data,
+            vec!["data\\clientinfo.xml", "data\\sprite", "data\\texture"]
+        );
+        let directory = tree.get_directory("data\\SPRITE\\Monster").unwrap();
+        assert_eq!(directory.relative_path(), "data\\sprite\\monster");
+        assert_eq!(directory.name(), "monster");
+        assert!(matches!(
+            tree.read_dir("data\\missing"),
+            Err(GrufError::EntryNotFound)
+        ));
+        assert!(tree.read_dir("readme.txt").is_err());
+    }
+
+    #[test]
+    fn test_walk() {
+        let entries = test_entries();
+        let mut tree = DirectoryTree::new(&entries);
+        tree.insert_directory("data\\texture\\empty");
+        let walked: Vec<&str> = tree
+            .walk("data\\texture")
+            .unwrap()
+            .map(|e| e.relative_path())
+            .collect();
+        assert_eq!(
+            walked,
+            vec![
+                "data/texture/a.bmp",
+                "data\\texture\\B.BMP",
+                "data\\texture\\empty"
+            ]
+        );
+        let walked: Vec<&str> = tree.walk("").unwrap().map(|e| e.relative_path()).collect();
+        assert_eq!(
+            walked,
+            vec![
+                "data",
+                "data\\clientinfo.xml",
+                "data\\sprite",
+                "data\\sprite\\monster",
+                "data\\sprite\\monster\\poring.act",
+                "data\\sprite\\monster\\poring.spr",
+                "data\\sprite\\npc",
+                "data\\Sprite\\npc\\kafra.spr",
+                "data\\texture",
+                "data/texture/a.bmp",
+                "data\\texture\\B.BMP",
+                "data\\texture\\empty",
+                "readme.txt",
+            ]
+        );
+        assert!(tree.walk("missing").is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let entries = test_entries();
+        let tree = DirectoryTree::new(&entries);
+        assert_eq!(
+            tree.root().stats(),
+            DirectoryStats {
+                file_count: 7,
+                size: 386,
+                size_compressed: 193,
+            }
+        );
+        let sprite_stats = tree.get_directory("data\\sprite").unwrap().stats();
+        assert_eq!(sprite_stats.file_count, 3);
+        assert_eq!(sprite_stats.size, 350);
+        let texture_stats = tree.get_directory("data\\texture").unwrap().stats();
+        assert_eq!(texture_stats.file_count, 2);
+        assert_eq!(texture_stats.size_compressed, 15);
+    }
+
+    #[test]
+    fn test_glob() {
+        let entries = test_entries();
+        let tree = DirectoryTree::new(&entries);
+        let glob = |pattern| paths(tree.glob(pattern).unwrap());
+        assert_eq!(
+            glob("data\\sprite\\**\\*.spr"),
+            vec![
+                "data\\sprite\\monster\\poring.spr",
+                "data\\Sprite\\npc\\kafra.spr"
+            ]
+        );
+        assert_eq!(glob("DATA/SPRITE/*.spr"), Vec::<&str>::new());
+        assert_eq!(glob("data\\texture\\?.bmp").len(), 2);
+        assert_eq!(glob("data\\texture\\[a].bmp"), vec!["data/texture/a.bmp"]);
+        assert_eq!(
+            glob("data\\texture\\[!a].bmp"),
+            vec!["data\\texture\\B.BMP"]
+        );
+        assert_eq!(glob("**\\*.txt"), vec!["readme.txt"]);
+        assert_eq!(glob("**").len(), entries.len());
+        assert_eq!(glob("data\\*.xml"), vec!["data\\clientinfo.xml"]);
+        assert_eq!(glob("data\\**.xml"), vec!["data\\clientinfo.xml"]);
+        assert!(tree.glob("data\\[a.bmp").is_err());
+    }
+
+    #[test]
+    fn test_find_regex() {
+        let entries = test_entries();
+        let tree = DirectoryTree::new(&entries);
+        assert_eq!(
+            paths(tree.find_regex(r"\\poring\.(spr|act)$").unwrap()),
+            vec![
+                "data\\sprite\\monster\\poring.act",
+                "data\\sprite\\monster\\poring.spr"
+            ]
+        );
+        assert_eq!(
+            paths(tree.find_regex(r"(?i)\.bmp$").unwrap()),
+            vec!["data/texture/a.bmp", "data\\texture\\B.BMP"]
+        );
+        assert!(tree.find_regex("(").is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::archive::{display_path, normalize_path, to_archive_path};
+use crate::data_ini::{data_ini_path, DataIni};
+use crate::grf::GrfArchive;
+use crate::{GrufError, Result};
+
+/// Layer of a `Vfs` a file or a directory has been resolved from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VfsLayer {
+    /// Files read from the disk, relative to the given directory
+    Directory(PathBuf),
+    /// Entries of the GRF archive located at the given path
+    Grf(PathBuf),
+}
+
+/// Entry returned when listing a directory of a `Vfs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsEntry {
+    /// Path of the entry, in the archives' form and with the casing found in
+    /// the layer it has been resolved from
+    pub relative_path: String,
+    pub is_directory: bool,
+    pub layer: VfsLayer,
+}
+
+/// Virtual file system that resolves files the way the game client does.
+///
+/// Files are first searched on the disk (in the client's directory), then in
+/// GRF archives, in the priority order given by `DATA.INI`. Lookups are
+/// case-insensitive and accept both `/` and `\` as separators.
+pub struct Vfs {
+    disk_root: Option<PathBuf>,
+    grfs: Vec<(PathBuf, GrfArchive<File>)>,
+}
+
+impl Vfs {
+    /// Creates an empty file system, which reads files from `disk_root` (if
+    /// any) before GRF archives
+    pub fn new(disk_root: Option<PathBuf>) -> Self {
+        Self {
+            disk_root,
+            grfs: Vec::new(),
+        }
+    }
+
+    /// Creates the file system of the client located in `client_dir`, from
+    /// its `DATA.INI` file.
+    ///
+    /// GRF archives listed in `DATA.INI` that don't exist are skipped, like
+    /// the client does.
+    pub fn open<P: AsRef<Path>>(client_dir: P) -> Result<Self> {
+        let client_dir = client_dir.as_ref();
+        let data_ini = DataIni::open(data_ini_path(client_dir))?;
+        let mut vfs = Self::new(Some(client_dir.to_path_buf()));
+        for grf_name in data_ini.grf_names() {
+            if let Some(grf_path) = find_on_disk(client_dir, &grf_name) {
+                vfs.push_grf(grf_path)?;
+            }
+        }
+        Ok(vfs)
+    }
+
+    /// Adds a GRF archive with a lower priority than the current layers
+    pub fn push_grf<P: AsRef<Path>>(&mut self, grf_path: P) -> Result<()> {
+        let grf_path = grf_path.as_ref();
+        let grf = GrfArchive::open(grf_path)?;
+        self.grfs.push((grf_path.to_path_buf(), grf));
+        Ok(())
+    }
+
+    /// Returns the layers of the file system, by decreasing priority
+    pub fn layers(&self) -> Vec<VfsLayer> {
+        self.disk_root
+            .iter()
+            .map(|disk_root| VfsLayer::Directory(disk_root.clone()))
+            .chain(
+                self.grfs
+                    .iter()
+                    .map(|(grf_path, _)| VfsLayer::Grf(grf_path.clone())),
+            )
+            .collect()
+    }
+
+    /// Returns the layer the given file is loaded from
+    pub fn resolve<S: AsRef<str>>(&self, file_path: S) -> Option<VfsLayer> {
+        match self.resolve_file(file_path.as_ref())? {
+            ResolvedFile::Disk(_) => self.disk_root.clone().map(VfsLayer::Directory),
+            ResolvedFile::Grf(i) => Some(VfsLayer::Grf(self.grfs[i].0.clone())),
+        }
+    }
+
+    pub fn contains_file<S: AsRef<str>>(&self, file_path: S) -> bool {
+        self.resolve_file(file_path.as_ref()).is_some()
+    }
+
+    pub fn read_file_content<S: AsRef<str>>(&mut self, file_path: S) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open_file(file_path)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Opens the file the client loads for the given path
+    pub fn open_file<S: AsRef<str>>(&mut self, file_path: S) -> Result<Box<dyn Read + '_>> {
+        let file_path = file_path.as_ref();
+        match self
+            .resolve_file(file_path)
+            .ok_or(GrufError::EntryNotFound)?
+        {
+            ResolvedFile::Disk(disk_path) => Ok(Box::new(File::open(disk_path)?)),
+            ResolvedFile::Grf(i) => Ok(Box::new(self.grfs[i].1.open_entry(file_path)?)),
+        }
+    }
+
+    /// Lists the files and directories located directly in `directory_path`,
+    /// ordered by path.
+    ///
+    /// Entries found in several layers are returned once, from the layer with
+    /// the highest priority.
+    pub fn read_dir<S: AsRef<str>>(&self, directory_path: S) -> Result<Vec<VfsEntry>> {
+        let mut prefix = normalize_path(directory_path.as_ref());
+        while prefix.ends_with('\\') {
+            prefix.pop();
+        }
+        if !prefix.is_empty() {
+            prefix.push('\\');
+        }
+        let mut found_directory = prefix.is_empty();
+        let mut entries: BTreeMap<String, VfsEntry> = BTreeMap::new();
+        if let Some(disk_root) = &self.disk_root {
+            if let Some(disk_directory) = find_on_disk(disk_root, &prefix) {
+                if disk_directory.is_dir() {
+                    found_directory = true;
+                    for dir_entry in fs::read_dir(disk_directory)? {
+                        let dir_entry = dir_entry?;
+                        let name = dir_entry.file_name().to_string_lossy().to_string();
+                        let relative_path = format!("{}{}", prefix, to_archive_path(&name));
+                        entries
+                            .entry(normalize_path(&relative_path))
+                            .or_insert(VfsEntry {
+                                relative_path,
+                                is_directory: dir_entry.file_type()?.is_dir(),
+                                layer: VfsLayer::Directory(disk_root.clone()),
+                            });
+                    }
+                }
+            }
+        }
+        for (grf_path, grf) in &self.grfs {
+            let grf_entries = grf
+                .get_entries()
+                .map(|entry| (entry, false))
+                .chain(grf.get_directory_entries().map(|entry| (entry, true)));
+            for (grf_entry, is_directory_entry) in grf_entries {
+                // Normalization preserves byte offsets, prefixes
+                // can thus be sliced from the original path
+                let normalized_path = normalize_path(&grf_entry.relative_path);
+                if !normalized_path.starts_with(&prefix) || normalized_path.len() == prefix.len() {
+                    continue;
+                }
+                found_directory = true;
+                let (name_length, is_directory) = match normalized_path[prefix.len()..].find('\\') {
+                    Some(name_length) => (name_length, true),
+                    None => (normalized_path.len() - prefix.len(), is_directory_entry),
+                };
+                let path_length = prefix.len() + name_length;
+                entries
+                    .entry(normalized_path[..path_length].to_string())
+                    .or_insert_with(|| VfsEntry {
+                        relative_path: grf_entry.relative_path[..path_length].replace('/', "\\"),
+                        is_directory,
+                        layer: VfsLayer::Grf(grf_path.clone()),
+                    });
+            }
+        }
+        if !found_directory {
+            return Err(GrufError::EntryNotFound);
+        }
+        Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    fn resolve_file(&self, file_path: &str) -> Option<ResolvedFile> {
+        if let Some(disk_root) = &self.disk_root {
+            if let Some(disk_path) = find_on_disk(disk_root, file_path) {
+                if disk_path.is_file() {
+                    return Some(ResolvedFile::Disk(disk_path));
+                }
+            }
+        }
+        self.grfs
+            .iter()
+            .position(|(_, grf)| grf.contains_file(file_path))
+            .map(ResolvedFile::Grf)
+    }
+}
+
+enum ResolvedFile {
+    Disk(PathBuf),
+    Grf(usize),
+}
+
+/// Looks for a path (in the archives' form) on the disk, case-insensitively
+pub(crate) fn find_on_disk(root: &Path, relative_path: &str) -> Option<PathBuf> {
+    let mut disk_path = root.to_path_buf();
+    let relative_path = display_path(relative_path);
+    for component in relative_path.split(|c| c == '\\' || c == '/') {
+        if component.is_empty() || component == "." {
+            continue;
+        }
+        if component == ".." {
+            return None;
+        }
+        let exact_path = disk_path.join(component);
+        disk_path = match exact_path.symlink_metadata() {
+            Ok(_) => exact_path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let component = normalize_path(component);
+                fs::read_dir(&disk_path)
+                    .ok()?
+                    .filter_map(|dir_entry| dir_entry.ok())
+                    .find(|dir_entry| {
+                        normalize_path(&dir_entry.file_name().to_string_lossy()) == component
+                    })?
+                    .path()
+            }
+            Err(_) => return None,
+        };
+    }
+    Some(disk_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grf::build_test_archive;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_vfs() {
+        let temp_dir = tempdir().unwrap();
+        let client_dir = temp_dir.path();
+        let rdata_path = client_dir.join("rdata.grf");
+        let data_path = client_dir.join("data.grf");
+        fs::write(
+            client_dir.join("data.ini"),
+            "[Data]\r\n0=rdata.grf\r\n1=missing.grf\r\n2=DATA.GRF\r\n",
+        )
+        .unwrap();
+        fs::write(
+            &rdata_path,
+            build_test_archive(
+                2,
+                0,
+                &[
+                    ("data\\sprite\\a.spr", b"rdata a"),
+                    ("data\\Sprite\\B.spr", b"rdata b"),
+                ],
+            ),
+        )
+        .unwrap();
+        fs::write(
+            &data_path,
+            build_test_archive(
+                2,
+                0,
+                &[
+                    ("data\\sprite\\a.spr", b"data a"),
+                    ("data\\sprite\\c.spr", b"data c"),
+                    ("data\\sprite\\monster\\d.spr", b"data d"),
+                    ("data\\texture\\e.bmp", b"data e"),
+                ],
+            ),
+        )
+        .unwrap();
+        fs::create_dir_all(client_dir.join("data/Sprite")).unwrap();
+        fs::write(client_dir.join("data/Sprite/C.spr"), b"disk c").unwrap();
+
+        let mut vfs = Vfs::open(client_dir).unwrap();
+        let disk_layer = VfsLayer::Directory(client_dir.to_path_buf());
+        let rdata_layer = VfsLayer::Grf(rdata_path);
+        let data_layer = VfsLayer::Grf(data_path);
+        assert_eq!(
+            vfs.layers(),
+            vec![disk_layer.clone(), rdata_layer.clone(), data_layer.clone()]
+        );
+        // Lookups
+        for (file_path, layer, content) in &[
+            ("data\\sprite\\a.spr", &rdata_layer, &b"rdata a"[..]),
+            ("DATA/SPRITE/b.spr", &rdata_layer, b"rdata b"),
+            ("data\\sprite\\c.spr", &disk_layer, b"disk c"),
+            ("data\\sprite\\monster\\d.spr", &data_layer, b"data d"),
+        ] {
+            assert_eq!(vfs.resolve(file_path).as_ref(), Some(*layer));
+            assert_eq!(vfs.read_file_content(file_path).unwrap(), *content);
+        }
+        assert!(vfs.resolve("data\\sprite").is_none());
+        assert!(!vfs.contains_file("data\\sprite\\missing.spr"));
+        assert!(matches!(
+            vfs.read_file_content("data\\sprite\\missing.spr"),
+            Err(GrufError::EntryNotFound)
+        ));
+        // Directory listings
+        let entries = vfs.read_dir("data/sprite/").unwrap();
+        let expected_entries = [
+            ("data\\sprite\\a.spr", false, &rdata_layer),
+            ("data\\Sprite\\B.spr", false, &rdata_layer),
+            ("data\\sprite\\C.spr", false, &disk_layer),
+            ("data\\sprite\\monster", true, &data_layer),
+        ];
+        assert_eq!(entries.len(), expected_entries.len());
+        for (entry, (relative_path, is_directory, layer)) in entries.iter().zip(&expected_entries) {
+            assert_eq!(entry.relative_path, *relative_path);
+            assert_eq!(entry.is_directory, *is_directory);
+            assert_eq!(&entry.layer, *layer);
+        }
+        let entries = vfs.read_dir("data").unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.relative_path.as_str()).collect();
+        assert_eq!(paths, vec!["data\\Sprite", "data\\texture"]);
+        assert!(entries.iter().all(|e| e.is_directory));
+        assert_eq!(vfs.read_dir("").unwrap().len(), 4);
+        assert!(matches!(
+            vfs.read_dir("data\\missing"),
+            Err(GrufError::EntryNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_vfs_without_disk_root() {
+        let temp_dir = tempdir().unwrap();
+        let grf_path = temp_dir.path().join("data.grf");
+        fs::write(
+            &grf_path,
+            build_test_archive(2, 0, &[("data\\a.txt", b"a")]),
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("data.txt"), b"disk").unwrap();
+        let mut vfs = Vfs::new(None);
+        assert!(vfs.read_dir("").unwrap().is_empty());
+        vfs.push_grf(&grf_path).unwrap();
+        assert!(!vfs.contains_file("data.txt"));
+        assert_eq!(vfs.resolve("data\\a.txt"), Some(VfsLayer::Grf(grf_path)));
+        assert!(Vfs::open(temp_dir.path().join("missing")).is_err());
+    }
+}
@@ -1,11 +1,13 @@
 mod patch_definition;
 
 use std::fs::File;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::{env, process};
 
 use anyhow::{anyhow, Context, Result};
 use gruf::thor::ThorArchiveBuilder;
+use gruf::CompressionPolicy;
 use log::LevelFilter;
 use patch_definition::{parse_patch_definition, PatchDefinition};
 use simple_logger::SimpleLogger;
@@ -37,6 +39,12 @@ struct Opt {
         help = "Path to the output archive (default: <patch_definition_file_name>.thor)"
     )]
     output_file: Option<PathBuf>,
+    #[structopt(
+        short,
+        long,
+        help = "Number of threads used to compress files (default: number of logical CPUs)"
+    )]
+    jobs: Option<usize>,
 }
 
 fn run(cli_args: Opt) -> Result<()> {
@@ -62,6 +70,7 @@ fn run(cli_args: Opt) -> Result<()> {
     // Display patch info
     log::info!("GRF merging: {}", patch_definition.use_grf_merging);
     log::info!("Checksums included: {}", patch_definition.include_checksums);
+    log::info!("Compression: {:?}", patch_definition.compression);
     if let Some(target_grf_name) = &patch_definition.target_grf_name {
         log::info!("Target GRF: '{}'", target_grf_name);
     } else {
@@ -69,8 +78,13 @@ fn run(cli_args: Opt) -> Result<()> {
     }
 
     // Generate THOR archive
-    generate_patch_from_definition(patch_definition, patch_data_directory, &output_file_path)
-        .context("Failed to generate patch from definition")?;
+    generate_patch_from_definition(
+        patch_definition,
+        patch_data_directory,
+        &output_file_path,
+        cli_args.jobs,
+    )
+    .context("Failed to generate patch from definition")?;
     log::info!(
         "Patch generated at '{}'",
         output_file_path.to_string_lossy()
@@ -82,6 +96,7 @@ fn generate_patch_from_definition<P1, P2>(
     patch_definition: PatchDefinition,
     patch_data_directory: P1,
     output_path: P2,
+    thread_count: Option<usize>,
 ) -> Result<()>
 where
     P1: AsRef<Path>,
@@ -94,12 +109,21 @@ where
         patch_definition.target_grf_name,
         patch_definition.include_checksums,
     )?;
+    archive_builder.set_compression(patch_definition.compression);
+    if let Some(thread_count) = thread_count {
+        archive_builder.set_thread_count(thread_count);
+    }
+    // File updates are compressed in batches, pending updates
+    // must be written before removals to preserve the definition's order
+    let mut file_updates = Vec::new();
     for entry in patch_definition.entries {
+        let compression = entry.compression.unwrap_or(patch_definition.compression);
         let win32_relative_path = win32_path(&entry.relative_path);
         let target_win32_relative_path = entry.in_grf_path.unwrap_or(win32_relative_path.clone());
 
         if entry.is_removed {
             log::trace!("'{}' will be REMOVED", &win32_relative_path);
+            append_file_updates(&mut archive_builder, &mut file_updates)?;
             archive_builder.append_file_removal(win32_relative_path);
             continue;
         }
@@ -110,14 +134,14 @@ where
         if native_path.is_file() {
             // Path points to a single file
             log::trace!("'{}' will be UPDATED", &target_win32_relative_path);
-            let file = File::open(native_path)?;
-            archive_builder.append_file_update(target_win32_relative_path, file)?;
+            file_updates.push((target_win32_relative_path, native_path, compression));
         } else if native_path.is_dir() {
             // Path points to a directory
-            append_directory_update(
-                &mut archive_builder,
+            list_directory_updates(
+                &mut file_updates,
                 patch_data_directory.as_ref(),
                 native_path,
+                compression,
             )?;
         } else {
             return Err(anyhow!(
@@ -126,13 +150,27 @@ where
             ));
         }
     }
-    Ok(())
+    append_file_updates(&mut archive_builder, &mut file_updates)
 }
 
-fn append_directory_update<P1, P2>(
+/// Compresses and appends pending file updates to the archive
+fn append_file_updates(
     archive_builder: &mut ThorArchiveBuilder<File>,
+    file_updates: &mut Vec<(String, PathBuf, CompressionPolicy)>,
+) -> Result<()> {
+    archive_builder.append_file_updates_with_compression(file_updates.drain(..).map(
+        |(relative_path, native_path, compression)| {
+            (relative_path, LazyFile::new(native_path), compression)
+        },
+    ))?;
+    Ok(())
+}
+
+fn list_directory_updates<P1, P2>(
+    file_updates: &mut Vec<(String, PathBuf, CompressionPolicy)>,
     patch_data_directory: P1,
     directory_path: P2,
+    compression: CompressionPolicy,
 ) -> Result<()>
 where
     P1: AsRef<Path>,
@@ -148,13 +186,35 @@ where
                 .ok_or_else(|| anyhow!("Invalid file path encountered"))?;
             let win32_relative_path = win32_path(rel_path_str);
             log::trace!("'{}' will be UPDATED", &win32_relative_path);
-            let file = File::open(entry.path())?;
-            archive_builder.append_file_update(win32_relative_path, file)?;
+            file_updates.push((win32_relative_path, entry.into_path(), compression));
         }
     }
     Ok(())
 }
 
+/// File that is only opened once read, so that files waiting to be compressed
+/// aren't kept open
+struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LazyFile {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None }
+    }
+}
+
+impl Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => File::open(&self.path)?,
+        };
+        self.file.get_or_insert(file).read(buf)
+    }
+}
+
 fn main() {
     const SUCCESS_EXIT_CODE: i32 = 0;
     const FAILURE_EXIT_CODE: i32 = 1;
@@ -3,6 +3,7 @@ use std::io::BufReader;
 use std::path::Path;
 
 use anyhow::{Context, Result};
+use gruf::CompressionPolicy;
 use serde::Deserialize;
 
 #[derive(Deserialize, Clone)]
@@ -11,6 +12,8 @@ pub struct PatchDefinition {
     pub include_checksums: bool,
     pub use_grf_merging: bool,
     pub target_grf_name: Option<String>,
+    #[serde(default)] // Defaults to zlib's default level
+    pub compression: CompressionPolicy,
     pub entries: Vec<PatchEntry>,
 }
 
@@ -19,7 +22,8 @@ pub struct PatchEntry {
     pub relative_path: String,
     #[serde(default)] // Defaults to false
     pub is_removed: bool,
-    pub in_grf_path: Option<String>
+    pub in_grf_path: Option<String>,
+    pub compression: Option<CompressionPolicy>,
 }
 
 pub fn parse_patch_definition(file_path: impl AsRef<Path>) -> Result<PatchDefinition> {
@@ -67,9 +67,11 @@ pub struct ClientConfiguration {
 
 #[derive(Deserialize, Clone)]
 pub struct PatchingConfiguration {
-    pub in_place: bool,        // In-place GRF patching
-    pub check_integrity: bool, // Check THOR archives' integrity
-    pub create_grf: bool,      // Create new GRFs if they don't exist
+    pub in_place: bool,            // In-place GRF patching
+    pub check_integrity: bool,     // Check THOR archives' integrity
+    pub create_grf: bool,          // Create new GRFs if they don't exist
+    pub compact_grf: Option<bool>, // Compact GRFs patched in-place
+    pub grf_priority: Option<u32>, // Priority of created GRFs in DATA.INI
 }
 
 pub fn retrieve_patcher_configuration(
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::sync::{
@@ -21,7 +22,10 @@ use super::cancellation::{
     process_incoming_commands, wait_for_cancellation, InterruptibleFnError, InterruptibleFnResult,
 };
 use super::config::PatchServerInfo;
-use super::patching::{apply_patch_to_disk, apply_patch_to_grf, GrfPatchingMethod};
+use super::patching::{
+    apply_patch_to_disk, apply_patch_to_grf, is_grf_in_data_ini, register_grf_in_data_ini,
+    GrfPatchingMethod,
+};
 use super::{get_patcher_name, PatcherCommand, PatcherConfiguration};
 use crate::ui::{PatchingStatus, UiController};
 
@@ -575,6 +579,8 @@ async fn apply_patches(
     })?;
     let patch_count = pending_patch_queue.len();
     ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(0, patch_count));
+    // GRFs patched in-place, which might have to be compacted
+    let mut patched_grfs = HashSet::new();
     for (patch_number, pending_patch) in pending_patch_queue.into_iter().enumerate() {
         // Cancel the patching process if we've been asked to or if the other
         // end of the channel has been disconnected
@@ -582,9 +588,13 @@ async fn apply_patches(
 
         let patch_name = pending_patch.info.file_name;
         log::info!("Processing {}", patch_name);
-        apply_patch(pending_patch.local_file_path, config, &current_working_dir).map_err(|e| {
-            InterruptibleFnError::Err(format!("Failed to apply patch '{}': {}.", patch_name, e))
-        })?;
+        let patched_grf = apply_patch(pending_patch.local_file_path, config, &current_working_dir)
+            .map_err(|e| {
+                InterruptibleFnError::Err(format!("Failed to apply patch '{}': {}.", patch_name, e))
+            })?;
+        if let Some(grf_path) = patched_grf {
+            patched_grfs.insert(grf_path);
+        }
         // Update the cache file with the last successful patch's index
         if let Err(e) = write_cache_file(
             &cache_file_path,
@@ -602,14 +612,28 @@ async fn apply_patches(
             patch_count,
         ));
     }
+    if config.patching.compact_grf.unwrap_or(false) {
+        for grf_path in patched_grfs {
+            // An interrupted compaction leaves a valid GRF, failures aren't
+            // fatal
+            match gruf::grf::compact_file(&grf_path) {
+                Ok(reclaimed_size) => {
+                    log::info!("Reclaimed {} bytes in {:?}", reclaimed_size, grf_path)
+                }
+                Err(e) => log::warn!("Failed to compact {:?}: {}.", grf_path, e),
+            }
+        }
+    }
     Ok(())
 }
 
+/// Applies a patch and returns the path of the GRF it's been applied to, if it's
+/// been patched in-place.
 fn apply_patch(
     thor_archive_path: impl AsRef<Path>,
     config: &PatcherConfiguration,
     current_working_dir: impl AsRef<Path>,
-) -> Result<()> {
+) -> Result<Option<PathBuf>> {
     let mut thor_archive = ThorArchive::open(thor_archive_path.as_ref())?;
     if thor_archive.use_grf_merging() {
         // Patch GRF file
@@ -626,15 +650,41 @@ fn apply_patch(
             false => GrfPatchingMethod::OutOfPlace,
         };
         let target_grf_path = current_working_dir.as_ref().join(&target_grf_name);
+        if config.patching.create_grf && !target_grf_path.exists() {
+            // New GRFs are registered before being created, so that they're
+            // registered when patching is retried after a failure. Failures
+            // aren't fatal, clients don't necessarily use a DATA.INI file.
+            let priority = config.patching.grf_priority.unwrap_or(0);
+            match register_grf_in_data_ini(&current_working_dir, &target_grf_name, priority) {
+                Ok(true) => log::info!("Registered {} in DATA.INI", target_grf_name),
+                Ok(false) => {}
+                Err(e) => log::warn!("Failed to register {} in DATA.INI: {}.", target_grf_name, e),
+            }
+        } else {
+            match is_grf_in_data_ini(&current_working_dir, &target_grf_name) {
+                Ok(true) => {}
+                Ok(false) => log::warn!(
+                    "{} isn't listed in DATA.INI, the client won't load it.",
+                    target_grf_name
+                ),
+                Err(e) => log::warn!("Failed to read DATA.INI: {}.", e),
+            }
+        }
         apply_patch_to_grf(
             grf_patching_method,
             config.patching.create_grf,
-            target_grf_path,
+            &target_grf_path,
             &mut thor_archive,
-        )
+        )?;
+        if config.patching.in_place {
+            Ok(Some(target_grf_path))
+        } else {
+            Ok(None)
+        }
     } else {
         // Patch root directory
-        apply_patch_to_disk(current_working_dir, &mut thor_archive)
+        apply_patch_to_disk(current_working_dir, &mut thor_archive)?;
+        Ok(None)
     }
 }
 
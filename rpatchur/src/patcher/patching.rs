@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::{Read, Seek};
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use gruf::grf::{GrfArchive, GrfArchiveBuilder};
+use gruf::data_ini::{data_ini_path, DataIni};
+use gruf::grf::{recover_file, GrfArchive, GrfArchiveBuilder};
+use gruf::normalize_path;
 use gruf::thor::{ThorArchive, ThorFileEntry};
 
 /// Indicates the method that should be used when patching GRF files.
@@ -27,6 +29,7 @@ enum DataTransformation {
 }
 
 struct MergeEntry {
+    pub relative_path: String,
     pub source: MergeEntrySource,
     pub source_offset: u64,
     pub data_size: usize,
@@ -53,13 +56,14 @@ pub fn apply_patch_to_grf<R: Read + Seek>(
 
 /// Patches a GRF in an in-place manner.
 ///
-/// This is faster but produces output of bigger size and can corrupt file in
-/// case of error.
+/// This is faster but produces output of bigger size. The GRF is patched in
+/// journaled mode, it's left untouched in case of error and patches
+/// interrupted by a crash are recovered the next time it's patched.
 fn apply_patch_to_grf_ip<R: Read + Seek>(
     grf_file_path: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
 ) -> Result<()> {
-    let mut builder = GrfArchiveBuilder::open(grf_file_path)?;
+    let mut builder = GrfArchiveBuilder::open_journaled(grf_file_path)?;
     let mut thor_entries: Vec<ThorFileEntry> = thor_archive
         .get_entries()
         .filter(|e| !e.is_internal())
@@ -73,6 +77,7 @@ fn apply_patch_to_grf_ip<R: Read + Seek>(
             builder.import_raw_entry_from_thor(thor_archive, entry.relative_path)?;
         }
     }
+    builder.finish()?;
     Ok(())
 }
 
@@ -83,15 +88,20 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
     grf_file_path: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
 ) -> Result<()> {
+    // Complete an interrupted in-place patch first, its journal wouldn't
+    // follow the renamed archive
+    recover_file(grf_file_path.as_ref())?;
     // Rename file to back it up
     let mut backup_file_path = grf_file_path.as_ref().to_path_buf();
     backup_file_path.set_extension("grf.bak");
     fs::rename(grf_file_path.as_ref(), &backup_file_path)?;
 
-    // Prepare file entries that'll be used to make the patched GRF
-    let mut merge_entries: HashMap<String, MergeEntry> = HashMap::new();
+    // Prepare file entries that'll be used to make the patched GRF. Entries are
+    // indexed by their normalized path to avoid case-only duplicates, and
+    // ordered so that the patched GRF is reproducible.
+    let mut merge_entries: BTreeMap<String, MergeEntry> = BTreeMap::new();
     // Add files from the original archive while discarding files remove in the patch
-    let mut grf_archive = GrfArchive::open(&backup_file_path)?;
+    let grf_archive = GrfArchive::open_mmap(&backup_file_path)?;
     for entry in grf_archive.get_entries() {
         if let Some(e) = thor_archive.get_file_entry(&entry.relative_path) {
             if e.is_removed {
@@ -99,8 +109,9 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
             }
         }
         merge_entries.insert(
-            entry.relative_path.clone(),
+            normalize_path(&entry.relative_path),
             MergeEntry {
+                relative_path: entry.relative_path.clone(),
                 source: MergeEntrySource::GrfArchive,
                 source_offset: entry.offset,
                 data_size: entry.size_compressed,
@@ -113,9 +124,16 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
         if entry.is_removed || entry.is_internal() {
             continue;
         }
+        let key = normalize_path(&entry.relative_path);
+        // Keep the original casing of replaced entries
+        let relative_path = match merge_entries.get(&key) {
+            Some(e) => e.relative_path.clone(),
+            None => entry.relative_path.clone(),
+        };
         merge_entries.insert(
-            entry.relative_path.clone(),
+            key,
             MergeEntry {
+                relative_path,
                 source: MergeEntrySource::ThorArchive,
                 source_offset: entry.offset,
                 data_size: entry.size_compressed,
@@ -127,10 +145,11 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
     {
         let grf_file = fs::File::create(grf_file_path)?;
         let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0)?;
-        for (relative_path, entry) in merge_entries {
+        for (_, entry) in merge_entries {
+            let relative_path = entry.relative_path;
             match entry.source {
                 MergeEntrySource::GrfArchive => {
-                    builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
+                    builder.import_raw_entry_from_grf_slice(&grf_archive, relative_path)?;
                 }
                 MergeEntrySource::ThorArchive => {
                     builder.import_raw_entry_from_thor(thor_archive, relative_path)?;
@@ -138,10 +157,36 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
             }
         }
     }
+    // Unmap the original archive before removing it
+    drop(grf_archive);
     // Remove backup file once the patched GRF has been built
     Ok(fs::remove_file(backup_file_path)?)
 }
 
+/// Registers a GRF in the DATA.INI file of the game client, with the given
+/// priority, so that the client loads it.
+///
+/// Returns false if the GRF was registered already.
+pub fn register_grf_in_data_ini(
+    client_directory: impl AsRef<Path>,
+    grf_name: &str,
+    priority: u32,
+) -> Result<bool> {
+    let data_ini_path = data_ini_path(client_directory);
+    let mut data_ini = DataIni::open(&data_ini_path)?;
+    if !data_ini.insert_grf(priority, grf_name) {
+        return Ok(false);
+    }
+    data_ini.write(&data_ini_path)?;
+    Ok(true)
+}
+
+/// Indicates whether a GRF is listed in the DATA.INI file of the game client.
+pub fn is_grf_in_data_ini(client_directory: impl AsRef<Path>, grf_name: &str) -> Result<bool> {
+    let data_ini = DataIni::open(data_ini_path(client_directory))?;
+    Ok(data_ini.contains_grf(grf_name))
+}
+
 /// Patches files located in the game client's directory with a THOR
 /// archive/patch.
 pub fn apply_patch_to_disk<R: Read + Seek>(
@@ -223,6 +268,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_register_grf_in_data_ini() {
+        let temp_dir = tempdir().unwrap();
+        assert!(register_grf_in_data_ini(temp_dir.path(), "myserver.grf", 0).is_err());
+        assert!(is_grf_in_data_ini(temp_dir.path(), "myserver.grf").is_err());
+
+        let data_ini_path = temp_dir.path().join("DATA.INI");
+        fs::write(&data_ini_path, "[Data]\r\n0=rdata.grf\r\n1=data.grf\r\n").unwrap();
+        assert!(!is_grf_in_data_ini(temp_dir.path(), "myserver.grf").unwrap());
+        assert!(register_grf_in_data_ini(temp_dir.path(), "myserver.grf", 1).unwrap());
+        assert!(!register_grf_in_data_ini(temp_dir.path(), "MyServer.grf", 0).unwrap());
+        assert!(is_grf_in_data_ini(temp_dir.path(), "myserver.grf").unwrap());
+        assert_eq!(
+            fs::read_to_string(&data_ini_path).unwrap(),
+            "[Data]\r\n0=rdata.grf\r\n1=myserver.grf\r\n2=data.grf\r\n"
+        );
+    }
+
     #[test]
     fn test_apply_patch_to_grf_ip_empty() {
         let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");